@@ -0,0 +1,45 @@
+//! Compares spawning a short pipe by forking and with `posix_spawn`.
+//!
+//! Run with `cargo bench --features posix_spawn`.
+
+use std::time::{Duration, Instant};
+
+use apipe::CommandPipe;
+
+const RUNS: u32 = 500;
+
+fn pipe() -> CommandPipe {
+    let mut pipe = CommandPipe::new();
+    pipe.add_command("true")
+        .add_command("true")
+        .add_command("true");
+    pipe
+}
+
+fn measure(name: &str, spawn: fn(&mut CommandPipe)) -> Duration {
+    // Warm up the page cache and the allocator.
+    for _ in 0..RUNS / 10 {
+        spawn(&mut pipe());
+    }
+
+    let start = Instant::now();
+    for _ in 0..RUNS {
+        spawn(&mut pipe());
+    }
+    let per_run = start.elapsed() / RUNS;
+    println!("{name:<28} {per_run:>10.1?} per pipe");
+    per_run
+}
+
+fn main() {
+    let forked = measure("spawn_with_output", |p| {
+        p.spawn_with_output().unwrap();
+    });
+    let spawned = measure("spawn_with_output_posix", |p| {
+        p.spawn_with_output_posix().unwrap();
+    });
+    println!(
+        "speedup                      {:>10.2}x",
+        forked.as_secs_f64() / spawned.as_secs_f64()
+    );
+}
@@ -0,0 +1,196 @@
+//! A typestate builder for [CommandPipe].
+//!
+//! The builder tracks at the type level whether a command has been added yet,
+//! so adding arguments to or spawning an empty pipe is a compile error instead
+//! of an [APipeError::EmptyPipe] at runtime.
+
+use crate::{cmd::Command, error::APipeError, pipe::CommandPipe};
+use std::{ffi::OsStr, marker::PhantomData};
+
+type Result<T> = std::result::Result<T, APipeError>;
+
+/// Builder state: no command has been added yet.
+#[derive(Debug)]
+pub struct Empty;
+
+/// Builder state: at least one command has been added.
+#[derive(Debug)]
+pub struct Ready;
+
+/// A builder for [CommandPipe] that can't be misused.
+///
+/// Created by [CommandPipe::builder]. Arguments can only be added, and the
+/// pipe can only be built or spawned, after at least one command was added.
+///
+/// ## Example
+///
+/// ```
+/// # fn main() -> Result<(), apipe::error::APipeError> {
+/// use apipe::CommandPipe;
+///
+/// let mut pipe = CommandPipe::builder()
+///     .add_command("echo")
+///     .arg("This is a test.")
+///     .add_command("grep")
+///     .args(["-Eo", r"\w\w\sa[^.]*"])
+///     .build();
+///
+/// let output = pipe.spawn_with_output()?;
+///
+/// assert_eq!(output.stdout(), "is a test\n".as_bytes());
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Adding an argument before any command doesn't compile:
+///
+/// ```compile_fail
+/// use apipe::CommandPipe;
+///
+/// let pipe = CommandPipe::builder().arg("-la");
+/// ```
+#[derive(Debug)]
+pub struct PipeBuilder<S> {
+    pipe: CommandPipe,
+    state: PhantomData<S>,
+}
+
+impl PipeBuilder<Empty> {
+    pub(crate) fn new() -> Self {
+        PipeBuilder {
+            pipe: CommandPipe::new(),
+            state: PhantomData,
+        }
+    }
+}
+
+impl<S> PipeBuilder<S> {
+    /// Add a command to the pipe.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// let builder = CommandPipe::builder().add_command("ls");
+    /// ```
+    pub fn add_command<C>(mut self, c: C) -> PipeBuilder<Ready>
+    where
+        C: AsRef<OsStr>,
+    {
+        self.pipe.pipeline.push(Command::new(c));
+
+        PipeBuilder {
+            pipe: self.pipe,
+            state: PhantomData,
+        }
+    }
+}
+
+impl PipeBuilder<Ready> {
+    /// Add a single argument to the preceding command in the pipe.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// let builder = CommandPipe::builder().add_command("ls").arg("-la");
+    /// ```
+    pub fn arg<A>(mut self, arg: A) -> Self
+    where
+        A: AsRef<OsStr>,
+    {
+        self.last_command().0.arg(arg);
+        self
+    }
+
+    /// Add multiple arguments to the preceding command in the pipe.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// let builder = CommandPipe::builder()
+    ///     .add_command("ls")
+    ///     .args(["-la", "~/Documents"]);
+    /// ```
+    pub fn args<I, A>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = A>,
+        A: AsRef<OsStr>,
+    {
+        self.last_command().0.args(args);
+        self
+    }
+
+    /// Finish building and return the [CommandPipe].
+    pub fn build(self) -> CommandPipe {
+        self.pipe
+    }
+
+    /// Build the pipe and run it, returning the spawned [CommandPipe].
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
+    /// use apipe::CommandPipe;
+    ///
+    /// let mut pipe = CommandPipe::builder()
+    ///     .add_command("echo")
+    ///     .arg("This is a test.")
+    ///     .spawn()?;
+    ///
+    /// assert_eq!(pipe.output()?.stdout(), "This is a test.\n".as_bytes());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn spawn(self) -> Result<CommandPipe> {
+        let mut pipe = self.pipe;
+        pipe.spawn()?;
+
+        Ok(pipe)
+    }
+
+    fn last_command(&mut self) -> &mut Command {
+        // The `Ready` state guarantees that a command was added.
+        self.pipe
+            .pipeline
+            .last_mut()
+            .expect("Ready builder always holds a command.")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builder() {
+        let pipe = CommandPipe::builder()
+            .add_command("ls")
+            .arg("-la")
+            .add_command("grep")
+            .args(["-E", "foo"])
+            .build();
+
+        assert_eq!(pipe.pipeline.len(), 2);
+        assert_eq!(pipe.pipeline[0].0.get_program(), "ls");
+        assert_eq!(
+            pipe.pipeline[1].0.get_args().collect::<Vec<&OsStr>>(),
+            &["-E", "foo"]
+        );
+    }
+
+    #[test]
+    fn test_builder_spawn() {
+        let mut pipe = CommandPipe::builder()
+            .add_command("echo")
+            .arg("This is a test.")
+            .add_command("grep")
+            .args(["-Eo", r"\w\w\sa[^.]*"])
+            .spawn()
+            .unwrap();
+
+        assert_eq!(pipe.output().unwrap().stdout(), "is a test\n".as_bytes());
+    }
+}
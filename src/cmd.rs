@@ -1,15 +1,26 @@
 //! Abstraction over an external command.
 
-use crate::pipe::CommandPipe;
-use std::{ffi::OsStr, ops};
+use crate::{
+    error::APipeError,
+    output::Output,
+    pipe::{CommandPipe, StageAction},
+    stdio::{FdTarget, StdioSpec},
+};
+use std::{
+    ffi::{OsStr, OsString},
+    fmt, ops,
+    path::{Component, Path},
+    process::ExitStatus,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 #[cfg(feature = "parser")]
 use lazy_static::lazy_static;
 #[cfg(feature = "parser")]
 use regex::Regex;
 
-#[cfg(feature = "parser")]
-type Result<T> = std::result::Result<T, crate::error::APipeError>;
+type Result<T> = std::result::Result<T, APipeError>;
 
 /// Abstraction of an external command.
 ///
@@ -27,17 +38,313 @@ type Result<T> = std::result::Result<T, crate::error::APipeError>;
 /// # }
 /// ```
 #[derive(Debug)]
-pub struct Command(pub(crate) std::process::Command);
+pub struct Command(pub(crate) std::process::Command, pub(crate) Options);
+
+/// Settings of a [Command] that can't be read back from the wrapped
+/// [std::process::Command].
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Options {
+    pub(crate) env_clear: bool,
+    pub(crate) stdout: Option<StdioSpec>,
+    pub(crate) capture: bool,
+    pub(crate) inherit_io: bool,
+    pub(crate) stdout_tap: Option<StdoutTap>,
+    pub(crate) timeout: Option<Duration>,
+    pub(crate) on_error: Option<ErrorHandler>,
+    #[cfg(unix)]
+    pub(crate) arg0: Option<OsString>,
+    #[cfg(unix)]
+    pub(crate) pre_exec: Vec<PreExec>,
+    #[cfg(unix)]
+    pub(crate) fd_redirects: Vec<(i32, FdTarget)>,
+}
+
+/// A callback called with chunks of output.
+pub(crate) type Callback = dyn FnMut(&[u8]) + Send;
+
+/// A callback receiving the chunks of the stdout of a stage.
+///
+/// Clones of a pipe share the callback.
+#[derive(Clone)]
+pub(crate) struct StdoutTap(pub(crate) Arc<Mutex<Callback>>);
+
+impl fmt::Debug for StdoutTap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("StdoutTap(..)")
+    }
+}
+
+/// A callback deciding how an unsuccessful exit of a stage is handled.
+///
+/// Clones of a pipe share the callback.
+#[derive(Clone)]
+pub(crate) struct ErrorHandler {
+    pub(crate) handler: Arc<dyn Fn(ExitStatus) -> StageAction + Send + Sync>,
+    /// Whether the callback may return [StageAction::Abort], which has to be
+    /// acted on while the other stages are still running.
+    pub(crate) may_abort: bool,
+}
+
+impl fmt::Debug for ErrorHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ErrorHandler(..)")
+    }
+}
+
+/// A closure run in the child process before it executes the program.
+///
+/// Clones of a pipe share the closure.
+#[cfg(unix)]
+#[derive(Clone)]
+pub(crate) struct PreExec(pub(crate) Arc<Mutex<dyn FnMut() -> std::io::Result<()> + Send>>);
+
+#[cfg(unix)]
+impl fmt::Debug for PreExec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PreExec(..)")
+    }
+}
 
 impl<T> From<T> for Command
 where
     T: AsRef<OsStr>,
 {
     fn from(s: T) -> Self {
-        Command(std::process::Command::new(s))
+        Command::new(s)
     }
 }
 
+impl Default for Command {
+    /// Creates a command with an empty program and no arguments.
+    ///
+    /// Such a command can't be run: validating or spawning a pipe containing
+    /// it fails with [APipeError::EmptyProgram](crate::APipeError::EmptyProgram).
+    fn default() -> Self {
+        Command::new("")
+    }
+}
+
+impl Clone for Command {
+    /// Clones the program, arguments, environment and working directory of
+    /// the command.
+    fn clone(&self) -> Self {
+        Command(
+            self.rebuild(self.0.get_program(), self.0.get_args()),
+            self.1.clone(),
+        )
+    }
+}
+
+impl Command {
+    /// Builds a copy of the wrapped [std::process::Command] running `program`
+    /// with `args`.
+    fn rebuild<'a, I>(&'a self, program: &OsStr, args: I) -> std::process::Command
+    where
+        I: IntoIterator<Item = &'a OsStr>,
+    {
+        let mut command = std::process::Command::new(program);
+        command.args(args);
+
+        if self.1.env_clear {
+            command.env_clear();
+        }
+        for (key, val) in self.0.get_envs() {
+            match val {
+                Some(val) => command.env(key, val),
+                None => command.env_remove(key),
+            };
+        }
+        if let Some(dir) = self.0.get_current_dir() {
+            command.current_dir(dir);
+        }
+        #[cfg(unix)]
+        if let Some(ref arg0) = self.1.arg0 {
+            std::os::unix::process::CommandExt::arg0(&mut command, arg0);
+        }
+        #[cfg(unix)]
+        for hook in &self.1.pre_exec {
+            let hook = hook.0.clone();
+            // SAFETY: the caller of `CommandPipe::pre_exec` upholds the
+            // contract of `CommandExt::pre_exec` for the closure.
+            unsafe {
+                std::os::unix::process::CommandExt::pre_exec(&mut command, move || {
+                    (*hook.lock().unwrap_or_else(|e| e.into_inner()))()
+                });
+            }
+        }
+
+        command
+    }
+
+    /// Builds the [std::process::Command] to spawn.
+    ///
+    /// A relative program path like `./script.sh` is resolved against the
+    /// working directory of the command instead of the one of the parent. A
+    /// relative working directory is relative to the one of the parent, so
+    /// it's made absolute first, as the child applies it before it runs the
+    /// program.
+    pub(crate) fn to_process(&self) -> std::process::Command {
+        let program = Path::new(self.0.get_program());
+        let dir = self
+            .0
+            .get_current_dir()
+            .and_then(|dir| match dir.is_absolute() {
+                true => Some(dir.to_path_buf()),
+                false => std::env::current_dir().ok().map(|cwd| cwd.join(dir)),
+            });
+
+        match dir {
+            Some(dir) if is_relative_path(program) => {
+                self.rebuild(dir.join(program).as_os_str(), self.0.get_args())
+            }
+            _ => self.rebuild(program.as_os_str(), self.0.get_args()),
+        }
+    }
+
+    /// Returns whether the command is a plain `cat` that only copies its
+    /// stdin to its stdout, without any arguments or settings.
+    pub(crate) fn is_plain_cat(&self) -> bool {
+        let options = &self.1;
+        #[cfg(unix)]
+        let unix_default = options.arg0.is_none()
+            && options.pre_exec.is_empty()
+            && options.fd_redirects.is_empty();
+        #[cfg(not(unix))]
+        let unix_default = true;
+
+        self.0.get_program() == "cat"
+            && self.0.get_args().next().is_none()
+            && self.0.get_envs().next().is_none()
+            && self.0.get_current_dir().is_none()
+            && !options.env_clear
+            && options.stdout.is_none()
+            && !options.capture
+            && !options.inherit_io
+            && options.stdout_tap.is_none()
+            && options.timeout.is_none()
+            && options.on_error.is_none()
+            && unix_default
+    }
+
+    /// Returns a copy of the command with `args` inserted before its own
+    /// arguments.
+    pub(crate) fn with_leading_args(&self, args: &[OsString]) -> Command {
+        let args = args
+            .iter()
+            .map(OsString::as_os_str)
+            .chain(self.0.get_args());
+
+        Command(self.rebuild(self.0.get_program(), args), self.1.clone())
+    }
+}
+
+/// Splits `s` into words at whitespace, removing the quotes around quoted
+/// parts.
+///
+/// Whitespace inside single or double quotes doesn't split words, and
+/// backslashes are kept as they are. Returns `None` if a quote isn't closed.
+#[cfg(feature = "parser")]
+pub(crate) fn split_words(s: &str) -> Option<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut quote = None;
+
+    for c in s.chars() {
+        match (c, quote) {
+            (c, Some(q)) if c == q => quote = None,
+            (c, Some(_)) => word.get_or_insert_with(String::new).push(c),
+            ('"' | '\'', None) => {
+                quote = Some(c);
+                word.get_or_insert_with(String::new);
+            }
+            (c, None) if c.is_whitespace() => words.extend(word.take()),
+            (c, None) => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+
+    if quote.is_some() {
+        return None;
+    }
+    words.extend(word);
+
+    Some(words)
+}
+
+/// Returns whether `program` is a relative path rather than a bare name that
+/// is looked up in the `PATH`.
+fn is_relative_path(program: &Path) -> bool {
+    program.is_relative()
+        && (program.components().count() > 1
+            || matches!(
+                program.components().next(),
+                Some(Component::CurDir | Component::ParentDir)
+            ))
+}
+
+impl fmt::Display for Command {
+    /// Formats the program and its arguments separated by spaces.
+    ///
+    /// Arguments that are empty or contain whitespace or quotes are wrapped in
+    /// single quotes.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_command_line(f, self.0.get_program(), self.0.get_args())
+    }
+}
+
+/// Writes `program` and `args` like the [Display](fmt::Display) form of
+/// [Command].
+pub(crate) fn write_command_line<'a, W, I>(f: &mut W, program: &OsStr, args: I) -> fmt::Result
+where
+    W: fmt::Write,
+    I: IntoIterator<Item = &'a OsStr>,
+{
+    write!(f, "{}", program.to_string_lossy())?;
+
+    for arg in args {
+        let arg = arg.to_string_lossy();
+
+        if arg.is_empty() || arg.contains(|c: char| c.is_whitespace() || c == '"' || c == '\'') {
+            write!(f, " '{}'", arg.replace('\'', r"'\''"))?;
+        } else {
+            write!(f, " {}", arg)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Quotes `s` for a POSIX shell, so the shell passes it on as a single word
+/// without expanding anything in it.
+pub(crate) fn shell_quote(s: &OsStr) -> String {
+    let s = s.to_string_lossy();
+    let safe = |c: char| c.is_ascii_alphanumeric() || "_-+=@%:,./".contains(c);
+
+    if !s.is_empty() && s.chars().all(safe) {
+        s.into_owned()
+    } else {
+        format!("'{}'", s.replace('\'', r"'\''"))
+    }
+}
+
+/// Returns a `printf` format string that prints exactly `bytes`, with every
+/// byte that isn't printable ASCII written as an octal escape.
+pub(crate) fn printf_format(bytes: &[u8]) -> String {
+    let mut format = String::with_capacity(bytes.len());
+
+    for (i, &b) in bytes.iter().enumerate() {
+        match b {
+            b'%' => format.push_str("%%"),
+            b'\\' => format.push_str(r"\\"),
+            // A leading `-` would be taken for an option.
+            b'-' if i == 0 => format.push_str(r"\055"),
+            b' '..=b'~' => format.push(b as char),
+            _ => format.push_str(&format!("\\{:03o}", b)),
+        }
+    }
+
+    format
+}
+
 impl ops::BitOr<Command> for Command {
     type Output = CommandPipe;
 
@@ -63,7 +370,23 @@ impl Command {
     where
         S: AsRef<OsStr>,
     {
-        Command(std::process::Command::new(command))
+        Command(std::process::Command::new(command), Options::default())
+    }
+
+    /// Creates a new command from a program and its arguments.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::Command;
+    /// let cmd = Command::from_parts("grep", &["-i", "foo"]);
+    /// ```
+    pub fn from_parts<P, A>(program: P, args: &[A]) -> Self
+    where
+        P: AsRef<OsStr>,
+        A: AsRef<OsStr>,
+    {
+        Command::new(program).args(args)
     }
 
     /// Adds a single argument to an existing Command instance.
@@ -99,9 +422,232 @@ impl Command {
         self
     }
 
+    /// Returns the program of the command.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::Command;
+    /// let cmd = Command::new("ls").arg("-la");
+    ///
+    /// assert_eq!(cmd.program(), "ls");
+    /// ```
+    pub fn program(&self) -> &OsStr {
+        self.0.get_program()
+    }
+
+    /// Returns the arguments of the command, without the program.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::Command;
+    /// let cmd = Command::new("ls").args(["-l", "-a"]);
+    ///
+    /// assert_eq!(cmd.get_args().collect::<Vec<_>>(), &["-l", "-a"]);
+    /// ```
+    pub fn get_args(&self) -> impl Iterator<Item = &OsStr> {
+        self.0.get_args()
+    }
+
+    /// Returns the working directory set with [Command::current_dir].
+    ///
+    /// Returns `None` if the command runs in the working directory of the
+    /// parent process.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::Command;
+    /// # use std::path::Path;
+    /// let cmd = Command::new("ls").current_dir("/tmp");
+    ///
+    /// assert_eq!(cmd.get_current_dir(), Some(Path::new("/tmp")));
+    /// ```
+    pub fn get_current_dir(&self) -> Option<&Path> {
+        self.0.get_current_dir()
+    }
+
+    /// Returns the environment variables set on the command.
+    ///
+    /// Each variable is returned with its value, or `None` if it was removed.
+    /// Variables set for the whole pipe with
+    /// [CommandPipe::env_all](crate::CommandPipe::env_all) aren't included.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::Command;
+    /// # use std::ffi::OsStr;
+    /// let cmd = Command::new("printenv").env("LANG", "C");
+    ///
+    /// let envs: Vec<_> = cmd.get_envs().collect();
+    /// assert_eq!(envs, &[(OsStr::new("LANG"), Some(OsStr::new("C")))]);
+    /// ```
+    pub fn get_envs(&self) -> std::process::CommandEnvs<'_> {
+        self.0.get_envs()
+    }
+
+    /// Estimates the number of bytes the program, arguments and environment
+    /// of the command take up when it's spawned.
+    ///
+    /// Operating systems limit this size, e.g. to `ARG_MAX` on Unix, and
+    /// fail to spawn commands exceeding it with
+    /// [APipeError::ArgumentListTooLarge](crate::APipeError::ArgumentListTooLarge).
+    /// Only the variables set on the command are counted, not the inherited
+    /// environment.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::Command;
+    /// let cmd = Command::new("ls").arg("-la");
+    ///
+    /// assert_eq!(cmd.estimated_argv_size(), "ls\0-la\0".len());
+    /// ```
+    pub fn estimated_argv_size(&self) -> usize {
+        let args: usize = std::iter::once(self.0.get_program())
+            .chain(self.0.get_args())
+            .map(|arg| arg.len() + 1)
+            .sum();
+        let envs: usize = self
+            .0
+            .get_envs()
+            .filter_map(|(key, val)| val.map(|val| key.len() + val.len() + 2))
+            .sum();
+
+        args + envs
+    }
+
+    /// Sets an environment variable for the command.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::Command;
+    /// let cmd = Command::new("printenv").arg("LANG").env("LANG", "C");
+    /// ```
+    pub fn env<K, V>(mut self, key: K, val: V) -> Self
+    where
+        K: AsRef<OsStr>,
+        V: AsRef<OsStr>,
+    {
+        self.0.env(key, val);
+        self
+    }
+
+    /// Sets the working directory of the command.
+    ///
+    /// Unlike with [std::process::Command], a relative program path such as
+    /// `./script.sh` is resolved against this directory, not against the
+    /// working directory of the parent process. Programs given by name only,
+    /// like `ls`, are still looked up in the `PATH`.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::Command;
+    /// let cmd = Command::new("./configure").current_dir("/tmp/project");
+    /// ```
+    pub fn current_dir<P>(mut self, dir: P) -> Self
+    where
+        P: AsRef<Path>,
+    {
+        self.0.current_dir(dir);
+        self
+    }
+
+    /// Connects the stdin, stdout and stderr of the command to the ones of
+    /// the parent process, e.g. for an interactive editor.
+    ///
+    /// In a pipe such a stage isn't connected to its neighbours: the output
+    /// of the stage before it is discarded, so that stage may fail writing
+    /// it, and the stage after it reads an empty stdin. If it's the last
+    /// stage, no stdout is collected.
+    ///
+    /// ## Example
+    ///
+    /// ```no_run
+    /// # use apipe::{Command, CommandPipe};
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
+    /// let mut pipe = CommandPipe::from(vec![Command::new("vi").arg("notes.txt").inherit_io()]);
+    /// pipe.spawn_with_output()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn inherit_io(mut self) -> Self {
+        self.1.inherit_io = true;
+        self.1.stdout = Some(StdioSpec::Inherit);
+        self
+    }
+
+    /// Sets the first argument the program receives, its `argv[0]`.
+    ///
+    /// By default it's the program as given. Some multi-call programs like
+    /// busybox decide by it what to do.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::Command;
+    /// let cmd = Command::new("/bin/busybox").arg0("ls").arg("-la");
+    /// ```
+    #[cfg(unix)]
+    pub fn arg0<S>(mut self, arg: S) -> Self
+    where
+        S: AsRef<OsStr>,
+    {
+        self.1.arg0 = Some(arg.as_ref().to_owned());
+        self
+    }
+
+    /// Clears the environment of the command, so it doesn't inherit any
+    /// variables from the parent process.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::Command;
+    /// let cmd = Command::new("printenv").env_clear().env("LANG", "C");
+    /// ```
+    pub fn env_clear(mut self) -> Self {
+        self.0.env_clear();
+        self.1.env_clear = true;
+        self
+    }
+
+    /// Runs the command on its own and returns its [Output], killing it if it
+    /// runs longer than `timeout`.
+    ///
+    /// This is a shorthand for a pipe of just this command with
+    /// [CommandPipe::stage_timeout], so it fails with
+    /// [APipeError::StageTimeout] if the command was killed.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::{Command, error::APipeError};
+    /// # use std::time::Duration;
+    /// let result = Command::new("sleep")
+    ///     .arg("10")
+    ///     .run_with_timeout(Duration::from_millis(100));
+    ///
+    /// assert!(matches!(result, Err(APipeError::StageTimeout { stage: 0 })));
+    /// ```
+    pub fn run_with_timeout(self, timeout: Duration) -> Result<Output> {
+        CommandPipe::from(vec![self])
+            .stage_timeout(0, timeout)?
+            .spawn_with_output()
+    }
+
     #[cfg(feature = "parser")]
     /// Constructs a Command from a string including the program and its args.
     ///
+    /// Redirections of file descriptors like `3>&1`, `4>file` or `4> file`
+    /// are applied to the command instead of passed as arguments, see
+    /// [CommandPipe::redirect_fd]. They fail with [APipeError::Unsupported]
+    /// on platforms other than Unix.
+    ///
     /// ## Example
     ///
     /// ```
@@ -116,23 +662,314 @@ impl Command {
             static ref RE: Regex = Regex::new(r#"([^\s"']+)|("[^"]*?")|('[^']*?')"#).unwrap();
         }
 
-        let matches = RE.captures_iter(c);
-        let cmd_parts: Vec<&str> = matches.map(|x| x.get(0).unwrap().as_str()).collect();
+        let mut matches = RE.captures_iter(c).map(|x| x.get(0).unwrap().as_str());
+        let mut cmd_parts: Vec<&str> = Vec::new();
+        let mut fd_redirects = Vec::new();
+
+        while let Some(part) = matches.next() {
+            match parse_fd_redirect(part, &mut matches) {
+                Some(redirect) => fd_redirects.push(redirect?),
+                None => cmd_parts.push(part),
+            }
+        }
 
         let (&cmd, args) = cmd_parts
             .split_first()
-            .ok_or_else(|| crate::error::APipeError::Parser(c.to_owned()))?;
+            .ok_or_else(|| APipeError::Parser(c.to_owned()))?;
 
         let command = Command::new(cmd).args(args);
 
+        #[cfg(unix)]
+        let command = Command(
+            command.0,
+            Options {
+                fd_redirects,
+                ..command.1
+            },
+        );
+        #[cfg(not(unix))]
+        if !fd_redirects.is_empty() {
+            return Err(APipeError::Unsupported("Redirecting file descriptors"));
+        }
+
         Ok(command)
     }
+
+    #[cfg(feature = "parser")]
+    /// Constructs a Command from the start of a string up to the first `|`
+    /// outside of quotes, and returns it with the rest of the string after
+    /// the `|`.
+    ///
+    /// The command is parsed like by [Command::parse_str]. The rest is empty
+    /// if there's no `|`, so a whole pipe can be parsed one command at a
+    /// time.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::{cmd::Command, error::APipeError};
+    /// # fn main() -> Result<(), APipeError> {
+    /// let (cmd, rest) = Command::parse_prefix("echo hi | cat")?;
+    ///
+    /// assert_eq!(cmd.program(), "echo");
+    /// assert_eq!(rest, "cat");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn parse_prefix(s: &str) -> Result<(Self, &str)> {
+        let mut quote = None;
+        let end = s.char_indices().find_map(|(i, c)| {
+            match (c, quote) {
+                ('"' | '\'', None) => quote = Some(c),
+                (c, Some(q)) if c == q => quote = None,
+                ('|', None) => return Some(i),
+                _ => {}
+            }
+            None
+        });
+
+        let (command, rest) = match end {
+            Some(i) => (&s[..i], s[i + 1..].trim_start()),
+            None => (s, ""),
+        };
+
+        Ok((Command::parse_str(command)?, rest))
+    }
 }
 
-#[cfg(all(test, feature = "parser"))]
+/// Parses `part` as a redirection of a file descriptor like `3>&1` or
+/// `3>file`, taking the file from `rest` if it's a separate word.
+///
+/// Returns `None` if `part` isn't a redirection.
+#[cfg(feature = "parser")]
+fn parse_fd_redirect<'a>(
+    part: &str,
+    rest: &mut impl Iterator<Item = &'a str>,
+) -> Option<Result<(i32, FdTarget)>> {
+    let (fd, target) = part.split_once('>')?;
+    if fd.is_empty() || !fd.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    let mut redirect = || {
+        let target = match target.strip_prefix('&') {
+            Some(to) => FdTarget::Fd(to.parse().ok()?),
+            None if target.is_empty() => FdTarget::File(unquote(rest.next()?).into()),
+            None => FdTarget::File(unquote(target).into()),
+        };
+        Some((fd.parse().ok()?, target))
+    };
+
+    Some(redirect().ok_or_else(|| APipeError::Syntax(part.to_owned())))
+}
+
+/// Removes the quotes around a word, if it has any.
+#[cfg(feature = "parser")]
+fn unquote(s: &str) -> &str {
+    ['"', '\'']
+        .iter()
+        .find_map(|&q| s.strip_prefix(q).and_then(|s| s.strip_suffix(q)))
+        .unwrap_or(s)
+}
+
+#[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_accessors() {
+        let cmd = Command::new("grep").arg("-i").args(["foo", "bar"]);
+
+        assert_eq!(cmd.program(), "grep");
+        assert_eq!(
+            cmd.get_args().collect::<Vec<&OsStr>>(),
+            &["-i", "foo", "bar"]
+        );
+        assert_eq!(Command::new("ls").get_args().count(), 0);
+    }
+
+    #[test]
+    fn test_get_current_dir_and_envs() {
+        let cmd = Command::new("ls");
+        assert_eq!(cmd.get_current_dir(), None);
+        assert_eq!(cmd.get_envs().count(), 0);
+
+        let cmd = cmd.current_dir("/tmp").env("FOO", "bar").env("BAZ", "qux");
+        assert_eq!(cmd.get_current_dir(), Some(Path::new("/tmp")));
+
+        let mut envs: Vec<(&OsStr, Option<&OsStr>)> = cmd.get_envs().collect();
+        envs.sort();
+        assert_eq!(
+            envs,
+            &[
+                (OsStr::new("BAZ"), Some(OsStr::new("qux"))),
+                (OsStr::new("FOO"), Some(OsStr::new("bar")))
+            ]
+        );
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_arg0() {
+        let cmd = Command::new("sh")
+            .arg0("custom-sh")
+            .args(["-c", "tr '\\0' ' ' < /proc/$$/cmdline"]);
+
+        // The `argv[0]` can't be read back, so clones must keep it too.
+        let output = cmd.clone().to_process().output().unwrap();
+        assert!(output.stdout.starts_with(b"custom-sh -c "));
+    }
+
+    #[test]
+    fn test_run_with_timeout() {
+        let output = Command::new("echo")
+            .arg("test")
+            .run_with_timeout(Duration::from_secs(10))
+            .unwrap();
+        assert_eq!(output.stdout(), b"test\n");
+
+        let started = std::time::Instant::now();
+        let result = Command::new("sleep")
+            .arg("10")
+            .run_with_timeout(Duration::from_millis(100));
+
+        assert!(matches!(result, Err(APipeError::StageTimeout { stage: 0 })));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_from_parts() {
+        let cmd = Command::from_parts("grep", &["-i", "foo"]);
+
+        assert_eq!(cmd.program(), "grep");
+        assert_eq!(cmd.get_args().collect::<Vec<&OsStr>>(), &["-i", "foo"]);
+
+        let mut pipe = Command::new("echo").arg("FOO") | cmd;
+        assert_eq!(pipe.spawn_with_output().unwrap(), "FOO\n");
+    }
+
+    #[test]
+    fn test_default() {
+        let cmd = Command::default();
+        assert!(cmd.program().is_empty());
+        assert_eq!(cmd.get_args().count(), 0);
+
+        let mut pipe = Command::new("ls") | Command::default();
+        assert!(matches!(
+            pipe.validate(),
+            Err(crate::APipeError::EmptyProgram(1))
+        ));
+        assert!(matches!(
+            pipe.spawn(),
+            Err(crate::APipeError::EmptyProgram(1))
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_relative_program_in_current_dir() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join("apipe_test_relative_program");
+        let script = dir.join("echo_wrapper.sh");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(&script, "#!/bin/sh\necho wrapped \"$@\"\n").unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let mut pipe: CommandPipe = [Command::new("./echo_wrapper.sh")
+            .arg("foo")
+            .current_dir(&dir)]
+        .into_iter()
+        .collect();
+
+        assert_eq!(pipe.spawn_with_output().unwrap(), "wrapped foo\n");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_relative_program_in_relative_current_dir() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // Tests run in the directory of the package.
+        let dir = Path::new("target/apipe_test_relative_current_dir");
+        let script = Path::new(env!("CARGO_MANIFEST_DIR")).join(dir).join("s.sh");
+        std::fs::create_dir_all(script.parent().unwrap()).unwrap();
+        std::fs::write(&script, "#!/bin/sh\necho ok\n").unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let mut pipe: CommandPipe = [Command::new("./s.sh").current_dir(dir)]
+            .into_iter()
+            .collect();
+
+        assert_eq!(pipe.spawn_with_output().unwrap(), "ok\n");
+
+        std::fs::remove_dir_all(script.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_is_relative_path() {
+        assert!(is_relative_path(Path::new("./script.sh")));
+        assert!(is_relative_path(Path::new("../script.sh")));
+        assert!(is_relative_path(Path::new("bin/script.sh")));
+        assert!(!is_relative_path(Path::new("ls")));
+        assert!(!is_relative_path(Path::new("/bin/ls")));
+    }
+
+    #[test]
+    fn test_display() {
+        let cmd = Command::new("grep").args(["-i", "foo bar", "", "it's"]);
+
+        assert_eq!(cmd.to_string(), r"grep -i 'foo bar' '' 'it'\''s'");
+    }
+
+    #[test]
+    fn test_shell_quote() {
+        let quote = |s: &str| shell_quote(OsStr::new(s));
+
+        assert_eq!(quote("--color=never"), "--color=never");
+        assert_eq!(quote("/tmp/file.txt"), "/tmp/file.txt");
+        assert_eq!(quote(""), "''");
+        assert_eq!(quote("$HOME *"), "'$HOME *'");
+        assert_eq!(quote("it's"), r"'it'\''s'");
+    }
+
+    #[cfg(feature = "parser")]
+    #[test]
+    fn test_split_words() {
+        assert_eq!(
+            split_words(r#"-i 'foo bar' "it's" a""b '' \w"#).unwrap(),
+            &["-i", "foo bar", "it's", "ab", "", r"\w"]
+        );
+        assert_eq!(split_words("  ").unwrap(), Vec::<String>::new());
+        assert_eq!(split_words("'foo"), None);
+    }
+
+    #[cfg(feature = "parser")]
+    #[test]
+    fn test_parse_prefix() -> Result<()> {
+        let (cmd, rest) = Command::parse_prefix("echo hi | cat")?;
+        assert_eq!(cmd.program(), "echo");
+        assert_eq!(cmd.get_args().collect::<Vec<&OsStr>>(), &["hi"]);
+        assert_eq!(rest, "cat");
+
+        let (cmd, rest) = Command::parse_prefix(rest)?;
+        assert_eq!(cmd.program(), "cat");
+        assert_eq!(rest, "");
+
+        let (cmd, rest) = Command::parse_prefix("grep 'a|b' | sort | uniq")?;
+        assert_eq!(cmd.get_args().collect::<Vec<&OsStr>>(), &["'a|b'"]);
+        assert_eq!(rest, "sort | uniq");
+
+        assert!(matches!(
+            Command::parse_prefix("| cat"),
+            Err(APipeError::Parser(_))
+        ));
+        Ok(())
+    }
+
+    #[cfg(feature = "parser")]
     #[test]
     fn test_literal_pipe() -> Result<()> {
         let pipe = Command::parse_str(r#"echo "This is a test.""#)?
@@ -0,0 +1,141 @@
+//! Parser for declarative pipe definitions, see
+//! [CommandPipe::from_config_str].
+
+use crate::{cmd::Command, error::APipeError, pipe::CommandPipe, stdio::StdioSpec};
+use serde::Deserialize;
+use std::{collections::BTreeMap, path::PathBuf};
+
+type Result<T> = std::result::Result<T, APipeError>;
+
+/// A pipe as it's written in the definition.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Definition {
+    #[serde(default)]
+    stage: Vec<Stage>,
+}
+
+/// A stage of a pipe as it's written in the definition.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Stage {
+    program: String,
+    #[serde(default)]
+    args: Vec<String>,
+    #[serde(default)]
+    env: BTreeMap<String, String>,
+    cwd: Option<PathBuf>,
+    stdout: Option<StdioSpec>,
+    stdout_file: Option<PathBuf>,
+}
+
+/// Parses a pipe definition into a [CommandPipe].
+pub(crate) fn parse_pipe(s: &str) -> Result<CommandPipe> {
+    let definition: Definition = toml::from_str(s).map_err(|e| match e.span() {
+        Some(span) => {
+            let line = s[..span.start].matches('\n').count() + 1;
+            APipeError::Syntax(format!("line {}: {}", line, e.message()))
+        }
+        None => APipeError::Syntax(e.message().to_owned()),
+    })?;
+    let mut pipe = CommandPipe::with_capacity(definition.stage.len());
+
+    for (i, stage) in definition.stage.into_iter().enumerate() {
+        let mut command = Command::new(stage.program).args(stage.args);
+        for (key, val) in stage.env {
+            command = command.env(key, val);
+        }
+        if let Some(cwd) = stage.cwd {
+            command = command.current_dir(cwd);
+        }
+        command.1.stdout = match (stage.stdout, stage.stdout_file) {
+            (Some(_), Some(_)) => {
+                return Err(APipeError::Syntax(format!(
+                    "stage {} has both `stdout` and `stdout_file`",
+                    i
+                )))
+            }
+            (stdout, None) => stdout,
+            (None, Some(path)) => Some(StdioSpec::File(path)),
+        };
+
+        pipe.pipeline.push(command);
+    }
+
+    Ok(pipe)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsStr;
+
+    #[test]
+    fn test_parse_pipe() {
+        let mut pipe = parse_pipe(
+            r#"
+            # Print the line containing `test`.
+            [[stage]]
+            program = "echo"
+            args = ["This is a test.", '\w']
+
+            [[stage]]
+            program = "sh"
+            args = [
+                "-c",
+                "grep -o test; echo \"$GREETING\"", # with a trailing comma
+            ]
+            cwd = "/"
+
+            [stage.env]
+            GREETING = "hi"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(pipe.pipeline.len(), 2);
+        assert_eq!(pipe.pipeline[1].get_current_dir(), Some("/".as_ref()));
+        assert_eq!(
+            pipe.pipeline[1].get_envs().collect::<Vec<_>>(),
+            &[(OsStr::new("GREETING"), Some(OsStr::new("hi")))]
+        );
+        assert_eq!(pipe.spawn_with_output().unwrap().stdout(), b"test\nhi\n");
+    }
+
+    #[test]
+    fn test_parse_pipe_stdout() {
+        let pipe = parse_pipe(
+            "[[stage]]\nprogram = 'ls'\nstdout = 'null'\n\
+             [[stage]]\nprogram = 'ls'\nstdout_file = '/tmp/out'\n\
+             [[stage]]\nprogram = 'ls'\nstdout = { file = '/tmp/out' }",
+        )
+        .unwrap();
+
+        assert_eq!(pipe.pipeline[0].1.stdout, Some(StdioSpec::Null));
+        assert_eq!(
+            pipe.pipeline[1].1.stdout,
+            Some(StdioSpec::File("/tmp/out".into()))
+        );
+        assert_eq!(pipe.pipeline[2].1.stdout, pipe.pipeline[1].1.stdout);
+    }
+
+    #[test]
+    fn test_parse_pipe_invalid() {
+        let error = |s| match parse_pipe(s) {
+            Err(APipeError::Syntax(msg)) => msg,
+            other => panic!("expected a syntax error, got {:?}", other),
+        };
+
+        assert!(error("program = 'ls'").starts_with("line 1: unknown field `program`"));
+        assert!(error("[[stage]]\nprogram = ls").starts_with("line 2: "));
+        assert!(error("[[stage]]\nargs = '-l'").starts_with("line 2: invalid type"));
+        assert!(error("[[stage]]\nprogram = 'ls'\nfoo = 'x'").contains("unknown field `foo`"));
+        assert!(error("[[stages]]").starts_with("line 1: unknown field `stages`"));
+        assert!(error("[[stage]]\nprogram = 'ls\n").starts_with("line 2: "));
+        assert!(error("[[stage]]\nargs = []").contains("missing field `program`"));
+        assert_eq!(
+            error("[[stage]]\nprogram = 'ls'\nstdout = 'null'\nstdout_file = 'x'"),
+            "stage 0 has both `stdout` and `stdout_file`"
+        );
+    }
+}
@@ -1,11 +1,114 @@
+use crate::cmd::Command;
 use std::error;
 use std::fmt::Display;
+use std::process::ExitStatus;
+
+/// Maximum number of bytes of stderr included in [APipeError::NonZeroExit].
+const MAX_STDERR_LEN: usize = 1024;
 
 #[derive(Debug)]
 pub enum APipeError {
     Parser(String),
-    ChildProcess(std::io::Error, &'static str),
+    /// A child process failed.
+    ChildProcess {
+        source: std::io::Error,
+        /// Description of the operation that failed.
+        context: &'static str,
+        /// The command the operation failed for.
+        command: String,
+        /// Index of the last stage that exited successfully, like all stages
+        /// before it, i.e. how far the pipe got before failing. `None` if no
+        /// stage was waited on before the failure.
+        last_completed: Option<usize>,
+    },
+    /// The command string isn't valid.
+    Syntax(String),
+    /// The output of the pipe was already collected.
     NoRunningProcesses,
+    /// The pipe was never spawned.
+    NotSpawned,
+    /// The pipe doesn't contain any commands, or arguments were added before
+    /// the first one.
+    EmptyPipe,
+    /// The command at the given stage has an empty program.
+    EmptyProgram(usize),
+    /// Two stdio settings of the pipe contradict each other.
+    ConflictingRedirection(&'static str, &'static str),
+    /// The niceness increment is outside of the valid range.
+    InvalidNice(i32),
+    /// The CPU affinity is empty or contains a CPU index that is too large.
+    InvalidCpuAffinity(Vec<usize>),
+    /// The pipe has no stage with the given index.
+    InvalidStage(usize),
+    /// A file descriptor of a redirection is negative.
+    InvalidFd(i32),
+    /// The operation isn't supported on this platform.
+    Unsupported(&'static str),
+    /// The stderr of a stage was piped into a stage that doesn't come after
+    /// it, see [CommandPipe::pipe_stderr](crate::CommandPipe::pipe_stderr).
+    InvalidStderrPipe {
+        /// Index of the stage whose stderr is piped.
+        from: usize,
+        /// Index of the stage whose stdin receives the stderr.
+        to: usize,
+    },
+    /// The output of the pipe isn't valid UTF-8.
+    InvalidUtf8(std::str::Utf8Error),
+    /// The arguments and environment of a command exceed the size the
+    /// operating system allows, see
+    /// [Command::estimated_argv_size](crate::Command::estimated_argv_size).
+    ArgumentListTooLarge {
+        /// Index of the stage that failed to spawn.
+        stage: usize,
+        /// The estimated size of the arguments and environment in bytes.
+        size: usize,
+    },
+    /// The program of a stage can't be executed, e.g. because it lacks the
+    /// permission to.
+    NotExecutable {
+        source: std::io::Error,
+        /// Index of the stage that failed to spawn.
+        stage: usize,
+        /// The program of the stage.
+        program: String,
+    },
+    /// The program of a stage is a directory.
+    IsDirectory {
+        source: std::io::Error,
+        /// Index of the stage that failed to spawn.
+        stage: usize,
+        /// The program of the stage.
+        program: String,
+    },
+    /// The program of a stage isn't in a format the operating system can
+    /// execute.
+    BadFormat {
+        source: std::io::Error,
+        /// Index of the stage that failed to spawn.
+        stage: usize,
+        /// The program of the stage.
+        program: String,
+    },
+    /// A stage was killed because it ran longer than its timeout.
+    StageTimeout {
+        /// Index of the stage that timed out.
+        stage: usize,
+    },
+    /// Running the pipe panicked, e.g. in a callback like
+    /// [CommandPipe::on_stage_stdout](crate::CommandPipe::on_stage_stdout) or
+    /// one of the threads of the pipe, see [CommandPipe::try_spawn_with_output](crate::CommandPipe::try_spawn_with_output).
+    Panicked(String),
+    /// The pipe exited unsuccessfully.
+    NonZeroExit {
+        /// The last command of the pipe.
+        command: String,
+        status: ExitStatus,
+        /// The captured stderr of the pipe, truncated to a reasonable length.
+        stderr: String,
+        /// Index of the last stage that exited successfully, like all stages
+        /// before it, i.e. how far the pipe got before failing.
+        last_completed: Option<usize>,
+    },
 }
 
 impl Display for APipeError {
@@ -14,8 +117,79 @@ impl Display for APipeError {
             APipeError::Parser(ref cmd) => {
                 write!(f, "Tried to parse empty command string: {}", cmd)
             }
-            APipeError::ChildProcess(_, s) => write!(f, "{}", s),
+            APipeError::ChildProcess {
+                ref source,
+                context,
+                ref command,
+                ..
+            } => write!(f, "{} `{}`: {}", context, command, source),
+            APipeError::Syntax(ref cmd) => write!(f, "Invalid command string: {}", cmd),
             APipeError::NoRunningProcesses => write!(f, "No running processes."),
+            APipeError::NotSpawned => write!(f, "The pipe was never spawned."),
+            APipeError::EmptyPipe => write!(f, "The pipe doesn't contain any commands."),
+            APipeError::EmptyProgram(i) => write!(f, "The command of stage {} is empty.", i),
+            APipeError::ConflictingRedirection(a, b) => {
+                write!(f, "Conflicting redirections: `{}` and `{}`.", a, b)
+            }
+            APipeError::InvalidNice(n) => {
+                write!(f, "Niceness increment {} is not in -20..=19.", n)
+            }
+            APipeError::InvalidCpuAffinity(ref cpus) => {
+                write!(f, "Invalid CPU affinity {:?}.", cpus)
+            }
+            APipeError::InvalidStage(i) => write!(f, "No stage with index {} in pipe.", i),
+            APipeError::InvalidFd(fd) => write!(f, "Invalid file descriptor {}.", fd),
+            APipeError::Unsupported(what) => {
+                write!(f, "{} is not supported on this platform.", what)
+            }
+            APipeError::InvalidStderrPipe { from, to } => write!(
+                f,
+                "Can't pipe the stderr of stage {} into stage {}, it has to come later.",
+                from, to
+            ),
+            APipeError::InvalidUtf8(ref e) => write!(f, "Output is not valid UTF-8: {}", e),
+            APipeError::ArgumentListTooLarge { stage, size } => write!(
+                f,
+                "The arguments of stage {} are too large to spawn (about {} bytes). \
+                 Pass large inputs through stdin or a file instead, or split them \
+                 across several runs.",
+                stage, size
+            ),
+            APipeError::NotExecutable {
+                stage, ref program, ..
+            } => write!(
+                f,
+                "Can't run stage {}, `{}` is not executable.",
+                stage, program
+            ),
+            APipeError::IsDirectory {
+                stage, ref program, ..
+            } => write!(
+                f,
+                "Can't run stage {}, `{}` is a directory.",
+                stage, program
+            ),
+            APipeError::BadFormat {
+                stage, ref program, ..
+            } => write!(
+                f,
+                "Can't run stage {}, `{}` is not in an executable format.",
+                stage, program
+            ),
+            APipeError::StageTimeout { stage } => write!(f, "Stage {} timed out.", stage),
+            APipeError::Panicked(ref msg) => write!(f, "Running the pipe panicked: {}", msg),
+            APipeError::NonZeroExit {
+                ref command,
+                status,
+                ref stderr,
+                ..
+            } => {
+                write!(f, "`{}` failed with {}", command, status)?;
+                if !stderr.is_empty() {
+                    write!(f, ": {}", stderr)?;
+                }
+                Ok(())
+            }
         }
     }
 }
@@ -23,8 +197,160 @@ impl Display for APipeError {
 impl error::Error for APipeError {
     fn source(&self) -> Option<&(dyn error::Error + 'static)> {
         match *self {
-            APipeError::ChildProcess(ref e, _) => Some(e),
+            APipeError::ChildProcess { ref source, .. }
+            | APipeError::NotExecutable { ref source, .. }
+            | APipeError::IsDirectory { ref source, .. }
+            | APipeError::BadFormat { ref source, .. } => Some(source),
+            APipeError::InvalidUtf8(ref e) => Some(e),
             _ => None,
         }
     }
 }
+
+impl APipeError {
+    /// Creates a [APipeError::ChildProcess] for `command`, without a
+    /// completed stage, see [APipeError::completed].
+    pub(crate) fn child_process(
+        source: std::io::Error,
+        context: &'static str,
+        command: &Command,
+    ) -> Self {
+        APipeError::ChildProcess {
+            source,
+            context,
+            command: command.to_string(),
+            last_completed: None,
+        }
+    }
+
+    /// Sets the last completed stage of a [APipeError::ChildProcess] from the
+    /// `statuses` of the stages that were waited on.
+    pub(crate) fn completed(mut self, statuses: &[ExitStatus]) -> Self {
+        if let APipeError::ChildProcess {
+            ref mut last_completed,
+            ..
+        } = self
+        {
+            *last_completed = last_successful(statuses);
+        }
+
+        self
+    }
+
+    /// Creates the error for the command of stage `stage` failing to spawn.
+    pub(crate) fn spawn(source: std::io::Error, command: &Command, stage: usize) -> Self {
+        if source.kind() == std::io::ErrorKind::ArgumentListTooLong {
+            return APipeError::ArgumentListTooLarge {
+                stage,
+                size: command.estimated_argv_size(),
+            };
+        }
+
+        #[cfg(unix)]
+        {
+            let program = command.program().to_string_lossy().into_owned();
+            match source.raw_os_error() {
+                // Executing a directory fails with `EACCES` on most systems,
+                // so it's told apart by looking at the program.
+                Some(libc::EACCES) if is_directory(command) => {
+                    return APipeError::IsDirectory {
+                        source,
+                        stage,
+                        program,
+                    }
+                }
+                // Changing into a working directory that can't be searched
+                // fails with `EACCES` as well.
+                Some(libc::EACCES) if can_enter_current_dir(command) => {
+                    return APipeError::NotExecutable {
+                        source,
+                        stage,
+                        program,
+                    }
+                }
+                Some(libc::EISDIR) => {
+                    return APipeError::IsDirectory {
+                        source,
+                        stage,
+                        program,
+                    }
+                }
+                Some(libc::ENOEXEC) => {
+                    return APipeError::BadFormat {
+                        source,
+                        stage,
+                        program,
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        APipeError::child_process(source, "failed to spawn", command)
+    }
+
+    /// Creates a [APipeError::NonZeroExit] for `command` of the stage at index
+    /// `stage`, given the `statuses` of all stages.
+    pub(crate) fn non_zero_exit(
+        command: &Command,
+        stage: usize,
+        statuses: &[ExitStatus],
+        stderr: &[u8],
+    ) -> Self {
+        let mut stderr = String::from_utf8_lossy(stderr).trim_end().to_owned();
+
+        if stderr.len() > MAX_STDERR_LEN {
+            let mut end = MAX_STDERR_LEN;
+            while !stderr.is_char_boundary(end) {
+                end -= 1;
+            }
+            stderr.truncate(end);
+            stderr.push_str("...");
+        }
+
+        APipeError::NonZeroExit {
+            command: command.to_string(),
+            status: statuses[stage],
+            stderr,
+            last_completed: last_successful(statuses),
+        }
+    }
+}
+
+/// Returns the index of the last stage of the leading successful `statuses`.
+fn last_successful(statuses: &[ExitStatus]) -> Option<usize> {
+    statuses
+        .iter()
+        .take_while(|status| status.success())
+        .count()
+        .checked_sub(1)
+}
+
+/// Returns whether the program of `command` is a path to a directory.
+///
+/// Programs without a path are looked up in the `PATH`, where directories are
+/// skipped, so they are never considered one.
+#[cfg(unix)]
+fn is_directory(command: &Command) -> bool {
+    let program = command.program();
+    let bytes = program.as_encoded_bytes();
+    if !bytes.iter().any(|&b| std::path::is_separator(b.into())) {
+        return false;
+    }
+    let program = std::path::Path::new(program);
+
+    match command.0.get_current_dir() {
+        Some(dir) => dir.join(program).is_dir(),
+        None => program.is_dir(),
+    }
+}
+
+/// Returns whether the working directory of `command`, if it has one, can be
+/// entered by the child.
+#[cfg(unix)]
+fn can_enter_current_dir(command: &Command) -> bool {
+    command
+        .0
+        .get_current_dir()
+        .is_none_or(crate::unix::is_searchable)
+}
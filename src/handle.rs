@@ -0,0 +1,143 @@
+//! A handle to a running [CommandPipe].
+
+use crate::{error::APipeError, output::Output, pipe::CommandPipe};
+
+type Result<T> = std::result::Result<T, APipeError>;
+
+/// A pipe that was started in the background.
+///
+/// Created by [CommandPipe::spawn_handle]. The handle owns the running
+/// processes, so the pipe can't be changed while it runs, and its output is
+/// collected by consuming the handle.
+///
+/// ## Example
+///
+/// ```
+/// # fn main() -> Result<(), apipe::error::APipeError> {
+/// use apipe::CommandPipe;
+///
+/// let mut pipe = CommandPipe::new();
+/// pipe.add_command("echo").arg("This is a test.");
+///
+/// let handle = pipe.spawn_handle()?;
+///
+/// // Do something else while the pipe runs.
+///
+/// assert_eq!(handle.join()?.stdout(), "This is a test.\n".as_bytes());
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct PipeHandle(CommandPipe);
+
+impl PipeHandle {
+    /// Starts `pipe` and returns a handle to it.
+    pub(crate) fn start(mut pipe: CommandPipe) -> Result<Self> {
+        pipe.start()?;
+
+        Ok(PipeHandle(pipe))
+    }
+
+    /// Returns the process ids of the stages, in the order of the stages.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
+    /// use apipe::CommandPipe;
+    ///
+    /// let mut pipe = CommandPipe::new();
+    /// pipe.add_command("echo").arg("foo").add_command("cat");
+    ///
+    /// let handle = pipe.spawn_handle()?;
+    ///
+    /// assert_eq!(handle.pids().len(), 2);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn pids(&self) -> Vec<u32> {
+        self.0
+            .children
+            .iter()
+            .map(std::process::Child::id)
+            .collect()
+    }
+
+    /// Kills every stage of the pipe that is still running.
+    ///
+    /// The output of the pipe can still be collected with [PipeHandle::join]
+    /// afterwards.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
+    /// use apipe::CommandPipe;
+    ///
+    /// let mut pipe = CommandPipe::new();
+    /// pipe.add_command("sleep").arg("10");
+    ///
+    /// let mut handle = pipe.spawn_handle()?;
+    /// handle.kill()?;
+    ///
+    /// assert_ne!(handle.join()?.status_code(), Some(0));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn kill(&mut self) -> Result<()> {
+        for (i, child) in self.0.children.iter_mut().enumerate() {
+            child
+                .kill()
+                .map_err(|e| APipeError::child_process(e, "failed to kill", &self.0.pipeline[i]))?;
+        }
+
+        Ok(())
+    }
+
+    /// Waits for the pipe to finish and returns its [Output].
+    pub fn join(mut self) -> Result<Output> {
+        self.0.output()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_join() {
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("echo")
+            .arg("This is a test.")
+            .add_command("grep")
+            .args(["-Eo", r"\w\w\sa[^.]*"]);
+
+        let handle = pipe.spawn_handle().unwrap();
+
+        assert_eq!(handle.pids().len(), 2);
+        assert_eq!(handle.join().unwrap().stdout(), "is a test\n".as_bytes());
+    }
+
+    #[test]
+    fn test_kill() {
+        let started = Instant::now();
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("sleep").arg("10").add_command("cat");
+
+        let mut handle = pipe.spawn_handle().unwrap();
+        handle.kill().unwrap();
+        let output = handle.join().unwrap();
+
+        assert!(output.statuses().iter().all(|status| !status.success()));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_spawn_error() {
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("apipe-does-not-exist");
+
+        assert!(pipe.spawn_handle().is_err());
+    }
+}
@@ -71,15 +71,41 @@
 //! # Ok(())
 //! # }
 //! ```
+//!
+//! ### No shell
+//!
+//! No shell is ever invoked to run a pipe. Each program is executed directly
+//! and its arguments are passed to it literally, so e.g. `echo *.txt` prints
+//! `*.txt`. Use [CommandPipe::via_shell] to opt into shell interpretation.
 
+pub mod builder;
 pub mod cmd;
+#[cfg(feature = "config")]
+mod config;
 pub mod error;
+pub mod handle;
 pub mod output;
 pub mod pipe;
+#[cfg(all(unix, feature = "posix_spawn"))]
+mod posix_spawn;
+pub mod stdio;
+#[cfg(feature = "tokio")]
+pub mod stream;
+#[cfg(unix)]
+mod unix;
 
+#[doc(inline)]
+pub use builder::PipeBuilder;
 #[doc(inline)]
 pub use cmd::Command;
 #[doc(inline)]
 pub use error::APipeError;
 #[doc(inline)]
+pub use handle::PipeHandle;
+#[doc(inline)]
 pub use pipe::CommandPipe;
+#[doc(inline)]
+pub use stdio::{FdTarget, StdioPolicy, StdioSpec};
+#[cfg(feature = "tokio")]
+#[doc(inline)]
+pub use stream::OutputStream;
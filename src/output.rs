@@ -1,14 +1,118 @@
 //! Output type exposing the stdout, stderr and exitcode of an executed pipe.
 
-use std::process;
+use crate::error::APipeError;
+use std::{
+    borrow::Cow,
+    process::{self, ExitStatus},
+    time::Duration,
+};
+
+type Result<T> = std::result::Result<T, APipeError>;
 
 /// Provides a thin wrapper around [std::process::Output]
 #[derive(Clone, Debug, PartialEq, Eq)]
-pub struct Output(process::Output);
+pub struct Output(pub(crate) process::Output, pub(crate) Details);
+
+/// Information about the run of a pipe beyond [std::process::Output].
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Details {
+    pub(crate) stdin_written: usize,
+    pub(crate) tagged_log: Vec<u8>,
+    /// The lines of the tagged log that would have appeared on a terminal.
+    pub(crate) combined: Vec<u8>,
+    pub(crate) statuses: Vec<ExitStatus>,
+    /// The end of the stderr of each stage in the captured stderr.
+    pub(crate) stderr_ends: Vec<usize>,
+    pub(crate) executed: Vec<String>,
+    pub(crate) usages: Vec<Option<ResourceUsage>>,
+}
+
+// The resource usage differs between runs of the same pipe, so it's ignored.
+impl PartialEq for Details {
+    fn eq(&self, other: &Self) -> bool {
+        self.stdin_written == other.stdin_written
+            && self.tagged_log == other.tagged_log
+            && self.combined == other.combined
+            && self.statuses == other.statuses
+            && self.stderr_ends == other.stderr_ends
+            && self.executed == other.executed
+    }
+}
+
+impl Eq for Details {}
+
+/// The resources a stage used, see [Output::resource_usage].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ResourceUsage {
+    /// The CPU time spent running the stage itself.
+    pub user_time: Duration,
+    /// The CPU time the system spent on behalf of the stage.
+    pub sys_time: Duration,
+    /// The largest resident set size of the stage, in bytes.
+    pub max_rss: u64,
+}
+
+/// Everything a run of a pipe produced, see
+/// [CommandPipe::run_full](crate::CommandPipe::run_full).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FullOutput {
+    /// The stdout of the last stage.
+    pub stdout: Vec<u8>,
+    /// The exit statuses of the stages, in the order of the stages.
+    pub statuses: Vec<ExitStatus>,
+    /// The stderr of the stages, in the order of the stages.
+    pub stderr: Vec<Vec<u8>>,
+}
+
+impl From<Output> for FullOutput {
+    fn from(output: Output) -> Self {
+        let mut start = 0;
+        let stderr = output
+            .1
+            .stderr_ends
+            .iter()
+            .map(|&end| {
+                let stage = output.0.stderr[start..end].to_vec();
+                start = end;
+                stage
+            })
+            .collect();
+
+        FullOutput {
+            stdout: output.0.stdout,
+            statuses: output.1.statuses,
+            stderr,
+        }
+    }
+}
 
 impl From<process::Output> for Output {
     fn from(command: process::Output) -> Self {
-        Output(command)
+        Output(command, Details::default())
+    }
+}
+
+impl PartialEq<[u8]> for Output {
+    fn eq(&self, other: &[u8]) -> bool {
+        self.stdout() == other
+    }
+}
+
+impl PartialEq<&[u8]> for Output {
+    fn eq(&self, other: &&[u8]) -> bool {
+        self.stdout() == *other
+    }
+}
+
+impl PartialEq<str> for Output {
+    fn eq(&self, other: &str) -> bool {
+        self.stdout() == other.as_bytes()
+    }
+}
+
+impl PartialEq<&str> for Output {
+    fn eq(&self, other: &&str) -> bool {
+        self.stdout() == other.as_bytes()
     }
 }
 
@@ -17,12 +121,373 @@ impl Output {
     pub fn status_code(&self) -> Option<i32> {
         self.0.status.code()
     }
+    /// Returns the exit code of the pipe, i.e. of its last stage.
+    ///
+    /// Returns `None` if the last stage was terminated by a signal. This is
+    /// the same as [Output::status_code].
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
+    /// use apipe::CommandPipe;
+    ///
+    /// let output = CommandPipe::new().add_command("false").spawn_with_output()?;
+    ///
+    /// assert_eq!(output.exit_code(), Some(1));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn exit_code(&self) -> Option<i32> {
+        self.0.status.code()
+    }
+    /// Returns the exit statuses of all stages, in the order of the stages.
+    ///
+    /// The status of the pipe as a whole, see [Output::status_code], is the
+    /// one of the last stage.
+    pub fn statuses(&self) -> &[ExitStatus] {
+        self.1.statuses.as_slice()
+    }
+    /// Returns the command lines that were executed, one for every stage.
+    ///
+    /// Programs given by name only are shown with the path they were found
+    /// at in the `PATH`, and relative programs resolved against the working
+    /// directory of their command. Arguments are never expanded by `apipe`,
+    /// so they are shown as passed, quoted like by the
+    /// [Display](std::fmt::Display) form of [Command](crate::Command).
+    pub fn executed_commands(&self) -> &[String] {
+        self.1.executed.as_slice()
+    }
+    /// Returns the resources the stage at index `stage` used, like its CPU
+    /// time and peak memory, to find the bottleneck of a pipe.
+    ///
+    /// The usage is only recorded on Unix, and not for a stage that was
+    /// already waited on before the pipe was collected, e.g. by
+    /// [CommandPipe::is_running](crate::CommandPipe::is_running).
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
+    /// use apipe::CommandPipe;
+    ///
+    /// let output = CommandPipe::new()
+    ///     .add_command("seq")
+    ///     .arg("1000")
+    ///     .add_command("wc")
+    ///     .arg("-l")
+    ///     .spawn_with_output()?;
+    ///
+    /// # #[cfg(unix)]
+    /// assert!(output.resource_usage(1).is_some_and(|usage| usage.max_rss > 0));
+    /// assert!(output.resource_usage(2).is_none());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn resource_usage(&self, stage: usize) -> Option<ResourceUsage> {
+        self.1.usages.get(stage).copied().flatten()
+    }
     /// See the `stdout` field of [std::process::Output]
     pub fn stdout(&self) -> &[u8] {
         self.0.stdout.as_slice()
     }
+    /// Compares the captured stdout against the expected bytes.
+    ///
+    /// `Output` can also be compared to `&str` and `&[u8]` directly, which
+    /// does the same:
+    ///
+    /// ```
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
+    /// use apipe::CommandPipe;
+    ///
+    /// let output = CommandPipe::new()
+    ///     .add_command("echo")
+    ///     .arg("This is a test.")
+    ///     .spawn_with_output()?;
+    ///
+    /// assert!(output.stdout_eq("This is a test.\n"));
+    /// assert_eq!(output, "This is a test.\n");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn stdout_eq<B>(&self, expected: B) -> bool
+    where
+        B: AsRef<[u8]>,
+    {
+        self.stdout() == expected.as_ref()
+    }
+    /// Returns the captured stdout as a `&str`.
+    ///
+    /// Fails with [APipeError::InvalidUtf8] if stdout isn't valid UTF-8.
+    pub fn stdout_str(&self) -> Result<&str> {
+        std::str::from_utf8(self.stdout()).map_err(APipeError::InvalidUtf8)
+    }
+    /// Returns a copy of the captured stdout as a `String`.
+    ///
+    /// Fails with [APipeError::InvalidUtf8] if stdout isn't valid UTF-8.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
+    /// use apipe::CommandPipe;
+    ///
+    /// let output = CommandPipe::new()
+    ///     .add_command("echo")
+    ///     .arg("This is a test.")
+    ///     .spawn_with_output()?;
+    ///
+    /// assert_eq!(output.stdout_string()?, "This is a test.\n");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn stdout_string(&self) -> Result<String> {
+        std::str::from_utf8(self.stdout())
+            .map(str::to_owned)
+            .map_err(APipeError::InvalidUtf8)
+    }
+    /// Converts the captured stdout into a `String` without copying it.
+    ///
+    /// Fails with [APipeError::InvalidUtf8] if stdout isn't valid UTF-8.
+    pub fn into_stdout_string(self) -> Result<String> {
+        String::from_utf8(self.0.stdout).map_err(|e| APipeError::InvalidUtf8(e.utf8_error()))
+    }
+    /// Returns the number of bytes captured from stdout and stderr together.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
+    /// use apipe::CommandPipe;
+    ///
+    /// let output = CommandPipe::new()
+    ///     .add_command("sh")
+    ///     .args(["-c", "echo out; echo err >&2"])
+    ///     .capture_stderr()
+    ///     .spawn_with_output()?;
+    ///
+    /// assert_eq!(output.total_bytes(), 8);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn total_bytes(&self) -> usize {
+        self.stdout().len() + self.stderr().len()
+    }
+    /// Returns the number of bytes written to the stdin of the pipe.
+    ///
+    /// If the first command exited before reading all bytes passed to
+    /// [CommandPipe::stdin_bytes](crate::CommandPipe::stdin_bytes), this is
+    /// the number of bytes the pipe accepted before it was closed.
+    pub fn stdin_bytes_written(&self) -> usize {
+        self.1.stdin_written
+    }
+    /// Returns the tagged log of everything the stages wrote to stdout and
+    /// stderr.
+    ///
+    /// Empty unless [CommandPipe::capture_tagged](crate::CommandPipe::capture_tagged)
+    /// was enabled.
+    pub fn tagged_log(&self) -> &[u8] {
+        self.1.tagged_log.as_slice()
+    }
+    /// Returns the stdout of the pipe interleaved with the stderr of all
+    /// stages, like it would have appeared on a terminal.
+    ///
+    /// Unlike [Output::stdout] and [Output::stderr], the lines of both
+    /// streams are kept in the order they were read, without the tags of
+    /// [Output::tagged_log]. Empty unless
+    /// [CommandPipe::capture_tagged](crate::CommandPipe::capture_tagged) was
+    /// enabled.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
+    /// use apipe::CommandPipe;
+    ///
+    /// let output = CommandPipe::new()
+    ///     .add_command("sh")
+    ///     .args(["-c", "echo out; sleep 0.1; echo err >&2"])
+    ///     .capture_tagged()
+    ///     .spawn_with_output()?;
+    ///
+    /// assert_eq!(output.combined(), b"out\nerr\n");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn combined(&self) -> &[u8] {
+        self.1.combined.as_slice()
+    }
     /// See the `stderr` field of [std::process::Output]
+    ///
+    /// Empty unless [CommandPipe::capture_stderr](crate::CommandPipe::capture_stderr)
+    /// was enabled.
     pub fn stderr(&self) -> &[u8] {
         self.0.stderr.as_slice()
     }
+    /// Returns the part of the captured stderr the stage at index `stage`
+    /// wrote.
+    pub(crate) fn stage_stderr(&self, stage: usize) -> &[u8] {
+        let ends = &self.1.stderr_ends;
+        let start = stage.checked_sub(1).and_then(|i| ends.get(i)).copied();
+        let end = ends.get(stage).copied();
+
+        &self.0.stderr[start.unwrap_or(0)..end.unwrap_or(self.0.stderr.len())]
+    }
+    /// Returns the captured stderr as a `&str`.
+    ///
+    /// Fails with [APipeError::InvalidUtf8] if stderr isn't valid UTF-8.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
+    /// use apipe::CommandPipe;
+    ///
+    /// let output = CommandPipe::new()
+    ///     .add_command("sh")
+    ///     .args(["-c", "echo failed >&2"])
+    ///     .capture_stderr()
+    ///     .spawn_with_output()?;
+    ///
+    /// assert_eq!(output.stderr_str()?, "failed\n");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn stderr_str(&self) -> Result<&str> {
+        std::str::from_utf8(self.stderr()).map_err(APipeError::InvalidUtf8)
+    }
+    /// Returns the captured stderr as a string, replacing invalid UTF-8 with
+    /// `U+FFFD REPLACEMENT CHARACTER`.
+    pub fn stderr_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(self.stderr())
+    }
+    /// Returns a copy of the captured stderr as a `String`.
+    ///
+    /// Fails with [APipeError::InvalidUtf8] if stderr isn't valid UTF-8.
+    pub fn stderr_string(&self) -> Result<String> {
+        self.stderr_str().map(str::to_owned)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn echo(s: &str) -> Output {
+        process::Command::new("echo")
+            .arg(s)
+            .output()
+            .unwrap()
+            .into()
+    }
+
+    fn sh(script: &str) -> Output {
+        process::Command::new("sh")
+            .args(["-c", script])
+            .output()
+            .unwrap()
+            .into()
+    }
+
+    #[test]
+    fn test_exit_code() {
+        assert_eq!(sh("exit 0").exit_code(), Some(0));
+        assert_eq!(sh("exit 42").exit_code(), Some(42));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_exit_code_signal() {
+        assert_eq!(sh("kill -9 $$").exit_code(), None);
+    }
+
+    #[test]
+    fn test_stdout_eq() {
+        let output = echo("test");
+
+        assert!(output.stdout_eq("test\n"));
+        assert!(output.stdout_eq(b"test\n"));
+        assert!(!output.stdout_eq("test"));
+    }
+
+    #[test]
+    fn test_stdout_string() {
+        let output = echo("test");
+
+        assert_eq!(output.stdout_str().unwrap(), "test\n");
+        assert_eq!(output.stdout_string().unwrap(), "test\n");
+        assert_eq!(output.into_stdout_string().unwrap(), "test\n");
+    }
+
+    #[test]
+    fn test_stdout_string_invalid_utf8() {
+        let output = Output::from(process::Output {
+            stdout: vec![0xff, 0xfe],
+            ..process::Command::new("true").output().unwrap()
+        });
+
+        assert!(matches!(
+            output.stdout_str(),
+            Err(APipeError::InvalidUtf8(_))
+        ));
+        assert!(matches!(
+            output.stdout_string(),
+            Err(APipeError::InvalidUtf8(_))
+        ));
+        assert!(matches!(
+            output.into_stdout_string(),
+            Err(APipeError::InvalidUtf8(_))
+        ));
+    }
+
+    #[test]
+    fn test_stderr_string() {
+        let output = sh("echo failed >&2");
+
+        assert_eq!(output.stderr_str().unwrap(), "failed\n");
+        assert_eq!(output.stderr_lossy(), "failed\n");
+        assert_eq!(output.stderr_string().unwrap(), "failed\n");
+    }
+
+    #[test]
+    fn test_stderr_string_invalid_utf8() {
+        let output = Output::from(process::Output {
+            stderr: vec![b'a', 0xff],
+            ..process::Command::new("true").output().unwrap()
+        });
+
+        assert!(matches!(
+            output.stderr_str(),
+            Err(APipeError::InvalidUtf8(_))
+        ));
+        assert!(matches!(
+            output.stderr_string(),
+            Err(APipeError::InvalidUtf8(_))
+        ));
+        assert_eq!(output.stderr_lossy(), "a\u{fffd}");
+    }
+
+    #[test]
+    fn test_total_bytes() {
+        assert_eq!(sh("echo out; echo error >&2").total_bytes(), 10);
+        assert_eq!(sh("true").total_bytes(), 0);
+    }
+
+    #[test]
+    fn test_eq_str() {
+        let output = echo("test");
+
+        assert_eq!(output, "test\n");
+        assert_eq!(output, *"test\n");
+        assert_ne!(output, "TEST\n");
+    }
+
+    #[test]
+    fn test_eq_bytes() {
+        let output = echo("test");
+
+        assert_eq!(output, "test\n".as_bytes());
+        assert_eq!(output, *b"test\n".as_slice());
+        assert_ne!(output, b"".as_slice());
+    }
 }
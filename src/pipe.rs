@@ -1,19 +1,213 @@
 //! An anonymous pipe.
 
-use crate::{cmd::Command, error::APipeError, output::Output};
+#[cfg(unix)]
+use crate::cmd::PreExec;
+#[cfg(feature = "tokio")]
+use crate::stream::OutputStream;
+use crate::{
+    builder::{Empty, PipeBuilder},
+    cmd::{printf_format, shell_quote, Command, ErrorHandler, StdoutTap},
+    error::APipeError,
+    handle::PipeHandle,
+    output::{FullOutput, Output, ResourceUsage},
+    stdio::{FdTarget, StdioPolicy, StdioSpec},
+};
 use std::{
-    ffi::OsStr,
+    any::Any,
+    collections::{BTreeMap, VecDeque},
+    ffi::{OsStr, OsString},
+    fmt,
+    io::{self, Read, Write},
     ops,
-    process::{Child, Stdio},
+    path::PathBuf,
+    process::{self, Child, ChildStdin, ChildStdout, ExitStatus, Stdio},
+    sync::{mpsc, Arc, Mutex, PoisonError},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
 type Result<T> = std::result::Result<T, APipeError>;
 
+/// Default size of the buffers used to copy data between threads and stages.
+const DEFAULT_CHUNK_SIZE: usize = 8 * 1024;
+
+/// How often stages with a timeout are checked for having exited.
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// How long to wait before retrying a spawn that failed transiently.
+const SPAWN_RETRY_DELAY: Duration = Duration::from_millis(10);
+
 #[derive(Debug, Default)]
 /// A type representing an anonymous pipe
 pub struct CommandPipe {
     pub(crate) pipeline: Vec<Command>,
-    last_spawned: Option<Child>,
+    pub(crate) children: Vec<Child>,
+    stdin: Option<ChildStdin>,
+    raw_stdin: Option<Stdio>,
+    feeder: Option<JoinHandle<io::Result<usize>>>,
+    tees: Vec<(usize, JoinHandle<io::Result<Vec<u8>>>)>,
+    mergers: Vec<(usize, JoinHandle<io::Result<()>>)>,
+    stderr_readers: Vec<(usize, JoinHandle<io::Result<Vec<u8>>>)>,
+    tagged_log: Option<TaggedLog>,
+    deadlines: Vec<Option<Instant>>,
+    executed: Vec<String>,
+    statuses: Vec<ExitStatus>,
+    actions: Vec<StageAction>,
+    stage_outputs: BTreeMap<usize, Vec<u8>>,
+    collected: Option<Output>,
+    state: State,
+    config: Config,
+}
+
+/// Where the pipe is in its lifecycle.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum State {
+    /// The pipe hasn't been spawned yet.
+    #[default]
+    Fresh,
+    /// The pipe was spawned and its output can be collected.
+    Spawned,
+    /// The output of the pipe was collected.
+    Collected,
+}
+
+/// The result of waiting for all stages of a pipe.
+struct Waited {
+    statuses: Vec<ExitStatus>,
+    usages: Vec<Option<ResourceUsage>>,
+    actions: Vec<StageAction>,
+    /// The first stage that was killed for running past its timeout.
+    timed_out: Option<usize>,
+}
+
+/// How an unsuccessful exit of a stage is handled, see
+/// [CommandPipe::on_stage_error].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StageAction {
+    /// The pipe fails when it's run with [CommandPipe::run_checked].
+    Fail,
+    /// The exit is tolerated, as if the stage succeeded.
+    Ignore,
+    /// The other stages are killed right away and the pipe fails like with
+    /// [StageAction::Fail].
+    Abort,
+}
+
+/// What running a pipe would do, see [CommandPipe::dry_run].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DryRunReport {
+    /// The stages of the pipe, in the order of the stages.
+    pub stages: Vec<DryRunStage>,
+}
+
+/// What running a stage of a pipe would do, see [CommandPipe::dry_run].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DryRunStage {
+    /// The program, with the path it's found at in the `PATH` if it's given
+    /// by name only.
+    pub program: PathBuf,
+    /// The arguments passed to the program.
+    pub args: Vec<OsString>,
+    /// Whether the environment of the parent process is cleared.
+    pub env_clear: bool,
+    /// The environment variables that are set, or removed if `None`.
+    pub env: Vec<(OsString, Option<OsString>)>,
+    /// The working directory, if it's not the one of the parent process.
+    pub current_dir: Option<PathBuf>,
+    /// Where stdin is read from, [StdioSpec::Piped] if it's the previous
+    /// stage or the input of the pipe.
+    pub stdin: StdioSpec,
+    /// Where stdout goes, [StdioSpec::Piped] if it's the next stage or
+    /// captured.
+    pub stdout: StdioSpec,
+    /// Where stderr goes, [StdioSpec::Piped] if it's captured or piped into
+    /// another stage.
+    pub stderr: StdioSpec,
+    /// The other redirected file descriptors, see
+    /// [CommandPipe::redirect_fd].
+    pub fd_redirects: Vec<(i32, FdTarget)>,
+}
+
+/// Settings that apply to the pipe as a whole.
+#[derive(Clone, Debug, Default)]
+struct Config {
+    args_without_command: bool,
+    envs: Vec<(OsString, OsString)>,
+    leading_args: Vec<OsString>,
+    clean_env: bool,
+    output_capacity: usize,
+    stdin: Option<Vec<u8>>,
+    inherit_stdin: bool,
+    keep_stdin_open: bool,
+    stdout: Option<StdioSpec>,
+    default_stdio: StdioPolicy,
+    kill_on_drop: bool,
+    spawn_retries: u32,
+    capture_stderr: bool,
+    quiet: bool,
+    stderr_pipes: BTreeMap<usize, usize>,
+    capture_tagged: bool,
+    max_output_lines: Option<usize>,
+    tail_output_bytes: Option<usize>,
+    wait_all_concurrent: bool,
+    io_chunk_size: Option<usize>,
+    shell: Option<OsString>,
+    verbose: Option<Verbose>,
+    progress: Option<Progress>,
+    #[cfg(unix)]
+    umask: Option<u32>,
+    #[cfg(unix)]
+    nice: Option<i32>,
+    #[cfg(all(unix, feature = "rlimit"))]
+    memory_limit: Option<u64>,
+    #[cfg(all(unix, feature = "rlimit"))]
+    cpu_time_limit: Option<u64>,
+    #[cfg(all(target_os = "linux", feature = "affinity"))]
+    cpu_affinity: Option<Vec<usize>>,
+    #[cfg(windows)]
+    creation_flags: Option<u32>,
+}
+
+impl Clone for CommandPipe {
+    /// Clones the definition of the pipe.
+    ///
+    /// The clone has not been spawned yet, regardless of the state of the
+    /// original pipe.
+    fn clone(&self) -> Self {
+        CommandPipe {
+            pipeline: self.pipeline.clone(),
+            children: Vec::new(),
+            stdin: None,
+            raw_stdin: None,
+            feeder: None,
+            tees: Vec::new(),
+            mergers: Vec::new(),
+            stderr_readers: Vec::new(),
+            tagged_log: None,
+            deadlines: Vec::new(),
+            executed: Vec::new(),
+            statuses: Vec::new(),
+            actions: Vec::new(),
+            stage_outputs: BTreeMap::new(),
+            collected: None,
+            state: State::Fresh,
+            config: self.config.clone(),
+        }
+    }
+}
+
+impl fmt::Display for CommandPipe {
+    /// Formats the commands of the pipe separated by ` | `.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, command) in self.pipeline.iter().enumerate() {
+            if i > 0 {
+                f.write_str(" | ")?;
+            }
+            write!(f, "{}", command)?;
+        }
+
+        Ok(())
+    }
 }
 
 impl ops::BitOr<Command> for CommandPipe {
@@ -25,14 +219,48 @@ impl ops::BitOr<Command> for CommandPipe {
     }
 }
 
+impl FromIterator<Command> for CommandPipe {
+    fn from_iter<I: IntoIterator<Item = Command>>(iter: I) -> Self {
+        let mut pipe = CommandPipe::new();
+        pipe.extend(iter);
+        pipe
+    }
+}
+
+impl Extend<Command> for CommandPipe {
+    fn extend<I: IntoIterator<Item = Command>>(&mut self, iter: I) {
+        self.pipeline.extend(iter);
+    }
+}
+
+impl From<Vec<Command>> for CommandPipe {
+    fn from(pipeline: Vec<Command>) -> Self {
+        pipeline.into_iter().collect()
+    }
+}
+
 #[cfg(feature = "parser")]
 impl TryFrom<&str> for CommandPipe {
     type Error = APipeError;
 
+    /// Parses a pipe from a command string.
+    ///
+    /// The first command may read its stdin from a here-string, e.g.
+    /// `grep foo <<< "foo bar"`. Like in the shell, the quotes around the
+    /// here-string are removed and a newline is appended to it.
     fn try_from(value: &str) -> Result<Self> {
         let mut pipe = CommandPipe::new();
 
-        for cmd in value.split_terminator("|") {
+        for (i, cmd) in split_stages(value).into_iter().enumerate() {
+            let cmd = match split_here_string(cmd) {
+                Some((cmd, input)) if i == 0 => {
+                    pipe.stdin_bytes(parse_here_string(input));
+                    cmd
+                }
+                Some(_) => return Err(APipeError::Syntax(value.to_owned())),
+                None => cmd,
+            };
+
             match Command::parse_str(cmd) {
                 Ok(c) => pipe.pipeline.push(c),
                 Err(e) => return Err(e),
@@ -42,6 +270,119 @@ impl TryFrom<&str> for CommandPipe {
     }
 }
 
+#[cfg(feature = "parser")]
+impl TryFrom<String> for CommandPipe {
+    type Error = APipeError;
+
+    fn try_from(value: String) -> Result<Self> {
+        CommandPipe::try_from(value.as_str())
+    }
+}
+
+#[cfg(feature = "parser")]
+impl TryFrom<&String> for CommandPipe {
+    type Error = APipeError;
+
+    fn try_from(value: &String) -> Result<Self> {
+        CommandPipe::try_from(value.as_str())
+    }
+}
+
+/// Removes the comments from a script and joins its lines.
+///
+/// Like in the shell, a comment starts with a `#` at the beginning of a word
+/// outside of quotes and extends to the end of the line.
+#[cfg(feature = "parser")]
+fn strip_comments(script: &str) -> String {
+    let mut stripped = String::with_capacity(script.len());
+    let mut quote = None;
+    let mut comment = false;
+    let mut prev = ' ';
+
+    for c in script.chars() {
+        match (c, quote) {
+            ('\n', _) if comment || quote.is_none() => {
+                comment = false;
+                stripped.push(' ');
+            }
+            _ if comment => {}
+            ('#', None) if prev.is_whitespace() || prev == '|' => comment = true,
+            ('"' | '\'', None) => {
+                quote = Some(c);
+                stripped.push(c);
+            }
+            (c, Some(q)) if c == q => {
+                quote = None;
+                stripped.push(c);
+            }
+            (c, _) => stripped.push(c),
+        }
+        prev = c;
+    }
+
+    stripped
+}
+
+/// Splits a command string into the strings of its stages.
+///
+/// Pipe characters inside single or double quotes don't separate stages. A
+/// trailing pipe character is ignored.
+#[cfg(feature = "parser")]
+fn split_stages(s: &str) -> Vec<&str> {
+    let mut stages = Vec::new();
+    let mut quote = None;
+    let mut start = 0;
+
+    for (i, c) in s.char_indices() {
+        match (c, quote) {
+            ('"' | '\'', None) => quote = Some(c),
+            (c, Some(q)) if c == q => quote = None,
+            ('|', None) => {
+                stages.push(&s[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    if start < s.len() {
+        stages.push(&s[start..]);
+    }
+
+    stages
+}
+
+/// Splits a stage into the command and its here-string at the first `<<<`.
+///
+/// A `<<<` inside single or double quotes is part of an argument, like in
+/// the shell.
+#[cfg(feature = "parser")]
+fn split_here_string(s: &str) -> Option<(&str, &str)> {
+    let mut quote = None;
+
+    for (i, c) in s.char_indices() {
+        match (c, quote) {
+            ('"' | '\'', None) => quote = Some(c),
+            (c, Some(q)) if c == q => quote = None,
+            ('<', None) if s[i..].starts_with("<<<") => return Some((&s[..i], &s[i + 3..])),
+            _ => {}
+        }
+    }
+
+    None
+}
+
+/// Returns the stdin bytes of a here-string without its quotes.
+#[cfg(feature = "parser")]
+fn parse_here_string(s: &str) -> String {
+    let s = s.trim();
+    let unquoted = ['"', '\'']
+        .iter()
+        .find_map(|&q| s.strip_prefix(q).and_then(|s| s.strip_suffix(q)))
+        .unwrap_or(s);
+
+    format!("{}\n", unquoted)
+}
+
 impl CommandPipe {
     /// Create a new empty pipe.
     ///
@@ -54,10 +395,144 @@ impl CommandPipe {
     pub fn new() -> Self {
         CommandPipe {
             pipeline: Vec::new(),
-            last_spawned: None,
+            children: Vec::new(),
+            stdin: None,
+            raw_stdin: None,
+            feeder: None,
+            tees: Vec::new(),
+            mergers: Vec::new(),
+            stderr_readers: Vec::new(),
+            tagged_log: None,
+            deadlines: Vec::new(),
+            executed: Vec::new(),
+            statuses: Vec::new(),
+            actions: Vec::new(),
+            stage_outputs: BTreeMap::new(),
+            collected: None,
+            state: State::Fresh,
+            config: Config::default(),
         }
     }
 
+    /// Create a new empty pipe with room for `stages` commands.
+    ///
+    /// The pipe behaves exactly like one created with [CommandPipe::new], but
+    /// adding up to `stages` commands doesn't reallocate.
+    ///
+    /// ## Example
+    ///
+    /// ```rust
+    /// # use apipe::CommandPipe;
+    /// let mut pipe = CommandPipe::with_capacity(2);
+    /// pipe.add_command("ls").add_command("wc");
+    /// ```
+    pub fn with_capacity(stages: usize) -> Self {
+        let mut pipe = CommandPipe::new();
+        pipe.pipeline.reserve_exact(stages);
+        pipe
+    }
+
+    /// Create a [PipeBuilder] that checks at compile time that a command is
+    /// added before any arguments.
+    ///
+    /// ## Example
+    ///
+    /// ```rust
+    /// # use apipe::CommandPipe;
+    /// let pipe = CommandPipe::builder()
+    ///     .add_command("ls")
+    ///     .arg("-la")
+    ///     .build();
+    /// ```
+    pub fn builder() -> PipeBuilder<Empty> {
+        PipeBuilder::new()
+    }
+
+    /// Create a pipe with a single command made of `program` and `args`.
+    ///
+    /// See [Command::from_parts]. Further commands can be added with
+    /// [CommandPipe::add_command].
+    ///
+    /// ## Example
+    ///
+    /// ```rust
+    /// # use apipe::CommandPipe;
+    /// let mut pipe = CommandPipe::from_parts("ls", &["-l", "-a"]);
+    /// pipe.add_command("grep").arg("foo");
+    /// ```
+    pub fn from_parts<P, A>(program: P, args: &[A]) -> Self
+    where
+        P: AsRef<OsStr>,
+        A: AsRef<OsStr>,
+    {
+        CommandPipe::from(vec![Command::from_parts(program, args)])
+    }
+
+    /// Create a pipe from already tokenized commands.
+    ///
+    /// Each stage is given as `[program, arg1, arg2, ...]`, so nothing is
+    /// parsed or unquoted. Fails with [APipeError::EmptyProgram] if a stage
+    /// has no tokens.
+    ///
+    /// ## Example
+    ///
+    /// ```rust
+    /// # use apipe::CommandPipe;
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
+    /// let stages = vec![
+    ///     vec!["echo".to_owned(), "This is a test.".to_owned()],
+    ///     vec!["grep".to_owned(), "-o".to_owned(), "test".to_owned()],
+    /// ];
+    /// let mut pipe = CommandPipe::from_token_stages(stages)?;
+    ///
+    /// assert_eq!(pipe.spawn_with_output()?.stdout(), "test\n".as_bytes());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn from_token_stages(stages: Vec<Vec<String>>) -> Result<Self> {
+        stages
+            .into_iter()
+            .enumerate()
+            .map(|(i, tokens)| match tokens.split_first() {
+                Some((program, args)) => Ok(Command::from_parts(program, args)),
+                None => Err(APipeError::EmptyProgram(i)),
+            })
+            .collect()
+    }
+
+    /// Create a pipe from a flat list of arguments, where every argument equal
+    /// to `sep` separates two stages.
+    ///
+    /// This suits command lines that were already split into arguments, like
+    /// the trailing arguments of a CLI, since nothing is parsed or unquoted.
+    /// Fails with [APipeError::EmptyProgram] if a stage has no arguments,
+    /// e.g. because of two separators in a row.
+    ///
+    /// ## Example
+    ///
+    /// ```rust
+    /// # use apipe::CommandPipe;
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
+    /// let args = ["echo", "This is a test.", "|", "grep", "-o", "test"];
+    /// let mut pipe = CommandPipe::from_argv(&args, "|")?;
+    ///
+    /// assert_eq!(pipe.spawn_with_output()?.stdout(), "test\n".as_bytes());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn from_argv<A>(args: &[A], sep: &str) -> Result<Self>
+    where
+        A: AsRef<OsStr>,
+    {
+        args.split(|arg| arg.as_ref() == sep)
+            .enumerate()
+            .map(|(i, stage)| match stage.split_first() {
+                Some((program, args)) => Ok(Command::from_parts(program, args)),
+                None => Err(APipeError::EmptyProgram(i)),
+            })
+            .collect()
+    }
+
     /// Add a command to the pipe.
     ///
     /// The command is passed eiter as an absolute path or as a relative path.
@@ -80,56 +555,123 @@ impl CommandPipe {
         self
     }
 
-    /// Add a single argument to the preceding command in the pipe.
+    /// Add a command to the pipe if `cond` is true, so optional stages don't
+    /// break a chain of calls.
     ///
-    /// Arguments need to be passed one at a time.
+    /// Since [CommandPipe::arg] and [CommandPipe::args] add to the last
+    /// command, which is a different one if `cond` is false, pass a
+    /// [Command] to give the optional stage arguments.
     ///
     /// ## Example
     ///
     /// ```
-    /// # use apipe::CommandPipe;
+    /// # use apipe::{Command, CommandPipe};
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
+    /// let ignore_case = true;
+    ///
     /// let mut pipe = CommandPipe::new();
-    /// pipe.add_command("ls").arg("-la");
+    /// pipe.add_command("echo")
+    ///     .arg("This is a TEST.")
+    ///     .add_command_if(ignore_case, Command::new("tr").args(["A-Z", "a-z"]))
+    ///     .add_command("grep")
+    ///     .arg("test");
+    ///
+    /// assert_eq!(pipe.spawn_with_output()?.stdout(), "this is a test.\n".as_bytes());
+    /// # Ok(())
+    /// # }
     /// ```
-    pub fn arg<S>(&mut self, arg: S) -> &mut Self
+    pub fn add_command_if<C>(&mut self, cond: bool, c: C) -> &mut Self
     where
-        S: AsRef<OsStr>,
+        C: Into<Command>,
     {
-        let command = self
-            .pipeline
-            .pop()
-            .expect("No Command in pipe to add args to.");
+        if cond {
+            self.pipeline.push(c.into());
+        }
 
-        self.pipeline.push(command.arg(arg));
         self
     }
 
-    /// Add multiple arguments to the preceding command in the pipe.
+    /// Add a command at the front of the pipe, so it becomes the first stage.
     ///
-    /// Arguments are passed as a sequence.
+    /// The existing stages move back by one. Since [CommandPipe::arg] and
+    /// [CommandPipe::args] add to the last command, pass a [Command] to give
+    /// the new first stage arguments.
     ///
     /// ## Example
     ///
     /// ```
-    /// # use apipe::CommandPipe;
+    /// # use apipe::{Command, CommandPipe};
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
     /// let mut pipe = CommandPipe::new();
-    /// pipe.add_command("ls").args(vec!["-la", "~/Documents"]);
+    /// pipe.add_command("grep")
+    ///     .arg("test")
+    ///     .prepend_command(Command::new("echo").arg("This is a test."));
+    ///
+    /// assert_eq!(pipe.spawn_with_output()?.stdout(), "This is a test.\n".as_bytes());
+    /// # Ok(())
+    /// # }
     /// ```
-    pub fn args<I, S>(&mut self, args: I) -> &mut Self
+    pub fn prepend_command<C>(&mut self, c: C) -> &mut Self
     where
-        I: IntoIterator<Item = S>,
-        S: AsRef<OsStr>,
+        C: Into<Command>,
+    {
+        self.pipeline.insert(0, c.into());
+        self.config.stderr_pipes = std::mem::take(&mut self.config.stderr_pipes)
+            .into_iter()
+            .map(|(from, to)| (from + 1, to + 1))
+            .collect();
+
+        self
+    }
+
+    /// Replace the command of the stage at index `stage` with `c` and return
+    /// the old one.
+    ///
+    /// The rest of the pipe is unchanged, so settings of the pipe that refer
+    /// to the stage, like a piped stderr, apply to the new command. Fails
+    /// with [APipeError::InvalidStage] if there is no such stage.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::{Command, CommandPipe};
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
+    /// let mut pipe = CommandPipe::new();
+    /// pipe.add_command("echo").arg("This is a test.").add_command("grep").arg("test");
+    ///
+    /// let old = pipe.replace_command(1, Command::new("tr").args(["a-z", "A-Z"]))?;
+    ///
+    /// assert_eq!(old.to_string(), "grep test");
+    /// assert_eq!(pipe.spawn_with_output()?.stdout(), "THIS IS A TEST.\n".as_bytes());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn replace_command<C>(&mut self, stage: usize, c: C) -> Result<Command>
+    where
+        C: Into<Command>,
     {
         let command = self
             .pipeline
-            .pop()
-            .expect("No Command in pipe to add args to.");
+            .get_mut(stage)
+            .ok_or(APipeError::InvalidStage(stage))?;
 
-        self.pipeline.push(command.args(args));
-        self
+        Ok(std::mem::replace(command, c.into()))
     }
 
-    /// Runs the commands in the pipe.
+    /// Remove the stages that provably don't change what flows through the
+    /// pipe.
+    ///
+    /// Only a plain `cat` without arguments between two other stages is
+    /// removed, and only if nothing else is set on it, like a redirection,
+    /// an environment variable or a timeout, and its stderr isn't piped.
+    /// Since such a stage only copies its input to its output, the pipe
+    /// produces the same output with one process less. The stages after a
+    /// removed one move forward, which changes their indices.
+    ///
+    /// Nothing is removed if a setting of the pipe applies to every stage,
+    /// like [CommandPipe::prepend_args_all], [CommandPipe::env_all],
+    /// [CommandPipe::clean_env], [CommandPipe::via_shell] or
+    /// [CommandPipe::capture_tagged].
     ///
     /// ## Example
     ///
@@ -137,172 +679,6856 @@ impl CommandPipe {
     /// # use apipe::CommandPipe;
     /// # fn main() -> Result<(), apipe::error::APipeError> {
     /// let mut pipe = CommandPipe::new();
-    /// pipe.add_command("ls")
-    ///     .args(vec!["-la", "~/Documents"])
+    /// pipe.add_command("echo")
+    ///     .arg("This is a test.")
+    ///     .add_command("cat")
     ///     .add_command("grep")
-    ///     .arg("My_Dir")
-    ///     .spawn()
-    ///     .expect("Failed to spawn pipe.");
+    ///     .arg("test")
+    ///     .simplify();
+    ///
+    /// assert_eq!(pipe.to_string(), "echo 'This is a test.' | grep test");
     /// # Ok(())
     /// # }
     /// ```
-    pub fn spawn(&mut self) -> Result<()> {
-        for command in self.pipeline.iter_mut() {
-            let stdin = self.last_spawned.take().map_or(Stdio::null(), |mut std| {
-                std.stdout.take().map_or(Stdio::null(), Stdio::from)
-            });
+    pub fn simplify(&mut self) -> &mut Self {
+        let config = &self.config;
+        // These change what every stage runs as, so a `cat` isn't plain anymore.
+        if !config.leading_args.is_empty()
+            || !config.envs.is_empty()
+            || config.clean_env
+            || config.shell.is_some()
+            || config.capture_tagged
+        {
+            return self;
+        }
 
-            let mut child = command
-                .0
-                .stdin(stdin)
-                .stdout(Stdio::piped())
-                .spawn()
-                .map_err(|e| APipeError::ChildProcess(e, "Failed to spawn child command"))?;
+        let mut i = 1;
 
-            child.wait().map_err(|e| {
-                APipeError::ChildProcess(e, "Child process exited with error code.")
-            })?;
+        while i + 1 < self.pipeline.len() {
+            let piped = (self.config.stderr_pipes.iter()).any(|(&from, &to)| from == i || to == i);
+            if piped || !self.pipeline[i].is_plain_cat() {
+                i += 1;
+                continue;
+            }
 
-            self.last_spawned.replace(child);
+            self.pipeline.remove(i);
+            self.config.stderr_pipes = std::mem::take(&mut self.config.stderr_pipes)
+                .into_iter()
+                .map(|(from, to)| {
+                    let shift = |stage: usize| if stage > i { stage - 1 } else { stage };
+                    (shift(from), shift(to))
+                })
+                .collect();
         }
 
-        Ok(())
+        self
     }
 
-    /// Spawns all commands in the pipe and returns the [`Output`].
+    /// Connect the stdout of the pipe to the stdin of `other`, returning a
+    /// pipe of the stages of both.
+    ///
+    /// This is the same as appending the stages of `other`, but checks that
+    /// the two pipes can actually be connected: the stdout of the pipe must
+    /// not be redirected and `other` must not have its own stdin. Fails with
+    /// [APipeError::ConflictingRedirection] otherwise. Where the stdout of
+    /// `other` goes and the stderr it pipes into its stages are kept, its
+    /// other settings are replaced by the ones of the pipe.
     ///
     /// ## Example
     ///
     /// ```
     /// # use apipe::CommandPipe;
     /// # fn main() -> Result<(), apipe::error::APipeError> {
-    /// let output = CommandPipe::new()
-    ///     .add_command("echo")
-    ///     .arg("This is a test.")
-    ///     .add_command("grep")
-    ///     .arg("-Eo")
-    ///     .arg(r"\w\w\sa[^.]*")
-    ///     .spawn_with_output()?;
+    /// let mut list = CommandPipe::new();
+    /// list.add_command("ls").arg("/");
     ///
-    /// assert_eq!(output.stdout(), "is a test\n".as_bytes());
+    /// let mut count = CommandPipe::new();
+    /// count.add_command("wc").arg("-l");
+    ///
+    /// let output = list.feed_into(count)?.spawn_with_output()?;
+    /// assert!(!output.stdout().is_empty());
     /// # Ok(())
     /// # }
     /// ```
-    pub fn spawn_with_output(&mut self) -> Result<Output> {
-        self.spawn()?;
-        self.output()
+    pub fn feed_into(mut self, mut other: CommandPipe) -> Result<CommandPipe> {
+        use APipeError::ConflictingRedirection as Conflict;
+
+        if self.config.stdout.is_some() {
+            return Err(Conflict("stdout_to_file", "feed_into"));
+        }
+        let last_stdout = self.pipeline.last().and_then(|c| c.1.stdout.as_ref());
+        if last_stdout.is_some_and(|spec| *spec != StdioSpec::Piped) {
+            return Err(Conflict("set_stage_stdout", "feed_into"));
+        }
+        if other.config.stdin.is_some() {
+            return Err(Conflict("stdin_bytes", "feed_into"));
+        }
+        if other.config.inherit_stdin {
+            return Err(Conflict("inherit_stdin", "feed_into"));
+        }
+        if other.config.keep_stdin_open {
+            return Err(Conflict("no_stdin_close", "feed_into"));
+        }
+        if other.raw_stdin.is_some() {
+            return Err(Conflict("first_stdin_raw", "feed_into"));
+        }
+
+        let offset = self.pipeline.len();
+        self.pipeline.append(&mut other.pipeline);
+        self.config.stdout = other.config.stdout.take();
+        self.config.stderr_pipes.extend(
+            std::mem::take(&mut other.config.stderr_pipes)
+                .into_iter()
+                .map(|(from, to)| (from + offset, to + offset)),
+        );
+
+        Ok(self)
     }
 
-    /// Returns the [`Output`] of the pipe.
+    /// Parses a pipe from a declarative definition in TOML.
+    ///
+    /// Every `[[stage]]` table describes a command with its `program`, and
+    /// optionally `args`, the working directory `cwd`, and where its `stdout`
+    /// goes as a [StdioSpec](crate::stdio::StdioSpec), e.g. `"null"` or
+    /// `{ file = "out.txt" }`, or a file given as `stdout_file`. Environment
+    /// variables are set in a `[stage.env]` table following the stage. Fails
+    /// with [APipeError::Syntax] if the definition isn't valid.
+    ///
+    /// This requires the `config` feature to be enabled.
     ///
     /// ## Example
     ///
     /// ```
     /// # use apipe::CommandPipe;
-    /// let mut pipe = apipe::CommandPipe::new();
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
+    /// let mut pipe = CommandPipe::from_config_str(
+    ///     r#"
+    ///     [[stage]]
+    ///     program = "echo"
+    ///     args = ["This is a test."]
     ///
-    /// pipe.add_command("echo")
-    ///     .arg("This is a test.")
-    ///     .add_command("grep")
-    ///     .arg("-Eo")
-    ///     .arg(r"\w\w\sa[^.]*")
-    ///     .spawn()
-    ///     .expect("Failed to spawn pipe.");
-    ///     
-    /// let output = pipe.output().unwrap();
-    ///     
-    /// assert_eq!(output.stdout(), "is a test\n".as_bytes());
+    ///     [[stage]]
+    ///     program = "grep"
+    ///     args = ["-Eo", '\w\w\sa[^.]*']
+    ///
+    ///     [stage.env]
+    ///     LC_ALL = "C"
+    ///     "#,
+    /// )?;
+    ///
+    /// assert_eq!(pipe.spawn_with_output()?.stdout(), "is a test\n".as_bytes());
+    /// # Ok(())
+    /// # }
     /// ```
-    pub fn output(&mut self) -> Result<Output> {
-        if let Some(last_proc) = self.last_spawned.take() {
-            let output = last_proc.wait_with_output().map_err(|e| {
-                APipeError::ChildProcess(e, "Child process exited with error code.")
-            })?;
-            return Ok(Output::from(output));
-        } else {
-            Err(APipeError::NoRunningProcesses)
-        }
+    #[cfg(feature = "config")]
+    pub fn from_config_str(s: &str) -> Result<Self> {
+        crate::config::parse_pipe(s)
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
 
-    #[test]
-    fn test_arg() {
+    /// Parses a pipe from a script that may span multiple lines and contain
+    /// comments.
+    ///
+    /// A `#` at the beginning of a word starts a comment that extends to the
+    /// end of the line, unless it's quoted. The lines are then joined and
+    /// parsed like by [CommandPipe::try_from].
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
+    /// let mut pipe = CommandPipe::try_from_script(
+    ///     "# Print the line containing `test`.
+    ///      echo 'This is a test.' # the input
+    ///      | grep -o test",
+    /// )?;
+    ///
+    /// assert_eq!(pipe.spawn_with_output()?.stdout(), "test\n".as_bytes());
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[cfg(feature = "parser")]
+    pub fn try_from_script(script: &str) -> Result<Self> {
+        CommandPipe::try_from(strip_comments(script).as_str())
+    }
+
+    /// Parse `s` as a pipe and append its commands to the pipe.
+    ///
+    /// `s` is parsed like by [CommandPipe::try_from], a leading pipe
+    /// character is allowed. A here-string is only accepted if the pipe is
+    /// still empty. If `s` can't be parsed, the pipe is left unchanged.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
+    /// let mut pipe = CommandPipe::new();
+    /// pipe.add_command("echo")
+    ///     .arg("This is a test.")
+    ///     .append_pipe_str("| grep -Eo \\w\\w\\sa[^.]*")?;
+    ///
+    /// assert_eq!(pipe.spawn_with_output()?.stdout(), "is a test\n".as_bytes());
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[cfg(feature = "parser")]
+    pub fn append_pipe_str(&mut self, s: &str) -> Result<&mut Self> {
+        let fragment = s.trim_start();
+        let mut parsed = CommandPipe::try_from(fragment.strip_prefix('|').unwrap_or(fragment))?;
+
+        if let Some(stdin) = parsed.config.stdin.take() {
+            if !self.pipeline.is_empty() {
+                return Err(APipeError::Syntax(s.to_owned()));
+            }
+            self.config.stdin = Some(stdin);
+        }
+        self.pipeline.append(&mut parsed.pipeline);
+
+        Ok(self)
+    }
+
+    /// Add a single argument to the preceding command in the pipe.
+    ///
+    /// Arguments need to be passed one at a time. If there is no preceding
+    /// command, running the pipe fails with [APipeError::EmptyPipe].
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// let mut pipe = CommandPipe::new();
+    /// pipe.add_command("ls").arg("-la");
+    /// ```
+    pub fn arg<S>(&mut self, arg: S) -> &mut Self
+    where
+        S: AsRef<OsStr>,
+    {
+        match self.pipeline.pop() {
+            Some(command) => self.pipeline.push(command.arg(arg)),
+            None => self.config.args_without_command = true,
+        }
+        self
+    }
+
+    /// Set the `argv[0]` of the preceding command in the pipe, see
+    /// [Command::arg0]. If there is no preceding command, running the pipe
+    /// fails with [APipeError::EmptyPipe].
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// let mut pipe = CommandPipe::new();
+    /// pipe.add_command("/bin/busybox").arg0("ls").arg("-la");
+    /// ```
+    #[cfg(unix)]
+    pub fn arg0<S>(&mut self, arg: S) -> &mut Self
+    where
+        S: AsRef<OsStr>,
+    {
+        match self.pipeline.pop() {
+            Some(command) => self.pipeline.push(command.arg0(arg)),
+            None => self.config.args_without_command = true,
+        }
+        self
+    }
+
+    /// Add multiple arguments to the preceding command in the pipe.
+    ///
+    /// Arguments are passed as a sequence. If there is no preceding command,
+    /// running the pipe fails with [APipeError::EmptyPipe].
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// let mut pipe = CommandPipe::new();
+    /// pipe.add_command("ls").args(vec!["-la", "~/Documents"]);
+    /// ```
+    pub fn args<I, S>(&mut self, args: I) -> &mut Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        match self.pipeline.pop() {
+            Some(command) => self.pipeline.push(command.args(args)),
+            None => self.config.args_without_command = true,
+        }
+        self
+    }
+
+    /// Split `s` into arguments and add them to the preceding command in the
+    /// pipe.
+    ///
+    /// Arguments are separated by whitespace. Quotes group words into a single
+    /// argument and are removed, backslashes are kept. Fails with
+    /// [APipeError::Syntax] if a quote isn't closed and with
+    /// [APipeError::EmptyPipe] if there is no command to add the arguments
+    /// to.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
+    /// let mut pipe = CommandPipe::new();
+    /// pipe.add_command("grep").args_str("-i 'foo bar'")?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[cfg(feature = "parser")]
+    pub fn args_str(&mut self, s: &str) -> Result<&mut Self> {
+        let words = crate::cmd::split_words(s).ok_or_else(|| APipeError::Syntax(s.to_owned()))?;
+        let command = self.pipeline.last_mut().ok_or(APipeError::EmptyPipe)?;
+
+        command.0.args(words);
+        Ok(self)
+    }
+
+    /// Set an environment variable for every command in the pipe.
+    ///
+    /// Variables set on an individual [Command] take precedence over the ones
+    /// set for the whole pipe.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// let mut pipe = CommandPipe::new();
+    /// pipe.add_command("printenv")
+    ///     .arg("LANG")
+    ///     .env_all("LANG", "C");
+    /// ```
+    pub fn env_all<K, V>(&mut self, key: K, val: V) -> &mut Self
+    where
+        K: AsRef<OsStr>,
+        V: AsRef<OsStr>,
+    {
+        self.config
+            .envs
+            .push((key.as_ref().to_owned(), val.as_ref().to_owned()));
+        self
+    }
+
+    /// Insert `args` before the arguments of every command in the pipe.
+    ///
+    /// The arguments are added when the pipe is spawned, in the given order
+    /// and each as a single argument, so this is a way to pass a common flag
+    /// like `--color=never` to every stage. Calling it again adds further
+    /// arguments after the ones added before.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
+    /// let output = CommandPipe::new()
+    ///     .add_command("echo")
+    ///     .arg("This is a test.")
+    ///     .add_command("grep")
+    ///     .arg("test")
+    ///     .prepend_args_all(["-n"])
+    ///     .spawn_with_output()?;
+    ///
+    /// assert_eq!(output.stdout(), "1:This is a test.\n".as_bytes());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn prepend_args_all<I, S>(&mut self, args: I) -> &mut Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        self.config
+            .leading_args
+            .extend(args.into_iter().map(|arg| arg.as_ref().to_owned()));
+        self
+    }
+
+    /// Don't inherit the environment of the parent process.
+    ///
+    /// Every command in the pipe starts with an empty environment that only
+    /// contains the variables set via [CommandPipe::env_all] and the ones set
+    /// on the individual [Command].
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// let mut pipe = CommandPipe::new();
+    /// pipe.add_command("printenv")
+    ///     .clean_env()
+    ///     .env_all("PATH", "/bin");
+    /// ```
+    pub fn clean_env(&mut self) -> &mut Self {
+        self.config.clean_env = true;
+        self
+    }
+
+    /// Set where the stdout of the stage at index `stage` is connected to.
+    ///
+    /// By default every stage is [StdioSpec::Piped] into the next one. If a
+    /// stage isn't piped, the next stage reads from the null device.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::{CommandPipe, StdioSpec};
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
+    /// let mut pipe = CommandPipe::new();
+    /// pipe.add_command("echo")
+    ///     .arg("This is a test.")
+    ///     .set_stage_stdout(0, StdioSpec::Null)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn set_stage_stdout(&mut self, stage: usize, stdio: StdioSpec) -> Result<&mut Self> {
+        let command = self
+            .pipeline
+            .get_mut(stage)
+            .ok_or(APipeError::InvalidStage(stage))?;
+
+        command.1.stdout = Some(stdio);
+        Ok(self)
+    }
+
+    /// Feed the given bytes to the stdin of the first command in the pipe.
+    ///
+    /// The bytes are written from a separate thread while the pipe runs. If
+    /// the command exits without reading all of its input, the rest is
+    /// discarded.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
+    /// let output = CommandPipe::new()
+    ///     .add_command("grep")
+    ///     .arg("foo")
+    ///     .stdin_bytes("foo\nbar\n")
+    ///     .spawn_with_output()?;
+    ///
+    /// assert_eq!(output.stdout(), "foo\n".as_bytes());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn stdin_bytes<B>(&mut self, bytes: B) -> &mut Self
+    where
+        B: Into<Vec<u8>>,
+    {
+        self.config.stdin = Some(bytes.into());
+        self
+    }
+
+    /// Let the first command in the pipe read from the stdin of the parent
+    /// process.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// let mut pipe = CommandPipe::new();
+    /// pipe.add_command("grep").arg("foo").inherit_stdin();
+    /// ```
+    pub fn inherit_stdin(&mut self) -> &mut Self {
+        self.config.inherit_stdin = true;
+        self
+    }
+
+    /// Let the first command in the pipe read from `stdio`, e.g. a
+    /// [File](std::fs::File), the read end of a [pipe](std::io::pipe) or the
+    /// [ChildStdout] of another process.
+    ///
+    /// The pipe takes ownership of `stdio` and hands it to the first stage
+    /// the next time it's started. The parent closes its copy right after
+    /// spawning, so the stage sees the end of its input once every other
+    /// copy of the write end was closed. Since it can only be used once,
+    /// later runs and clones of the pipe read from the null device again.
+    /// Can't be combined with the other settings of the stdin of the pipe.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// # use std::io::Write;
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let (reader, mut writer) = std::io::pipe()?;
+    /// writer.write_all(b"This is a test.\n")?;
+    /// drop(writer);
+    ///
+    /// let output = CommandPipe::new()
+    ///     .add_command("cat")
+    ///     .first_stdin_raw(reader)
+    ///     .spawn_with_output()?;
+    ///
+    /// assert_eq!(output.stdout(), b"This is a test.\n");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn first_stdin_raw<S>(&mut self, stdio: S) -> &mut Self
+    where
+        S: Into<Stdio>,
+    {
+        self.raw_stdin = Some(stdio.into());
+        self
+    }
+
+    /// Keep the stdin of the first command in the pipe open after starting
+    /// it, so data can be written to it over time.
+    ///
+    /// Write to it through [CommandPipe::stdin] and close it with
+    /// [CommandPipe::close_stdin], or let [CommandPipe::output] close it.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// # use std::io::Write;
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let mut pipe = CommandPipe::new();
+    /// pipe.add_command("tr")
+    ///     .args(["a-z", "A-Z"])
+    ///     .no_stdin_close()
+    ///     .start()?;
+    ///
+    /// pipe.stdin().expect("stdin is kept open").write_all(b"foo")?;
+    /// pipe.stdin().expect("stdin is kept open").write_all(b"bar")?;
+    /// pipe.close_stdin();
+    ///
+    /// assert_eq!(pipe.output()?.stdout(), b"FOOBAR");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn no_stdin_close(&mut self) -> &mut Self {
+        self.config.keep_stdin_open = true;
+        self
+    }
+
+    /// Write the stdout of the last command in the pipe to a file.
+    ///
+    /// The file is created if it doesn't exist and truncated otherwise. Since
+    /// the output isn't captured, this can't be combined with
+    /// [CommandPipe::spawn_with_output].
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// let mut pipe = CommandPipe::new();
+    /// pipe.add_command("ls").stdout_to_file("listing.txt");
+    /// ```
+    pub fn stdout_to_file<P>(&mut self, path: P) -> &mut Self
+    where
+        P: Into<PathBuf>,
+    {
+        self.config.stdout = Some(StdioSpec::File(path.into()));
+        self
+    }
+
+    /// Choose where the stdin of the first stage and the stdout and stderr of
+    /// the pipe go unless they're set otherwise.
+    ///
+    /// With [StdioPolicy::Capture], the default, the first stage reads from
+    /// the null device, the stdout of the last stage is captured and stderr
+    /// is inherited. [StdioPolicy::Inherit] passes everything through to the
+    /// parent process, e.g. its terminal, and [StdioPolicy::Null] discards
+    /// everything. The more specific settings like
+    /// [CommandPipe::stdin_bytes], [CommandPipe::stdout_to_file],
+    /// [CommandPipe::set_stage_stdout] or [CommandPipe::capture_stderr] still
+    /// take precedence. Unless stdout is captured, the [Output] of the pipe
+    /// has an empty stdout.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::{CommandPipe, StdioPolicy};
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
+    /// let output = CommandPipe::new()
+    ///     .add_command("echo")
+    ///     .arg("This is a test.")
+    ///     .with_default_stdio(StdioPolicy::Null)
+    ///     .spawn_with_output()?;
+    ///
+    /// assert!(output.stdout().is_empty());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn with_default_stdio(&mut self, policy: StdioPolicy) -> &mut Self {
+        self.config.default_stdio = policy;
+        self
+    }
+
+    /// Pre-allocate the buffer the output of the pipe is captured into.
+    ///
+    /// For pipes with a predictable, large output this avoids growing the
+    /// buffer repeatedly while reading. The buffer still grows if the output
+    /// exceeds the given number of bytes.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// let mut pipe = CommandPipe::new();
+    /// pipe.add_command("cat")
+    ///     .arg("large_file.txt")
+    ///     .output_capacity(1024 * 1024);
+    /// ```
+    pub fn output_capacity(&mut self, bytes: usize) -> &mut Self {
+        self.config.output_capacity = bytes;
+        self
+    }
+
+    /// Run every command in the pipe through `shell`.
+    ///
+    /// By default no shell is involved: the program of each command is
+    /// executed directly and its arguments are passed to it literally, so
+    /// globs, variables and quotes have no special meaning. With this setting
+    /// each stage is instead run as `shell -c "<command>"` (`shell /C ...` for
+    /// `cmd`), where `<command>` is the [Display](std::fmt::Display) form of
+    /// the [Command]. Arguments containing whitespace or quotes are quoted
+    /// there, so they still reach the program as a single argument. Only use
+    /// it with commands you trust.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
+    /// let output = CommandPipe::new()
+    ///     .add_command("echo")
+    ///     .arg("$((1+1))")
+    ///     .via_shell("sh")
+    ///     .spawn_with_output()?;
+    ///
+    /// assert_eq!(output.stdout(), "2\n".as_bytes());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn via_shell(&mut self, shell: &str) -> &mut Self {
+        self.config.shell = Some(shell.into());
+        self
+    }
+
+    /// Stop capturing the output of the pipe after `lines` lines, like
+    /// `head -n`.
+    ///
+    /// Once the limit is reached, the remaining output is discarded and the
+    /// commands of the pipe are killed, so a producer of an endless stream
+    /// doesn't keep running. The exit status then reports the commands as
+    /// killed. Only complete lines are kept: the output is cut right after
+    /// the last allowed newline. If the pipe finishes before the limit is
+    /// reached, its whole output is kept, including a final line without a
+    /// newline.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
+    /// let output = CommandPipe::new()
+    ///     .add_command("yes")
+    ///     .max_output_lines(2)
+    ///     .spawn_with_output()?;
+    ///
+    /// assert_eq!(output.stdout(), "y\ny\n".as_bytes());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn max_output_lines(&mut self, lines: usize) -> &mut Self {
+        self.config.max_output_lines = Some(lines);
+        self
+    }
+
+    /// Keep only the last `bytes` bytes of the stdout of the pipe.
+    ///
+    /// Earlier output is discarded while it's read, so the memory needed
+    /// stays bounded for commands that produce a lot of output of which only
+    /// the end matters, like logs. Together with
+    /// [CommandPipe::max_output_lines] the last bytes of the allowed lines
+    /// are kept.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
+    /// let output = CommandPipe::new()
+    ///     .add_command("seq")
+    ///     .arg("1000")
+    ///     .tail_output_bytes(8)
+    ///     .spawn_with_output()?;
+    ///
+    /// assert_eq!(output.stdout(), "99\n1000\n".as_bytes());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn tail_output_bytes(&mut self, bytes: usize) -> &mut Self {
+        self.config.tail_output_bytes = Some(bytes);
+        self
+    }
+
+    /// Set the file mode creation mask of every command in the pipe.
+    ///
+    /// Files created by the commands don't get the permissions set in `mask`,
+    /// e.g. a mask of `0o077` makes new files only accessible by their owner.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// let mut pipe = CommandPipe::new();
+    /// pipe.add_command("touch").arg("private.txt").with_umask(0o077);
+    /// ```
+    #[cfg(unix)]
+    pub fn with_umask(&mut self, mask: u32) -> &mut Self {
+        self.config.umask = Some(mask);
+        self
+    }
+
+    /// Run every command in the pipe with its niceness raised by `increment`.
+    ///
+    /// The increment must be in the range `-20..=19`, otherwise spawning the
+    /// pipe fails with [APipeError::InvalidNice]. Lowering the niceness, i.e.
+    /// raising the priority, usually requires elevated privileges and makes
+    /// spawning fail without them.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// let mut pipe = CommandPipe::new();
+    /// pipe.add_command("gzip").arg("large_file.txt").nice(10);
+    /// ```
+    #[cfg(unix)]
+    pub fn nice(&mut self, increment: i32) -> &mut Self {
+        self.config.nice = Some(increment);
+        self
+    }
+
+    /// Capture the stderr of every command in the pipe.
+    ///
+    /// The stderr of all stages is returned concatenated in the order of the
+    /// stages by [Output::stderr]. By default stderr is inherited from the
+    /// parent process.
+    ///
+    /// Stdout and stderr are read concurrently and kept apart, so
+    /// [Output::stdout] still only contains the stdout of the last stage,
+    /// however much a stage writes to either stream.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
+    /// let output = CommandPipe::new()
+    ///     .add_command("ls")
+    ///     .arg("/nonexistent")
+    ///     .capture_stderr()
+    ///     .spawn_with_output()?;
+    ///
+    /// assert!(!output.stderr().is_empty());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn capture_stderr(&mut self) -> &mut Self {
+        self.config.capture_stderr = true;
+        self
+    }
+
+    /// Discard the stderr of every command in the pipe.
+    ///
+    /// The stderr of all stages is connected to the null device instead of
+    /// being inherited from the parent process. Capturing stderr with
+    /// [CommandPipe::capture_stderr] or [CommandPipe::capture_tagged] takes
+    /// precedence, so errors are still reported by [CommandPipe::run_checked].
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
+    /// let output = CommandPipe::new()
+    ///     .add_command("ls")
+    ///     .arg("/nonexistent")
+    ///     .quiet()
+    ///     .spawn_with_output()?;
+    ///
+    /// assert_ne!(output.status_code(), Some(0));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn quiet(&mut self) -> &mut Self {
+        self.config.quiet = true;
+        self
+    }
+
+    /// Pipe the stderr of the stage at index `from` into the stdin of the
+    /// stage at index `to`, like `2>&1 |` in a shell.
+    ///
+    /// The stage at `to` reads the stderr of `from` merged with the stdout of
+    /// its preceding stage. Both are copied by their own threads, so chunks
+    /// of them may interleave. The stderr of `from` is no longer returned by
+    /// [Output::stderr]. Fails with [APipeError::InvalidStage] if there is no
+    /// stage `to` and with [APipeError::InvalidStderrPipe] if `to` doesn't
+    /// come after `from`.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
+    /// let output = CommandPipe::new()
+    ///     .add_command("ls")
+    ///     .arg("/nonexistent")
+    ///     .add_command("wc")
+    ///     .arg("-l")
+    ///     .pipe_stderr(0, 1)?
+    ///     .spawn_with_output()?;
+    ///
+    /// assert_eq!(output.stdout_string()?.trim(), "1");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn pipe_stderr(&mut self, from: usize, to: usize) -> Result<&mut Self> {
+        if to >= self.pipeline.len() {
+            return Err(APipeError::InvalidStage(to));
+        }
+        if from >= to {
+            return Err(APipeError::InvalidStderrPipe { from, to });
+        }
+
+        self.config.stderr_pipes.insert(from, to);
+        Ok(self)
+    }
+
+    /// Call `f` with the total number of bytes of output read so far, every
+    /// time another chunk of the stdout of the pipe was read.
+    ///
+    /// This allows showing the progress of pipes with a lot of output. The
+    /// count includes output that is discarded because of
+    /// [CommandPipe::tail_output_bytes], so after the last call it's the full
+    /// length of the stdout. Output that's read after
+    /// [CommandPipe::max_output_lines] was reached isn't counted.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
+    /// CommandPipe::new()
+    ///     .add_command("seq")
+    ///     .arg("100000")
+    ///     .on_progress(|bytes| eprintln!("{} bytes read", bytes))
+    ///     .spawn_with_output()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn on_progress<F>(&mut self, f: F) -> &mut Self
+    where
+        F: FnMut(u64) + Send + 'static,
+    {
+        self.config.progress = Some(Progress(Arc::new(Mutex::new(f))));
+        self
+    }
+
+    /// Print the pipe to `w` every time before it's started, like `set -x` in
+    /// a shell.
+    ///
+    /// The line is prefixed with `+ ` and the commands are formatted like by
+    /// the [Display](fmt::Display) implementation of the pipe. Failing to
+    /// write it doesn't keep the pipe from running.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
+    /// CommandPipe::new()
+    ///     .add_command("echo")
+    ///     .arg("This is a test.")
+    ///     .verbose(std::io::stderr())
+    ///     .spawn()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn verbose<W>(&mut self, w: W) -> &mut Self
+    where
+        W: Write + Send + 'static,
+    {
+        self.config.verbose = Some(Verbose(Arc::new(Mutex::new(w))));
+        self
+    }
+
+    /// Returns the pipe as a command line that reproduces it when run by a
+    /// POSIX shell, e.g. to log it or to run it by hand.
+    ///
+    /// Unlike the [Display](fmt::Display) form, every word is quoted so the
+    /// shell passes it on literally, and the settings of the pipe are written
+    /// out: environment variables as assignments or with `env`, working
+    /// directories with `cd`, the shell of [CommandPipe::via_shell], and the
+    /// redirections of stdin, stdout, stderr and other file descriptors.
+    /// Settings that are applied in the child before it executes the
+    /// program, like [CommandPipe::with_umask], and stderr piped into a stage
+    /// other than the next one aren't included. Neither is the stdin of
+    /// [CommandPipe::first_stdin_raw], which can't be written down, so the
+    /// first stage reads the stdin of the shell instead.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// let mut pipe = CommandPipe::new();
+    /// pipe.add_command("echo")
+    ///     .arg("$HOME")
+    ///     .add_command("tr")
+    ///     .args(["a-z", "A-Z"])
+    ///     .stdout_to_file("out.txt");
+    ///
+    /// assert_eq!(
+    ///     pipe.to_shell_line(),
+    ///     "echo '$HOME' < /dev/null | tr a-z A-Z > out.txt"
+    /// );
+    /// ```
+    pub fn to_shell_line(&self) -> String {
+        let mut line = String::new();
+
+        if let Some(ref stdin) = self.config.stdin {
+            line.push_str("printf ");
+            // An argument can't hold every byte, but escapes in the format can.
+            match std::str::from_utf8(stdin) {
+                Ok(stdin) if !stdin.contains('\0') => {
+                    line.push_str("'%s' ");
+                    line.push_str(&shell_quote(OsStr::new(stdin)));
+                }
+                _ => line.push_str(&shell_quote(OsStr::new(&printf_format(stdin)))),
+            }
+            line.push_str(" | ");
+        }
+        for i in 0..self.pipeline.len() {
+            if i > 0 {
+                line.push_str(" | ");
+            }
+            line.push_str(&self.shell_stage(i));
+        }
+
+        line
+    }
+
+    /// Returns the stage at index `stage` as a shell command, see
+    /// [CommandPipe::to_shell_line].
+    fn shell_stage(&self, stage: usize) -> String {
+        let leading;
+        let command = match self.config.leading_args.as_slice() {
+            [] => &self.pipeline[stage],
+            args => {
+                leading = self.pipeline[stage].with_leading_args(args);
+                &leading
+            }
+        };
+        let mut words = Vec::new();
+
+        let clear = self.config.clean_env || command.1.env_clear;
+        let removed: Vec<&OsStr> = (command.0.get_envs())
+            .filter_map(|(key, val)| val.is_none().then_some(key))
+            .collect();
+        if clear || !removed.is_empty() {
+            words.push("env".to_owned());
+            if clear {
+                words.push("-i".to_owned());
+            }
+            for key in removed {
+                words.push(format!("-u {}", shell_quote(key)));
+            }
+        }
+        let envs = (self.config.envs.iter())
+            .map(|(key, val)| (key.as_os_str(), val.as_os_str()))
+            .chain(command.0.get_envs().filter_map(|(k, v)| Some((k, v?))));
+        for (key, val) in envs {
+            words.push(format!("{}={}", key.to_string_lossy(), shell_quote(val)));
+        }
+
+        match &self.config.shell {
+            Some(shell) => {
+                let shell_command = shell_command(shell, command);
+                words.push(shell_quote(shell));
+                words.extend(shell_command.get_args().map(shell_quote));
+            }
+            None => {
+                words.push(shell_quote(command.0.get_program()));
+                words.extend(command.0.get_args().map(shell_quote));
+            }
+        }
+
+        let reads_stdin = self.config.stdin.is_some()
+            || self.raw_stdin.is_some()
+            || self.config.inherit_stdin
+            || self.config.keep_stdin_open
+            || self.config.default_stdio == StdioPolicy::Inherit
+            || command.1.inherit_io;
+        if stage == 0 && !reads_stdin {
+            words.push("< /dev/null".to_owned());
+        }
+        if self.config.stderr_pipes.get(&stage) == Some(&(stage + 1)) {
+            words.push("2>&1".to_owned());
+        } else if (self.config.quiet || self.config.default_stdio == StdioPolicy::Null)
+            && !self.config.capture_stderr
+            && !self.config.capture_tagged
+        {
+            words.push("2> /dev/null".to_owned());
+        }
+        let last = stage + 1 == self.pipeline.len();
+        let default_stdout = self.config.default_stdio.stdout();
+        let stdout = (command.1.stdout.as_ref())
+            .or(self.config.stdout.as_ref().filter(|_| last))
+            .or(default_stdout.as_ref().filter(|_| last));
+        match stdout {
+            Some(StdioSpec::File(path)) => words.push(format!("> {}", shell_quote(path.as_ref()))),
+            Some(StdioSpec::Null) => words.push("> /dev/null".to_owned()),
+            _ => {}
+        }
+        #[cfg(unix)]
+        for (fd, target) in &command.1.fd_redirects {
+            words.push(match target {
+                FdTarget::Fd(to) => format!("{}>&{}", fd, to),
+                FdTarget::File(path) => format!("{}> {}", fd, shell_quote(path.as_ref())),
+                FdTarget::Null => format!("{}> /dev/null", fd),
+            });
+        }
+
+        let words = words.join(" ");
+        match command.0.get_current_dir() {
+            Some(dir) => format!("(cd {} && {})", shell_quote(dir.as_ref()), words),
+            None => words,
+        }
+    }
+
+    /// Returns what running the pipe would do, without spawning anything,
+    /// e.g. for a `--dry-run` flag.
+    ///
+    /// Unlike [CommandPipe::to_shell_line], the report is structured: for
+    /// every stage it has the resolved program, the arguments, the
+    /// environment, the working directory and the redirections. Settings of
+    /// the pipe, like [CommandPipe::env_all] or [CommandPipe::via_shell], are
+    /// applied to the stages. The pipe isn't validated, see
+    /// [CommandPipe::validate].
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::{CommandPipe, StdioSpec};
+    /// let mut pipe = CommandPipe::new();
+    /// pipe.add_command("echo")
+    ///     .arg("This is a test.")
+    ///     .add_command("grep")
+    ///     .arg("test")
+    ///     .stdout_to_file("out.txt");
+    ///
+    /// let report = pipe.dry_run();
+    ///
+    /// assert!(report.stages[1].program.ends_with("grep"));
+    /// assert_eq!(report.stages[1].args, ["test"]);
+    /// assert_eq!(report.stages[1].stdout, StdioSpec::File("out.txt".into()));
+    /// ```
+    pub fn dry_run(&self) -> DryRunReport {
+        let last = self.pipeline.len().saturating_sub(1);
+        let default_stdout = self.config.default_stdio.stdout();
+        let reads_stdin =
+            self.config.stdin.is_some() || self.raw_stdin.is_some() || self.config.keep_stdin_open;
+
+        let stages = (self.pipeline.iter().enumerate())
+            .map(|(i, command)| {
+                let prepared = self.prepare(i);
+
+                let stdin = if command.1.inherit_io {
+                    StdioSpec::Inherit
+                } else if i > 0 || reads_stdin {
+                    StdioSpec::Piped
+                } else if self.config.inherit_stdin
+                    || self.config.default_stdio == StdioPolicy::Inherit
+                {
+                    StdioSpec::Inherit
+                } else {
+                    StdioSpec::Null
+                };
+                let stdout = (command.1.stdout.as_ref())
+                    .or(self.config.stdout.as_ref().filter(|_| i == last))
+                    .or(default_stdout.as_ref().filter(|_| i == last))
+                    .cloned()
+                    .unwrap_or(StdioSpec::Piped);
+                let stderr = if command.1.inherit_io {
+                    StdioSpec::Inherit
+                } else if self.config.capture_stderr
+                    || self.config.capture_tagged
+                    || self.config.stderr_pipes.contains_key(&i)
+                {
+                    StdioSpec::Piped
+                } else if self.config.quiet || self.config.default_stdio == StdioPolicy::Null {
+                    StdioSpec::Null
+                } else {
+                    StdioSpec::Inherit
+                };
+
+                DryRunStage {
+                    program: resolve_program(&prepared),
+                    args: prepared.get_args().map(OsStr::to_os_string).collect(),
+                    env_clear: self.config.clean_env || command.1.env_clear,
+                    env: (prepared.get_envs())
+                        .map(|(key, val)| (key.to_os_string(), val.map(OsStr::to_os_string)))
+                        .collect(),
+                    current_dir: prepared.get_current_dir().map(PathBuf::from),
+                    stdin,
+                    stdout,
+                    stderr,
+                    #[cfg(unix)]
+                    fd_redirects: command.1.fd_redirects.clone(),
+                    #[cfg(not(unix))]
+                    fd_redirects: Vec::new(),
+                }
+            })
+            .collect();
+
+        DryRunReport { stages }
+    }
+
+    /// Capture the stdout and stderr of every stage into a single log with
+    /// each line tagged with its origin.
+    ///
+    /// Lines are prefixed with `[stageN:out] ` or `[stageN:err] ` and appear
+    /// in the log in the order they were read, which gives an overview of
+    /// what every stage emitted and when. The log is available through
+    /// [Output::tagged_log]. The output of the stages still flows through
+    /// the pipe as usual, and stderr is also returned by [Output::stderr].
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
+    /// let output = CommandPipe::new()
+    ///     .add_command("echo")
+    ///     .arg("This is a test.")
+    ///     .add_command("grep")
+    ///     .arg("test")
+    ///     .capture_tagged()
+    ///     .spawn_with_output()?;
+    ///
+    /// let log = String::from_utf8_lossy(output.tagged_log());
+    ///
+    /// assert!(log.contains("[stage0:out] This is a test.\n"));
+    /// assert!(log.contains("[stage1:out] This is a test.\n"));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn capture_tagged(&mut self) -> &mut Self {
+        self.config.capture_tagged = true;
+        self
+    }
+
+    /// Wraps `source`, the `stream` of the stage at index `stage`, so
+    /// everything read from it is added to the tagged log if there is one.
+    fn tagged<R>(&self, source: R, stage: usize, stream: &str) -> Box<dyn Read + Send>
+    where
+        R: Read + Send + 'static,
+    {
+        match &self.tagged_log {
+            Some(log) => Box::new(Tagged {
+                inner: source,
+                log: Arc::clone(log),
+                tag: format!("[stage{}:{}] ", stage, stream),
+                line: Vec::new(),
+            }),
+            None => Box::new(source),
+        }
+    }
+
+    /// Limit the address space of every command in the pipe to `bytes`.
+    ///
+    /// The limit is applied with `setrlimit(RLIMIT_AS)` before each command
+    /// starts, so allocations beyond it fail and the command usually exits
+    /// with an error or is killed. Note that the limit covers virtual memory,
+    /// which some runtimes reserve far more of than they use, and that not
+    /// every platform enforces it (e.g. macOS doesn't). Processes started by
+    /// the commands inherit the limit.
+    ///
+    /// Requires the `rlimit` feature.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// let mut pipe = CommandPipe::new();
+    /// pipe.add_command("sort")
+    ///     .arg("large_file.txt")
+    ///     .limit_memory(512 * 1024 * 1024);
+    /// ```
+    #[cfg(all(unix, feature = "rlimit"))]
+    pub fn limit_memory(&mut self, bytes: u64) -> &mut Self {
+        self.config.memory_limit = Some(bytes);
+        self
+    }
+
+    /// Limit the CPU time of every command in the pipe to `secs` seconds.
+    ///
+    /// The limit is applied with `setrlimit(RLIMIT_CPU)` before each command
+    /// starts. A command exceeding it is killed by the operating system,
+    /// with `SIGXCPU` or `SIGKILL` depending on the platform. Only time spent computing counts, not
+    /// time spent waiting, so this doesn't replace a timeout. Processes
+    /// started by the commands inherit the limit.
+    ///
+    /// Requires the `rlimit` feature.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// let mut pipe = CommandPipe::new();
+    /// pipe.add_command("gzip").arg("large_file.txt").limit_cpu_time(60);
+    /// ```
+    #[cfg(all(unix, feature = "rlimit"))]
+    pub fn limit_cpu_time(&mut self, secs: u64) -> &mut Self {
+        self.config.cpu_time_limit = Some(secs);
+        self
+    }
+
+    /// Pin every command in the pipe to the CPUs with the given indices.
+    ///
+    /// The affinity is applied with `sched_setaffinity` before each command
+    /// starts, e.g. to get reproducible benchmarks or to keep a heavy pipe
+    /// off the cores of other work. Processes started by the commands
+    /// inherit it. Validating or spawning the pipe fails with
+    /// [APipeError::InvalidCpuAffinity] if `cpus` is empty or an index isn't
+    /// below `CPU_SETSIZE`, and spawning fails if none of the CPUs is
+    /// available to the process.
+    ///
+    /// Requires the `affinity` feature and is only available on Linux.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// let mut pipe = CommandPipe::new();
+    /// pipe.add_command("gzip").arg("large_file.txt").cpu_affinity(&[0, 1]);
+    /// ```
+    #[cfg(all(target_os = "linux", feature = "affinity"))]
+    pub fn cpu_affinity(&mut self, cpus: &[usize]) -> &mut Self {
+        self.config.cpu_affinity = Some(cpus.to_vec());
+        self
+    }
+
+    /// Wraps the stdout of the stage at index `stage`, so everything read
+    /// from it is added to the tagged log and passed to the stdout tap of the
+    /// stage if there are any.
+    fn stdout_reader<R>(&self, source: R, stage: usize) -> Box<dyn Read + Send>
+    where
+        R: Read + Send + 'static,
+    {
+        let source = self.tagged(source, stage, "out");
+
+        match &self.pipeline[stage].1.stdout_tap {
+            Some(tap) => Box::new(Tapped {
+                inner: source,
+                tap: tap.clone(),
+            }),
+            None => source,
+        }
+    }
+
+    /// Wraps the stdout of the pipe, so the progress callback is called for
+    /// everything read from it if there is one.
+    fn progress_reader(&self, source: Box<dyn Read + Send>) -> Box<dyn Read + Send> {
+        match &self.config.progress {
+            Some(progress) => Box::new(Counted {
+                inner: source,
+                progress: progress.clone(),
+                total: 0,
+            }),
+            None => source,
+        }
+    }
+
+    /// Set the size of the chunks data is copied in by the threads feeding
+    /// stdin and capturing the output of stages.
+    ///
+    /// Defaults to 8 KiB. Pipes with a high throughput can use larger chunks
+    /// to reduce the number of system calls. A size of `0` is treated as `1`.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// let mut pipe = CommandPipe::new();
+    /// pipe.add_command("cat")
+    ///     .stdin_bytes(vec![0; 1024 * 1024])
+    ///     .io_chunk_size(64 * 1024);
+    /// ```
+    pub fn io_chunk_size(&mut self, bytes: usize) -> &mut Self {
+        self.config.io_chunk_size = Some(bytes.max(1));
+        self
+    }
+
+    fn chunk_size(&self) -> usize {
+        self.config.io_chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE)
+    }
+
+    /// Set the [process creation flags] for every command in the pipe.
+    ///
+    /// The flags are applied to each stage when the pipe is spawned, e.g.
+    /// `CREATE_NO_WINDOW` (`0x08000000`) keeps console programs from opening
+    /// a window when spawned from a GUI application.
+    ///
+    /// [process creation flags]: https://learn.microsoft.com/en-us/windows/win32/procthread/process-creation-flags
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// const CREATE_NO_WINDOW: u32 = 0x08000000;
+    ///
+    /// let mut pipe = CommandPipe::new();
+    /// pipe.add_command("cmd").creation_flags(CREATE_NO_WINDOW);
+    /// ```
+    #[cfg(windows)]
+    pub fn creation_flags(&mut self, flags: u32) -> &mut Self {
+        self.config.creation_flags = Some(flags);
+        self
+    }
+
+    /// Returns a new, not yet spawned pipe with the same definition.
+    ///
+    /// The commands with their arguments, environment and working directory
+    /// as well as all settings of the pipe are copied, the state of a run
+    /// isn't. Unlike [Clone::clone], the definition is checked with
+    /// [CommandPipe::validate] first, so the template is known to be
+    /// runnable.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
+    /// let mut pipe = CommandPipe::new();
+    /// pipe.add_command("echo").arg("This is a test.").spawn()?;
+    ///
+    /// let mut again = pipe.clone_template()?;
+    ///
+    /// assert_eq!(again.spawn_with_output()?, pipe.output()?);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn clone_template(&self) -> Result<CommandPipe> {
+        self.validate()?;
+
+        Ok(self.clone())
+    }
+
+    /// Runs the commands in the pipe and waits for them to finish.
+    ///
+    /// The output of the pipe is collected before returning and kept until
+    /// it's retrieved with [CommandPipe::output], which then doesn't have to
+    /// wait on anything. Use [CommandPipe::run] to chain both calls.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
+    /// let mut pipe = CommandPipe::new();
+    /// pipe.add_command("ls")
+    ///     .args(vec!["-la", "~/Documents"])
+    ///     .add_command("grep")
+    ///     .arg("My_Dir")
+    ///     .spawn()
+    ///     .expect("Failed to spawn pipe.");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn spawn(&mut self) -> Result<()> {
+        self.start()?;
+        self.collected = Some(self.collect()?);
+        self.state = State::Collected;
+
+        Ok(())
+    }
+
+    /// Like [CommandPipe::spawn], but returns the pipe, so the output can be
+    /// retrieved in the same expression.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
+    /// let mut pipe = CommandPipe::new();
+    /// let output = pipe.add_command("echo").arg("This is a test.").run()?.output()?;
+    ///
+    /// assert_eq!(output.stdout(), "This is a test.\n".as_bytes());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn run(&mut self) -> Result<&mut Self> {
+        self.spawn()?;
+
+        Ok(self)
+    }
+
+    /// Starts the commands in the pipe in the background and returns a
+    /// [PipeHandle] owning them.
+    ///
+    /// Unlike [CommandPipe::start], the pipe is moved into the handle, which
+    /// is joined to collect the output.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
+    /// let mut pipe = CommandPipe::new();
+    /// pipe.add_command("echo").arg("This is a test.");
+    ///
+    /// let handle = pipe.spawn_handle()?;
+    ///
+    /// assert_eq!(handle.join()?.stdout(), "This is a test.\n".as_bytes());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn spawn_handle(self) -> Result<PipeHandle> {
+        PipeHandle::start(self)
+    }
+
+    /// Starts the commands in the pipe and returns the stdout of the last
+    /// stage as a [Stream](futures_core::Stream) of chunks, as they arrive.
+    ///
+    /// This suits async code like web handlers that pass the output on
+    /// while the pipe still runs. The processes are moved into the
+    /// [OutputStream] and killed when it's dropped. Exit statuses aren't
+    /// reported, only failures to read the output.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
+    /// let mut pipe = CommandPipe::new();
+    /// let stream = pipe.add_command("echo").arg("This is a test.").spawn_stream_async()?;
+    ///
+    /// // Pass the stream on, e.g. as the body of a response.
+    /// # drop(stream);
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[cfg(feature = "tokio")]
+    pub fn spawn_stream_async(&mut self) -> Result<OutputStream> {
+        self.start()?;
+
+        let last = self.children.len() - 1;
+        let stdout = (self.children[last].stdout.take())
+            .map(|out| self.progress_reader(self.stdout_reader(out, last)));
+        let children = std::mem::take(&mut self.children);
+        let command = self.pipeline[last].clone();
+
+        Ok(OutputStream::new(
+            stdout,
+            children,
+            command,
+            self.chunk_size(),
+        ))
+    }
+
+    /// Starts the commands in the pipe without waiting for them to finish.
+    ///
+    /// The output can be collected with [CommandPipe::output] once the pipe
+    /// is done. Starting the pipe again discards what's left of an earlier
+    /// run, including output that wasn't retrieved, and kills its stages
+    /// that are still running.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
+    /// let mut pipe = CommandPipe::new();
+    /// pipe.add_command("echo").arg("This is a test.").start()?;
+    ///
+    /// // Do something else while the pipe runs.
+    ///
+    /// assert_eq!(pipe.output()?.stdout(), "This is a test.\n".as_bytes());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn start(&mut self) -> Result<()> {
+        self.validate()?;
+        self.reset();
+
+        if let Some(Verbose(ref w)) = self.config.verbose {
+            let mut w = w.lock().unwrap_or_else(PoisonError::into_inner);
+            // The trace is only informational, so failing to write it is ignored.
+            let _ = writeln!(w, "+ {}", self).and_then(|_| w.flush());
+        }
+
+        self.state = State::Spawned;
+
+        let started = Instant::now();
+        self.deadlines = self
+            .pipeline
+            .iter()
+            .map(|c| c.1.timeout.map(|timeout| started + timeout))
+            .collect();
+
+        let last = self.pipeline.len().saturating_sub(1);
+        let mut children: Vec<Child> = Vec::with_capacity(self.pipeline.len());
+        let mut next_stdin: Option<Stdio> = None;
+        let mut piped_stderr: BTreeMap<usize, Vec<Box<dyn Read + Send>>> = BTreeMap::new();
+        let mut raw_stdin = self.raw_stdin.take();
+        let default_stdout = self.config.default_stdio.stdout();
+        let inherit_all = self.config.default_stdio == StdioPolicy::Inherit;
+        self.tagged_log = self.config.capture_tagged.then(TaggedLog::default);
+
+        for i in 0..self.pipeline.len() {
+            let mut prepared = self.prepare(i);
+            let command = &self.pipeline[i];
+            let input = self.config.stdin.as_ref().filter(|_| i == 0);
+            let raw = if i == 0 { raw_stdin.take() } else { None };
+
+            let stdin = match input {
+                _ if command.1.inherit_io => Stdio::inherit(),
+                Some(_) => Stdio::piped(),
+                None if i == 0 && self.config.inherit_stdin => Stdio::inherit(),
+                None if i == 0 && self.config.keep_stdin_open => Stdio::piped(),
+                None if i == 0 && raw.is_none() && inherit_all => Stdio::inherit(),
+                None => next_stdin.take().or(raw).unwrap_or_else(Stdio::null),
+            };
+            let stdout = (command.1.stdout.as_ref())
+                .or(self.config.stdout.as_ref())
+                .or(default_stdout.as_ref())
+                .filter(|_| command.1.stdout.is_some() || i == last);
+
+            let stderr_to = self.config.stderr_pipes.get(&i).copied();
+            if command.1.inherit_io {
+                prepared.stderr(Stdio::inherit());
+            } else if self.config.capture_stderr
+                || self.config.capture_tagged
+                || stderr_to.is_some()
+            {
+                prepared.stderr(Stdio::piped());
+            } else if self.config.quiet || self.config.default_stdio == StdioPolicy::Null {
+                prepared.stderr(Stdio::null());
+            }
+            self.executed.push(executed_command_line(&prepared));
+
+            let retries = self.config.spawn_retries;
+            let mut child = match spawn_stage(prepared, command, i, stdin, stdout, retries) {
+                Ok(child) => child,
+                Err(e) => {
+                    kill_all(&mut children);
+                    return Err(e);
+                }
+            };
+
+            if i == 0 && self.config.keep_stdin_open {
+                self.stdin = child.stdin.take();
+            }
+            if let Some((bytes, stdin)) = input.zip(child.stdin.take()) {
+                self.feeder = Some(feed_stdin(stdin, bytes.clone(), self.chunk_size()));
+            }
+            if let Some(stderr) = child.stderr.take() {
+                let stderr = self.tagged(stderr, i, "err");
+                match stderr_to {
+                    Some(to) => piped_stderr.entry(to).or_default().push(stderr),
+                    None => self.stderr_readers.push((i, read_all(stderr))),
+                }
+            }
+
+            // The stdout of the last stage is collected in `output`.
+            let stdout = if i < last { child.stdout.take() } else { None };
+            children.push(child);
+
+            let mut merged = piped_stderr.remove(&(i + 1));
+            next_stdin = match stdout {
+                Some(stdout)
+                    if command.1.capture
+                        || command.1.stdout_tap.is_some()
+                        || self.config.capture_tagged
+                        || merged.is_some() =>
+                {
+                    match tee(self.stdout_reader(stdout, i), self.chunk_size()) {
+                        Ok((reader, handle)) => {
+                            self.tees.push((i, handle));
+                            match merged.as_mut() {
+                                Some(sources) => {
+                                    sources.insert(0, Box::new(reader));
+                                    None
+                                }
+                                None => Some(reader.into()),
+                            }
+                        }
+                        Err(e) => {
+                            kill_all(&mut children);
+                            return Err(APipeError::child_process(
+                                e,
+                                "failed to capture stdout of",
+                                command,
+                            ));
+                        }
+                    }
+                }
+                stdout => stdout.map(Stdio::from),
+            };
+
+            if let Some(sources) = merged {
+                match merge(sources, self.chunk_size()) {
+                    Ok((reader, handles)) => {
+                        self.mergers.extend(handles.into_iter().map(|h| (i + 1, h)));
+                        next_stdin = Some(reader.into());
+                    }
+                    Err(e) => {
+                        kill_all(&mut children);
+                        return Err(APipeError::child_process(
+                            e,
+                            "failed to pipe stderr into",
+                            &self.pipeline[i + 1],
+                        ));
+                    }
+                }
+            }
+        }
+
+        self.children = children;
+
+        Ok(())
+    }
+
+    /// Capture the stdout of the stage at index `stage`.
+    ///
+    /// The output is copied while it flows to the next stage, so the rest of
+    /// the pipe is unaffected. Once the output of the pipe was collected, the
+    /// captured bytes are available through [CommandPipe::stage_output]. If
+    /// the next stage exits before reading everything, only what was passed
+    /// on until then is captured.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
+    /// let mut pipe = CommandPipe::new();
+    /// pipe.add_command("echo")
+    ///     .arg("This is a test.")
+    ///     .add_command("grep")
+    ///     .args(["-Eo", r"\w\w\sa[^.]*"])
+    ///     .capture_stage(0)?
+    ///     .spawn()?;
+    ///
+    /// assert_eq!(pipe.output()?.stdout(), "is a test\n".as_bytes());
+    /// assert_eq!(pipe.stage_output(0), Some("This is a test.\n".as_bytes()));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn capture_stage(&mut self, stage: usize) -> Result<&mut Self> {
+        let command = self
+            .pipeline
+            .get_mut(stage)
+            .ok_or(APipeError::InvalidStage(stage))?;
+
+        command.1.capture = true;
+        Ok(self)
+    }
+
+    /// Call `f` with every chunk of the stdout of the stage at index `stage`
+    /// while the pipe runs.
+    ///
+    /// The output still flows to the next stage, or into the [Output] for
+    /// the last stage, so this allows monitoring a stage without consuming
+    /// its output, e.g. to show progress. `f` is called from a thread of the
+    /// pipe. Clones of the pipe share the callback.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
+    /// let mut pipe = CommandPipe::new();
+    /// pipe.add_command("echo")
+    ///     .arg("This is a test.")
+    ///     .add_command("wc")
+    ///     .arg("-c")
+    ///     .on_stage_stdout(0, |chunk| eprintln!("read {} bytes", chunk.len()))?
+    ///     .spawn()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn on_stage_stdout<F>(&mut self, stage: usize, f: F) -> Result<&mut Self>
+    where
+        F: FnMut(&[u8]) + Send + 'static,
+    {
+        let command = self
+            .pipeline
+            .get_mut(stage)
+            .ok_or(APipeError::InvalidStage(stage))?;
+
+        command.1.stdout_tap = Some(StdoutTap(Arc::new(Mutex::new(f))));
+        Ok(self)
+    }
+
+    /// Run `f` in the child process of the stage at index `stage` after it
+    /// was forked but before it executes the program, see
+    /// [CommandExt::pre_exec](std::os::unix::process::CommandExt::pre_exec).
+    ///
+    /// This allows setting up the child in ways the pipe doesn't support
+    /// directly, like changing the root directory, dropping privileges or
+    /// closing file descriptors. An error returned by `f` makes spawning the
+    /// stage fail with it. Several closures of a stage run in the order they
+    /// were added.
+    ///
+    /// ## Safety
+    ///
+    /// `f` runs in a copy of the parent after `fork`, where only the calling
+    /// thread exists. Locks held by other threads of the parent, including
+    /// the one of the allocator, are never released there, so `f` must only
+    /// do what's async-signal-safe, like plain system calls, and must not
+    /// allocate, lock or panic. The same contract as for
+    /// [CommandExt::pre_exec](std::os::unix::process::CommandExt::pre_exec)
+    /// applies.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
+    /// let mut pipe = CommandPipe::new();
+    /// pipe.add_command("sh").args(["-c", "umask"]);
+    ///
+    /// // SAFETY: `umask` is async-signal-safe.
+    /// unsafe {
+    ///     pipe.pre_exec(0, || {
+    ///         libc::umask(0o077);
+    ///         Ok(())
+    ///     })?;
+    /// }
+    ///
+    /// assert_eq!(pipe.spawn_with_output()?.stdout(), b"0077\n");
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[cfg(unix)]
+    pub unsafe fn pre_exec<F>(&mut self, stage: usize, f: F) -> Result<&mut Self>
+    where
+        F: FnMut() -> io::Result<()> + Send + Sync + 'static,
+    {
+        let command = self
+            .pipeline
+            .get_mut(stage)
+            .ok_or(APipeError::InvalidStage(stage))?;
+
+        command.1.pre_exec.push(PreExec(Arc::new(Mutex::new(f))));
+        Ok(self)
+    }
+
+    /// Redirect the file descriptor `fd` of the stage at index `stage` to
+    /// `to`, like `3>&1` or `3>file` in a shell.
+    ///
+    /// This is meant for programs that write to descriptors other than
+    /// stdout and stderr. Redirections are applied in the order they were
+    /// added, after the stdio of the stage was connected, so
+    /// [FdTarget::Fd(1)](FdTarget::Fd) refers to the stdout of the stage
+    /// as connected by the pipe. Fails with [APipeError::Unsupported] on
+    /// platforms other than Unix.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::{CommandPipe, FdTarget};
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
+    /// # #[cfg(unix)]
+    /// # {
+    /// let output = CommandPipe::new()
+    ///     .add_command("sh")
+    ///     .args(["-c", "echo 'to fd 3' >&3"])
+    ///     .redirect_fd(0, 3, FdTarget::Fd(1))?
+    ///     .spawn_with_output()?;
+    ///
+    /// assert_eq!(output.stdout(), b"to fd 3\n");
+    /// # }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn redirect_fd(&mut self, stage: usize, fd: i32, to: FdTarget) -> Result<&mut Self> {
+        match to {
+            FdTarget::Fd(to) if to < 0 => return Err(APipeError::InvalidFd(to)),
+            _ if fd < 0 => return Err(APipeError::InvalidFd(fd)),
+            _ => {}
+        }
+        let command = self
+            .pipeline
+            .get_mut(stage)
+            .ok_or(APipeError::InvalidStage(stage))?;
+
+        #[cfg(unix)]
+        {
+            command.1.fd_redirects.push((fd, to));
+            Ok(self)
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = command;
+            Err(APipeError::Unsupported("Redirecting file descriptors"))
+        }
+    }
+
+    /// Decide how an unsuccessful exit of the stage at index `stage` is
+    /// handled.
+    ///
+    /// `f` is called with the exit status whenever the stage exits
+    /// unsuccessfully and returns the [StageAction] to take. Without a
+    /// handler, only an unsuccessful exit of the last stage makes
+    /// [CommandPipe::run_checked] fail, like in a shell without `pipefail`.
+    ///
+    /// Since `f` may return [StageAction::Abort], the stages are polled while
+    /// they run. [CommandPipe::allow_exit_codes] never aborts and doesn't poll.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::{CommandPipe, pipe::StageAction};
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
+    /// // `grep` exits with 1 if nothing matches.
+    /// let output = CommandPipe::new()
+    ///     .add_command("echo")
+    ///     .arg("This is a test.")
+    ///     .add_command("grep")
+    ///     .arg("foo")
+    ///     .on_stage_error(1, |_| StageAction::Ignore)?
+    ///     .run_checked()?;
+    ///
+    /// assert!(output.stdout().is_empty());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn on_stage_error<F>(&mut self, stage: usize, f: F) -> Result<&mut Self>
+    where
+        F: Fn(ExitStatus) -> StageAction + Send + Sync + 'static,
+    {
+        self.set_error_handler(
+            stage,
+            ErrorHandler {
+                handler: Arc::new(f),
+                may_abort: true,
+            },
+        )
+    }
+
+    /// Sets the [ErrorHandler] of the stage at index `stage`.
+    fn set_error_handler(&mut self, stage: usize, handler: ErrorHandler) -> Result<&mut Self> {
+        let command = self
+            .pipeline
+            .get_mut(stage)
+            .ok_or(APipeError::InvalidStage(stage))?;
+
+        command.1.on_error = Some(handler);
+        Ok(self)
+    }
+
+    /// Treat the given exit codes of the stage at index `stage` as success.
+    ///
+    /// This is a shorthand for [CommandPipe::on_stage_error] with a handler
+    /// that ignores the given codes and fails on any other unsuccessful exit,
+    /// including being killed by a signal.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
+    /// // `grep` exits with 1 if nothing matches.
+    /// let output = CommandPipe::new()
+    ///     .add_command("echo")
+    ///     .arg("This is a test.")
+    ///     .add_command("grep")
+    ///     .arg("foo")
+    ///     .allow_exit_codes(1, &[1])?
+    ///     .run_checked()?;
+    ///
+    /// assert!(output.stdout().is_empty());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn allow_exit_codes(&mut self, stage: usize, codes: &[i32]) -> Result<&mut Self> {
+        let codes = codes.to_vec();
+
+        // The handler never aborts, so the stages don't have to be polled.
+        self.set_error_handler(
+            stage,
+            ErrorHandler {
+                handler: Arc::new(move |status: ExitStatus| match status.code() {
+                    Some(code) if codes.contains(&code) => StageAction::Ignore,
+                    _ => StageAction::Fail,
+                }),
+                may_abort: false,
+            },
+        )
+    }
+
+    /// Returns how the exit of the stage at index `stage` with `status` is
+    /// handled.
+    fn stage_action(&self, stage: usize, status: ExitStatus) -> StageAction {
+        match self.pipeline[stage].1.on_error {
+            _ if status.success() => StageAction::Ignore,
+            Some(ref handler) => (handler.handler)(status),
+            None if stage + 1 == self.pipeline.len() => StageAction::Fail,
+            None => StageAction::Ignore,
+        }
+    }
+
+    /// Returns the stdout of a stage captured with [CommandPipe::capture_stage].
+    ///
+    /// Returns `None` if the stage wasn't captured or the output of the pipe
+    /// wasn't collected yet.
+    pub fn stage_output(&self, stage: usize) -> Option<&[u8]> {
+        self.stage_outputs.get(&stage).map(Vec::as_slice)
+    }
+
+    /// Returns the stdout of a stage captured with [CommandPipe::capture_stage]
+    /// as an [Output] of its own.
+    ///
+    /// The [Output] holds the exit status of the stage, so an intermediate
+    /// stage can be inspected like the whole pipe. Its stderr is empty.
+    /// Returns `None` if the stage wasn't captured or the output of the pipe
+    /// wasn't collected yet.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
+    /// let mut pipe = CommandPipe::new();
+    /// pipe.add_command("echo")
+    ///     .arg("This is a test.")
+    ///     .add_command("wc")
+    ///     .arg("-c")
+    ///     .capture_stage(0)?
+    ///     .spawn()?;
+    ///
+    /// let output = pipe.stage_output_struct(0).expect("stage 0 is captured");
+    ///
+    /// assert_eq!(output.stdout_str()?, "This is a test.\n");
+    /// assert_eq!(output.exit_code(), Some(0));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn stage_output_struct(&self, stage: usize) -> Option<Output> {
+        let stdout = self.stage_outputs.get(&stage)?;
+        let status = *self.statuses.get(stage)?;
+
+        let mut output = Output::from(process::Output {
+            status,
+            stdout: stdout.clone(),
+            stderr: Vec::new(),
+        });
+        output.1.statuses = vec![status];
+
+        Some(output)
+    }
+
+    /// Returns the exit code of the stage at index `stage`.
+    ///
+    /// Returns `None` if there is no such stage, the output of the pipe
+    /// wasn't collected yet, or the stage was terminated by a signal.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
+    /// let mut pipe = CommandPipe::new();
+    /// pipe.add_command("false").add_command("true").spawn()?;
+    ///
+    /// assert_eq!(pipe.status_code_of(0), Some(1));
+    /// assert_eq!(pipe.status_code_of(1), Some(0));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn status_code_of(&self, stage: usize) -> Option<i32> {
+        self.statuses.get(stage).and_then(ExitStatus::code)
+    }
+
+    /// Returns the exit statuses of the stages, in the order of the stages.
+    ///
+    /// Empty until the pipe finished, e.g. through [CommandPipe::wait] or
+    /// [CommandPipe::output]. The statuses are kept after the output was
+    /// collected, until the pipe is started again.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
+    /// let mut pipe = CommandPipe::new();
+    /// pipe.add_command("false").add_command("true").spawn()?;
+    ///
+    /// assert!(!pipe.statuses()[0].success());
+    /// assert!(pipe.statuses()[1].success());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn statuses(&self) -> &[ExitStatus] {
+        self.statuses.as_slice()
+    }
+
+    /// Returns the raw file descriptor of the stdout of a started stage.
+    ///
+    /// Only stages whose stdout is read by the pipe itself, i.e. the last
+    /// one, have such a descriptor. Returns `None` for the other stages, if
+    /// the stdout of the stage is redirected, or if the pipe wasn't started
+    /// or was already collected. The descriptor stays owned by the pipe, so
+    /// it must not be closed. Whatever is read from it is missing from the
+    /// [Output] of the pipe.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
+    /// let mut pipe = CommandPipe::new();
+    /// pipe.add_command("echo").arg("This is a test.").start()?;
+    ///
+    /// let fd = pipe.stdout_raw_fd(0).expect("stdout of the last stage is piped");
+    /// // Register `fd` with an event loop...
+    /// # let _ = fd;
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[cfg(unix)]
+    pub fn stdout_raw_fd(&self, stage: usize) -> Option<std::os::unix::io::RawFd> {
+        use std::os::unix::io::AsRawFd;
+
+        self.children
+            .get(stage)
+            .and_then(|child| child.stdout.as_ref())
+            .map(AsRawFd::as_raw_fd)
+    }
+
+    /// Returns the processes of the stages of a started pipe, in the order
+    /// of the stages.
+    ///
+    /// This gives access to what [Child] offers beyond the pipe, like the
+    /// process ids or platform specific handles. Empty if the pipe wasn't
+    /// started or its output was already collected. The pipe still waits on
+    /// the processes itself: reaping one of them some other way, e.g. with
+    /// `waitpid` on its process id, makes collecting the output of the pipe
+    /// fail.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
+    /// let mut pipe = CommandPipe::new();
+    /// pipe.add_command("echo").arg("foo").add_command("cat").start()?;
+    ///
+    /// for child in pipe.children() {
+    ///     println!("started process {}", child.id());
+    /// }
+    /// # pipe.output()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn children(&self) -> &[Child] {
+        self.children.as_slice()
+    }
+
+    /// Returns the stdin of the first stage of a started pipe.
+    ///
+    /// Returns `None` unless [CommandPipe::no_stdin_close] was set, or once
+    /// stdin was closed.
+    pub fn stdin(&mut self) -> Option<&mut ChildStdin> {
+        self.stdin.as_mut()
+    }
+
+    /// Closes the stdin of the first stage, so it sees the end of its input.
+    ///
+    /// Does nothing unless [CommandPipe::no_stdin_close] was set.
+    pub fn close_stdin(&mut self) {
+        self.stdin = None;
+    }
+
+    /// Returns the stdout of the last stage of a started pipe, to read its
+    /// output while it runs.
+    ///
+    /// Returns `None` if the stdout of the last stage is redirected, or if
+    /// the pipe wasn't started or was already collected. Whatever is read
+    /// from it is missing from the [Output] of the pipe.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// # use std::io::{Read, Write};
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let mut pipe = CommandPipe::new();
+    /// pipe.add_command("cat").no_stdin_close().start()?;
+    ///
+    /// pipe.stdin().expect("stdin is kept open").write_all(b"ping\n")?;
+    ///
+    /// let mut reply = [0; 5];
+    /// pipe.stdout().expect("stdout is piped").read_exact(&mut reply)?;
+    /// assert_eq!(&reply, b"ping\n");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn stdout(&mut self) -> Option<&mut ChildStdout> {
+        self.children
+            .last_mut()
+            .and_then(|child| child.stdout.as_mut())
+    }
+
+    /// Returns whether the last command of a started pipe is still running.
+    ///
+    /// This doesn't collect the output, so [CommandPipe::output] can still be
+    /// called afterwards.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
+    /// let mut pipe = CommandPipe::new();
+    /// pipe.add_command("sleep").arg("1").start()?;
+    ///
+    /// while pipe.is_running() {
+    ///     std::thread::sleep(std::time::Duration::from_millis(10));
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn is_running(&mut self) -> bool {
+        self.children
+            .last_mut()
+            .is_some_and(|child| matches!(child.try_wait(), Ok(None)))
+    }
+
+    /// Returns how many stages of a started pipe are still running.
+    ///
+    /// Like [CommandPipe::is_running], this only checks the stages without
+    /// blocking and doesn't collect the output. Returns `0` if the pipe
+    /// wasn't started or was already collected.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
+    /// let mut pipe = CommandPipe::new();
+    /// pipe.add_command("sleep").arg("1").add_command("cat").start()?;
+    ///
+    /// while pipe.num_running() > 0 {
+    ///     std::thread::sleep(std::time::Duration::from_millis(10));
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn num_running(&mut self) -> usize {
+        self.children
+            .iter_mut()
+            .map(Child::try_wait)
+            .filter(|status| matches!(status, Ok(None)))
+            .count()
+    }
+
+    /// Returns whether the output of the pipe was collected.
+    ///
+    /// Unlike [CommandPipe::is_running], this tells a pipe that finished
+    /// apart from one that was never started, without collecting anything.
+    /// Collecting the output of a complete pipe again fails with
+    /// [APipeError::NoRunningProcesses].
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
+    /// let mut pipe = CommandPipe::new();
+    /// pipe.add_command("ls");
+    /// assert!(!pipe.is_complete());
+    ///
+    /// pipe.spawn_with_output()?;
+    /// assert!(pipe.is_complete());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn is_complete(&self) -> bool {
+        self.state == State::Collected
+    }
+
+    /// Kill the commands of a still running pipe when it's dropped.
+    ///
+    /// By default the commands of a pipe that was started but not collected
+    /// keep running after the pipe is dropped.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
+    /// let mut pipe = CommandPipe::new();
+    /// pipe.add_command("sleep")
+    ///     .arg("10")
+    ///     .kill_on_drop(true)
+    ///     .start()?;
+    ///
+    /// drop(pipe); // `sleep` is killed here.
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn kill_on_drop(&mut self, kill: bool) -> &mut Self {
+        self.config.kill_on_drop = kill;
+        self
+    }
+
+    /// Retry spawning a stage up to `retries` times if it fails transiently.
+    ///
+    /// Under heavy load the operating system may refuse to create another
+    /// process for a moment, e.g. `fork` failing with `EAGAIN`. Such failures
+    /// are retried after a short delay, while failures that would happen
+    /// again, like a program that doesn't exist, fail right away. Defaults to
+    /// no retries.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
+    /// let output = CommandPipe::new()
+    ///     .add_command("echo")
+    ///     .arg("This is a test.")
+    ///     .spawn_retries(3)
+    ///     .spawn_with_output()?;
+    ///
+    /// assert_eq!(output.stdout(), "This is a test.\n".as_bytes());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn spawn_retries(&mut self, retries: u32) -> &mut Self {
+        self.config.spawn_retries = retries;
+        self
+    }
+
+    /// Waits for every stage of a started pipe at the same time instead of one
+    /// after the other.
+    ///
+    /// By default the stages are waited on in order, so a stage that exits
+    /// early isn't reaped until all stages before it exited. With this
+    /// setting every stage is waited on from its own thread and reaped as
+    /// soon as it exits, which helps pipes whose stages finish in an
+    /// unpredictable order.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
+    /// let mut pipe = CommandPipe::new();
+    /// pipe.add_command("sleep")
+    ///     .arg("0.2")
+    ///     .add_command("echo")
+    ///     .arg("done")
+    ///     .wait_all_concurrent()
+    ///     .spawn()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn wait_all_concurrent(&mut self) -> &mut Self {
+        self.config.wait_all_concurrent = true;
+        self
+    }
+
+    /// Waits for `children`, the processes of the stages of the pipe, and
+    /// returns their exit statuses.
+    fn wait_children(
+        &self,
+        children: &mut [Child],
+    ) -> Result<Vec<(ExitStatus, Option<ResourceUsage>)>> {
+        let wait = |(i, child): (usize, &mut Child)| {
+            wait_stage(child)
+                .map_err(|e| APipeError::child_process(e, "failed to wait on", &self.pipeline[i]))
+        };
+
+        if !self.config.wait_all_concurrent {
+            return children.iter_mut().enumerate().map(wait).collect();
+        }
+
+        thread::scope(|scope| {
+            let waiters: Vec<_> = children
+                .iter_mut()
+                .enumerate()
+                .map(|stage| scope.spawn(move || wait(stage)))
+                .collect();
+
+            waiters
+                .into_iter()
+                .map(|waiter| waiter.join().map_err(panicked)?)
+                .collect()
+        })
+    }
+
+    /// Forgets everything from an earlier run of the pipe, killing and
+    /// reaping its stages that are still running.
+    ///
+    /// The threads of the earlier run are detached, they finish once the
+    /// pipes of the killed stages are closed.
+    fn reset(&mut self) {
+        kill_all(&mut self.children);
+        self.stdin = None;
+        self.feeder = None;
+        self.tees.clear();
+        self.mergers.clear();
+        self.stderr_readers.clear();
+        self.tagged_log = None;
+        self.executed.clear();
+        self.statuses.clear();
+        self.actions.clear();
+        self.stage_outputs.clear();
+        self.collected = None;
+    }
+
+    /// Returns whether the stages have to be polled while they run, because a
+    /// stage has a deadline or its [StageAction::Abort] has to kill the others.
+    ///
+    /// Otherwise the error handlers run after [CommandPipe::wait_children].
+    fn polls(&self) -> bool {
+        self.deadlines.iter().any(Option::is_some)
+            || self
+                .pipeline
+                .iter()
+                .any(|c| c.1.on_error.as_ref().is_some_and(|h| h.may_abort))
+    }
+
+    /// Like [CommandPipe::wait_children], but kills the stages that are still
+    /// running after their deadline.
+    ///
+    /// The stdout of the last stage is read from another thread meanwhile.
+    /// Once a stage exited, the action for its status is decided, and if it's
+    /// [StageAction::Abort] the other stages are killed.
+    fn wait_with_deadlines(
+        &self,
+        children: &mut [Child],
+        out: Option<Box<dyn Read + Send>>,
+        stdout: Option<&mut Vec<u8>>,
+    ) -> Result<Waited> {
+        let last = children.len() - 1;
+        let (lines, chunk) = (self.config.max_output_lines, self.chunk_size());
+        let tail = self.config.tail_output_bytes;
+        let mut statuses: Vec<Option<ExitStatus>> = vec![None; children.len()];
+        let mut usages = vec![None; children.len()];
+        let mut actions = vec![StageAction::Ignore; children.len()];
+        let mut timed_out = None;
+
+        thread::scope(|scope| {
+            let mut reader = out.map(|mut out| {
+                scope.spawn(move || read_stdout(&mut out, stdout, lines, tail, chunk))
+            });
+
+            while statuses.iter().any(Option::is_none) {
+                let now = Instant::now();
+                let mut abort = false;
+
+                for (i, child) in children.iter_mut().enumerate() {
+                    if statuses[i].is_some() {
+                        continue;
+                    }
+                    match try_wait_stage(child) {
+                        Ok(Some((status, usage))) => {
+                            statuses[i] = Some(status);
+                            usages[i] = usage;
+                            actions[i] = self.stage_action(i, status);
+                            abort |= actions[i] == StageAction::Abort;
+                        }
+                        Ok(None) if self.deadlines[i].is_some_and(|d| d <= now) => {
+                            let _ = child.kill();
+                            timed_out = timed_out.or(Some(i));
+                        }
+                        Ok(None) => {}
+                        Err(e) => {
+                            kill_unreaped(children, &statuses);
+                            return Err(APipeError::child_process(
+                                e,
+                                "failed to wait on",
+                                &self.pipeline[i],
+                            ));
+                        }
+                    }
+                }
+                if abort {
+                    kill_unreaped(children, &statuses);
+                }
+
+                if reader.as_ref().is_some_and(|r| r.is_finished()) {
+                    let joined = reader.take().map(|r| r.join());
+                    let limited = match joined {
+                        Some(Ok(limited)) => limited,
+                        Some(Err(payload)) => {
+                            kill_unreaped(children, &statuses);
+                            return Err(panicked(payload));
+                        }
+                        None => Ok(false),
+                    };
+                    match limited {
+                        Ok(true) => kill_unreaped(children, &statuses),
+                        Ok(false) => {}
+                        Err(e) => {
+                            kill_unreaped(children, &statuses);
+                            return Err(APipeError::child_process(
+                                e,
+                                "failed to read stdout of",
+                                &self.pipeline[last],
+                            ));
+                        }
+                    }
+                }
+
+                thread::sleep(POLL_INTERVAL);
+            }
+
+            if let Some(reader) = reader {
+                reader.join().map_err(panicked)?.map_err(|e| {
+                    APipeError::child_process(e, "failed to read stdout of", &self.pipeline[last])
+                })?;
+            }
+
+            Ok(Waited {
+                statuses: statuses.into_iter().flatten().collect(),
+                usages,
+                actions,
+                timed_out,
+            })
+        })
+    }
+
+    /// Kill the stage at index `stage` if it's still running after
+    /// `timeout`.
+    ///
+    /// The pipe then fails with [APipeError::StageTimeout] once all stages
+    /// exited. The timeout starts when the pipe is started.
+    ///
+    /// A stage whose output isn't read anymore, e.g. because it's followed
+    /// by `head`, ends with `SIGPIPE` as soon as it writes again, like in a
+    /// shell. A stage that stays silent instead keeps the pipe waiting for
+    /// it, a timeout bounds how long.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::{CommandPipe, error::APipeError};
+    /// # use std::time::Duration;
+    /// # fn main() -> Result<(), APipeError> {
+    /// let mut pipe = CommandPipe::new();
+    /// pipe.add_command("sleep")
+    ///     .arg("10")
+    ///     .add_command("cat")
+    ///     .stage_timeout(0, Duration::from_millis(100))?;
+    ///
+    /// assert!(matches!(pipe.spawn(), Err(APipeError::StageTimeout { stage: 0 })));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn stage_timeout(&mut self, stage: usize, timeout: Duration) -> Result<&mut Self> {
+        let command = self
+            .pipeline
+            .get_mut(stage)
+            .ok_or(APipeError::InvalidStage(stage))?;
+
+        command.1.timeout = Some(timeout);
+        Ok(self)
+    }
+
+    /// Waits for the stdin feeder thread, if there is one.
+    ///
+    /// Returns the number of bytes written to stdin.
+    fn join_feeder(&mut self) -> Result<usize> {
+        match self.feeder.take() {
+            Some(feeder) => feeder.join().map_err(panicked)?.map_err(|e| {
+                APipeError::child_process(e, "failed to write stdin of", &self.pipeline[0])
+            }),
+            None => Ok(0),
+        }
+    }
+
+    /// Runs the commands in the pipe without waiting for them and returns
+    /// their process ids.
+    ///
+    /// The stages are connected as usual, but the stdin of the first and the
+    /// stdout of the last command are connected to the null device, so no
+    /// output is captured. The processes keep running after the pipe is
+    /// dropped. On Unix every stage starts a new session without a
+    /// controlling terminal, so signals sent to the parent's group or by the
+    /// terminal (e.g. `SIGINT` or `SIGHUP`) don't reach the pipeline.
+    ///
+    /// The stages are reaped by a background thread once they exit, so they
+    /// don't linger as zombies while the parent keeps running.
+    ///
+    /// The pipe is validated first, see [CommandPipe::validate]. File
+    /// descriptors redirected with [CommandPipe::redirect_fd] are honoured.
+    /// Input and stderr that would have to be passed on by the parent can't
+    /// be, so [CommandPipe::stdin_bytes], [CommandPipe::first_stdin_raw] and
+    /// [CommandPipe::pipe_stderr] fail with
+    /// [APipeError::ConflictingRedirection]. If a command fails to spawn,
+    /// the already running stages are killed.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
+    /// let mut pipe = CommandPipe::new();
+    /// pipe.add_command("sleep").arg("1");
+    ///
+    /// let pids = pipe.spawn_detached()?;
+    ///
+    /// assert_eq!(pids.len(), 1);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn spawn_detached(self) -> Result<Vec<u32>> {
+        self.validate()?;
+        if self.config.stdin.is_some() {
+            return Err(APipeError::ConflictingRedirection(
+                "stdin_bytes",
+                "spawn_detached",
+            ));
+        }
+        if self.raw_stdin.is_some() {
+            return Err(APipeError::ConflictingRedirection(
+                "first_stdin_raw",
+                "spawn_detached",
+            ));
+        }
+        if !self.config.stderr_pipes.is_empty() {
+            return Err(APipeError::ConflictingRedirection(
+                "pipe_stderr",
+                "spawn_detached",
+            ));
+        }
+
+        let mut children: Vec<Child> = Vec::with_capacity(self.pipeline.len());
+        let last = self.pipeline.len().saturating_sub(1);
+
+        for (i, command) in self.pipeline.iter().enumerate() {
+            let mut prepared = self.prepare(i);
+            let stdin = children
+                .last_mut()
+                .and_then(|prev| prev.stdout.take())
+                .map_or(Stdio::null(), Stdio::from);
+            let stdout = (i == last).then_some(&StdioSpec::Null);
+
+            #[cfg(unix)]
+            {
+                use std::os::unix::process::CommandExt;
+                // SAFETY: `unix::setsid` only calls async-signal-safe functions.
+                unsafe {
+                    prepared.pre_exec(crate::unix::setsid);
+                }
+            }
+
+            let retries = self.config.spawn_retries;
+            match spawn_stage(prepared, command, i, stdin, stdout, retries) {
+                Ok(child) => children.push(child),
+                Err(e) => {
+                    kill_all(&mut children);
+                    return Err(e);
+                }
+            }
+        }
+
+        let pids = children.iter().map(Child::id).collect();
+        thread::spawn(move || {
+            for mut child in children {
+                let _ = child.wait();
+            }
+        });
+
+        Ok(pids)
+    }
+
+    /// Runs a copy of the pipe for every input and returns their outputs in
+    /// the order of the inputs.
+    ///
+    /// Each input is fed to the stdin of the first command of its own copy of
+    /// the pipe. At most `concurrency` copies run at the same time. A panic
+    /// while running a copy, e.g. in a callback like
+    /// [CommandPipe::on_progress], is returned as [APipeError::Panicked] for
+    /// its input.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// let mut pipe = CommandPipe::new();
+    /// pipe.add_command("tr").args(["a-z", "A-Z"]);
+    ///
+    /// let outputs = pipe.map_inputs(vec![b"foo".to_vec(), b"bar".to_vec()], 2);
+    ///
+    /// assert_eq!(outputs[0].as_ref().unwrap().stdout(), b"FOO");
+    /// assert_eq!(outputs[1].as_ref().unwrap().stdout(), b"BAR");
+    /// ```
+    pub fn map_inputs<I>(&self, inputs: I, concurrency: usize) -> Vec<Result<Output>>
+    where
+        I: IntoIterator<Item = Vec<u8>>,
+    {
+        let jobs: Vec<(usize, CommandPipe)> = inputs
+            .into_iter()
+            .map(|input| {
+                let mut pipe = self.clone();
+                pipe.stdin_bytes(input);
+                pipe
+            })
+            .enumerate()
+            .collect();
+
+        let mut results: Vec<Option<Result<Output>>> = jobs.iter().map(|_| None).collect();
+        let workers = concurrency.clamp(1, jobs.len().max(1));
+        let jobs = Mutex::new(jobs.into_iter());
+        let (tx, rx) = mpsc::channel();
+
+        thread::scope(|scope| {
+            for _ in 0..workers {
+                let tx = tx.clone();
+                let jobs = &jobs;
+
+                scope.spawn(move || loop {
+                    let job = jobs.lock().unwrap_or_else(PoisonError::into_inner).next();
+                    let Some((i, mut pipe)) = job else {
+                        break;
+                    };
+                    let _ = tx.send((i, pipe.try_spawn_with_output()));
+                });
+            }
+        });
+        drop(tx);
+
+        for (i, result) in rx {
+            results[i] = Some(result);
+        }
+
+        results
+            .into_iter()
+            .map(|r| {
+                r.unwrap_or_else(|| Err(APipeError::Panicked("the worker panicked".to_owned())))
+            })
+            .collect()
+    }
+
+    /// Builds the command for the stage at index `stage` with the pipe wide
+    /// settings applied.
+    ///
+    /// The commands in the pipe are left untouched, so spawning a pipe again
+    /// doesn't apply the settings twice.
+    fn prepare(&self, stage: usize) -> process::Command {
+        let leading;
+        let command = match self.config.leading_args.as_slice() {
+            [] => &self.pipeline[stage],
+            args => {
+                leading = self.pipeline[stage].with_leading_args(args);
+                &leading
+            }
+        };
+        let mut prepared = match &self.config.shell {
+            Some(shell) => shell_command(shell, command),
+            None => command.to_process(),
+        };
+
+        if self.config.clean_env {
+            prepared.env_clear();
+        }
+
+        prepared.envs(self.config.envs.iter().map(|(k, v)| (k, v)));
+
+        for (key, val) in command.0.get_envs() {
+            match val {
+                Some(val) => prepared.env(key, val),
+                None => prepared.env_remove(key),
+            };
+        }
+
+        #[cfg(unix)]
+        if let Some(mask) = self.config.umask {
+            use std::os::unix::process::CommandExt;
+            // SAFETY: `umask` is async-signal-safe and can't fail.
+            unsafe {
+                prepared.pre_exec(move || {
+                    libc::umask(mask as libc::mode_t);
+                    Ok(())
+                });
+            }
+        }
+
+        #[cfg(unix)]
+        if let Some(increment) = self.config.nice {
+            use std::os::unix::process::CommandExt;
+            // SAFETY: `unix::nice` only calls async-signal-safe functions.
+            unsafe {
+                prepared.pre_exec(move || crate::unix::nice(increment));
+            }
+        }
+
+        #[cfg(all(unix, feature = "rlimit"))]
+        if let Some(bytes) = self.config.memory_limit {
+            use std::os::unix::process::CommandExt;
+            // SAFETY: `unix::limit_memory` only calls async-signal-safe functions.
+            unsafe {
+                prepared.pre_exec(move || crate::unix::limit_memory(bytes));
+            }
+        }
+
+        #[cfg(all(unix, feature = "rlimit"))]
+        if let Some(secs) = self.config.cpu_time_limit {
+            use std::os::unix::process::CommandExt;
+            // SAFETY: `unix::limit_cpu_time` only calls async-signal-safe functions.
+            unsafe {
+                prepared.pre_exec(move || crate::unix::limit_cpu_time(secs));
+            }
+        }
+
+        #[cfg(all(target_os = "linux", feature = "affinity"))]
+        if let Some(ref cpus) = self.config.cpu_affinity {
+            use std::os::unix::process::CommandExt;
+            let set = crate::unix::cpu_set(cpus);
+            // SAFETY: `unix::set_affinity` only calls async-signal-safe functions.
+            unsafe {
+                prepared.pre_exec(move || crate::unix::set_affinity(&set));
+            }
+        }
+
+        #[cfg(windows)]
+        if let Some(flags) = self.config.creation_flags {
+            use std::os::windows::process::CommandExt;
+            prepared.creation_flags(flags);
+        }
+
+        prepared
+    }
+
+    /// Spawns all commands in the pipe and returns the [`Output`].
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
+    /// let output = CommandPipe::new()
+    ///     .add_command("echo")
+    ///     .arg("This is a test.")
+    ///     .add_command("grep")
+    ///     .arg("-Eo")
+    ///     .arg(r"\w\w\sa[^.]*")
+    ///     .spawn_with_output()?;
+    ///
+    /// assert_eq!(output.stdout(), "is a test\n".as_bytes());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn spawn_with_output(&mut self) -> Result<Output> {
+        self.check_redirections(true)?;
+        self.spawn()?;
+        self.output()
+    }
+
+    /// Like [CommandPipe::spawn_with_output], but spawns the stages with
+    /// `posix_spawn` instead of forking where possible.
+    ///
+    /// The stages are spawned and waited on directly, without going through
+    /// [std::process::Command]. Note that on Linux std already spawns commands
+    /// without `pre_exec` hooks with `posix_spawn`, so there the two paths
+    /// take about the same time: the `spawn` benchmark (`cargo bench
+    /// --features posix_spawn`) measured 1.2ms per three stage pipe when
+    /// forking and 1.3ms with this method.
+    ///
+    /// Only pipes without settings that need code running in the child or
+    /// data passed on by the parent take this path. With any of
+    /// [CommandPipe::pre_exec], [CommandPipe::redirect_fd], a working
+    /// directory, stdin, stdout or stderr redirections or captures, stdout
+    /// callbacks, timeouts, [CommandPipe::on_stage_error], output limits,
+    /// retries, [CommandPipe::verbose], [CommandPipe::on_progress], or the
+    /// umask, niceness, resource limits or CPU affinity set, the pipe falls
+    /// back to [CommandPipe::spawn_with_output]. Both paths produce the same
+    /// [Output].
+    ///
+    /// Requires the `posix_spawn` feature.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
+    /// let output = CommandPipe::new()
+    ///     .add_command("echo")
+    ///     .arg("This is a test.")
+    ///     .add_command("tr")
+    ///     .args(["a-z", "A-Z"])
+    ///     .spawn_with_output_posix()?;
+    ///
+    /// assert_eq!(output.stdout(), "THIS IS A TEST.\n".as_bytes());
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[cfg(all(unix, feature = "posix_spawn"))]
+    pub fn spawn_with_output_posix(&mut self) -> Result<Output> {
+        use crate::posix_spawn;
+
+        self.validate()?;
+        if !self.posix_spawnable() {
+            return self.spawn_with_output();
+        }
+
+        self.reset();
+        self.state = State::Spawned;
+
+        let mut stages = Vec::with_capacity(self.pipeline.len());
+        for (i, command) in self.pipeline.iter().enumerate() {
+            let prepared = self.prepare(i);
+            let program = resolve_program(&prepared);
+            let env_clear = self.config.clean_env || command.1.env_clear;
+            let stage =
+                posix_spawn::Stage::new(&prepared, &program, env_clear, command.1.arg0.as_deref())
+                    .map_err(|e| APipeError::spawn(e, command, i))?;
+
+            self.executed.push(executed_command_line(&prepared));
+            stages.push(stage);
+        }
+
+        let (pids, mut out) = posix_spawn::spawn(&stages)
+            .map_err(|(i, e)| APipeError::spawn(e, &self.pipeline[i], i))?;
+        let last = pids.len() - 1;
+
+        let mut stdout = Vec::with_capacity(self.config.output_capacity);
+        if let Err(e) = out.read_to_end(&mut stdout) {
+            posix_spawn::kill_all(&pids);
+            return Err(APipeError::child_process(
+                e,
+                "failed to read stdout of",
+                &self.pipeline[last],
+            ));
+        }
+        drop(out);
+
+        let mut statuses = Vec::with_capacity(pids.len());
+        let mut usages = Vec::with_capacity(pids.len());
+        for (i, &pid) in pids.iter().enumerate() {
+            match crate::unix::wait_pid(pid, true) {
+                Ok(Some((status, usage))) => {
+                    statuses.push(status);
+                    usages.push(Some(usage));
+                }
+                Ok(None) => unreachable!("waiting blocks until the stage exited"),
+                Err(e) => {
+                    posix_spawn::kill_all(&pids[i..]);
+                    return Err(APipeError::child_process(
+                        e,
+                        "failed to wait on",
+                        &self.pipeline[i],
+                    )
+                    .completed(&statuses));
+                }
+            }
+        }
+
+        let mut output = Output::from(process::Output {
+            status: statuses[last],
+            stdout,
+            stderr: Vec::new(),
+        });
+        self.statuses.clone_from(&statuses);
+        output.1.statuses = statuses;
+        output.1.executed = std::mem::take(&mut self.executed);
+        output.1.stderr_ends = vec![0; self.pipeline.len()];
+        output.1.usages = usages;
+        self.state = State::Collected;
+
+        Ok(output)
+    }
+
+    /// Returns whether the pipe can be spawned by
+    /// [CommandPipe::spawn_with_output_posix] without falling back.
+    #[cfg(all(unix, feature = "posix_spawn"))]
+    fn posix_spawnable(&self) -> bool {
+        let config = &self.config;
+        let simple_stages = self.pipeline.iter().all(|c| {
+            c.0.get_current_dir().is_none()
+                && c.1.stdout.is_none()
+                && !c.1.capture
+                && !c.1.inherit_io
+                && c.1.stdout_tap.is_none()
+                && c.1.timeout.is_none()
+                && c.1.on_error.is_none()
+                && c.1.pre_exec.is_empty()
+                && c.1.fd_redirects.is_empty()
+        });
+        #[cfg(feature = "rlimit")]
+        let limits = config.memory_limit.is_none() && config.cpu_time_limit.is_none();
+        #[cfg(not(feature = "rlimit"))]
+        let limits = true;
+        #[cfg(all(target_os = "linux", feature = "affinity"))]
+        let affinity = config.cpu_affinity.is_none();
+        #[cfg(not(all(target_os = "linux", feature = "affinity")))]
+        let affinity = true;
+
+        simple_stages
+            && limits
+            && affinity
+            && config.stdin.is_none()
+            && self.raw_stdin.is_none()
+            && !config.inherit_stdin
+            && !config.keep_stdin_open
+            && config.stdout.is_none()
+            && config.default_stdio == StdioPolicy::Capture
+            && !config.capture_stderr
+            && !config.quiet
+            && config.stderr_pipes.is_empty()
+            && !config.capture_tagged
+            && config.max_output_lines.is_none()
+            && config.tail_output_bytes.is_none()
+            && config.spawn_retries == 0
+            && config.verbose.is_none()
+            && config.progress.is_none()
+            && config.umask.is_none()
+            && config.nice.is_none()
+    }
+
+    /// Like [CommandPipe::spawn_with_output], but never panics.
+    ///
+    /// Every error is returned as an [APipeError], including a panic while
+    /// running the pipe, e.g. in a callback like
+    /// [CommandPipe::on_stage_stdout] or one of the threads of the pipe,
+    /// which is returned as [APipeError::Panicked]. The stages still running
+    /// after a panic are killed. This suits callers that can't let a panic
+    /// cross their boundary, like FFI or server code. Panics can only be
+    /// caught if the panic strategy is `unwind`, and the panic hook still
+    /// reports them.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::{CommandPipe, error::APipeError};
+    /// let result = CommandPipe::new().try_spawn_with_output();
+    ///
+    /// assert!(matches!(result, Err(APipeError::EmptyPipe)));
+    /// ```
+    pub fn try_spawn_with_output(&mut self) -> Result<Output> {
+        let result =
+            std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| self.spawn_with_output()));
+
+        result.unwrap_or_else(|payload| {
+            kill_all(&mut self.children);
+            Err(panicked(payload))
+        })
+    }
+
+    /// Like [CommandPipe::spawn_with_output], but fails if the pipe exits
+    /// unsuccessfully.
+    ///
+    /// By default this is decided by the last stage, use
+    /// [CommandPipe::on_stage_error] to change that for individual stages.
+    /// Stderr is captured for this run, like with
+    /// [CommandPipe::capture_stderr], so the returned
+    /// [APipeError::NonZeroExit] contains what the failed stage wrote to it.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::{CommandPipe, error::APipeError};
+    /// let result = CommandPipe::new()
+    ///     .add_command("ls")
+    ///     .arg("/nonexistent")
+    ///     .run_checked();
+    ///
+    /// assert!(matches!(result, Err(APipeError::NonZeroExit { .. })));
+    /// ```
+    pub fn run_checked(&mut self) -> Result<Output> {
+        let output = self.spawn_capturing_stderr()?;
+
+        let failed = self.actions.iter().position(|a| *a != StageAction::Ignore);
+        if let Some(stage) = failed {
+            return Err(APipeError::non_zero_exit(
+                &self.pipeline[stage],
+                stage,
+                output.statuses(),
+                output.stage_stderr(stage),
+            ));
+        }
+
+        Ok(output)
+    }
+
+    /// Like [CommandPipe::spawn_with_output], but captures stderr for this
+    /// run only.
+    fn spawn_capturing_stderr(&mut self) -> Result<Output> {
+        let capture_stderr = std::mem::replace(&mut self.config.capture_stderr, true);
+        let output = self.spawn_with_output();
+        self.config.capture_stderr = capture_stderr;
+
+        output
+    }
+
+    /// Runs the pipe and returns everything it produced.
+    ///
+    /// Stderr is captured for this run, like with
+    /// [CommandPipe::capture_stderr], and returned together with the stdout
+    /// of the last stage and the exit status of every stage. Unlike
+    /// [CommandPipe::run_checked], an unsuccessful exit isn't an error.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
+    /// let full = CommandPipe::new()
+    ///     .add_command("ls")
+    ///     .arg("/nonexistent")
+    ///     .add_command("cat")
+    ///     .run_full()?;
+    ///
+    /// assert!(!full.statuses[0].success());
+    /// assert!(!full.stderr[0].is_empty());
+    /// assert!(full.stderr[1].is_empty());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn run_full(&mut self) -> Result<FullOutput> {
+        let output = self.spawn_capturing_stderr()?;
+
+        Ok(FullOutput::from(output))
+    }
+
+    /// Runs the pipe and returns its stdout as a `String`, without a single
+    /// trailing newline.
+    ///
+    /// Fails with [APipeError::InvalidUtf8] if the output isn't valid UTF-8.
+    /// Like [CommandPipe::spawn_with_output], an unsuccessful exit isn't an
+    /// error.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
+    /// let stdout = CommandPipe::new()
+    ///     .add_command("echo")
+    ///     .arg("This is a test.")
+    ///     .run_trimmed()?;
+    ///
+    /// assert_eq!(stdout, "This is a test.");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn run_trimmed(&mut self) -> Result<String> {
+        let mut stdout = self.spawn_with_output()?.into_stdout_string()?;
+
+        if stdout.ends_with('\n') {
+            stdout.pop();
+            if stdout.ends_with('\r') {
+                stdout.pop();
+            }
+        }
+
+        Ok(stdout)
+    }
+
+    /// Runs the pipe and captures its stdout into `buf`.
+    ///
+    /// `buf` is cleared first, but keeps its allocation. When running many
+    /// pipes in a loop, reusing the same buffer avoids allocating a new one
+    /// for every run.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
+    /// let mut pipe = CommandPipe::new();
+    /// pipe.add_command("echo").arg("This is a test.");
+    ///
+    /// let mut buf = Vec::new();
+    /// for _ in 0..3 {
+    ///     let status = pipe.spawn_into(&mut buf)?;
+    ///
+    ///     assert!(status.success());
+    ///     assert_eq!(buf, "This is a test.\n".as_bytes());
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn spawn_into(&mut self, buf: &mut Vec<u8>) -> Result<ExitStatus> {
+        self.check_redirections(true)?;
+        self.start()?;
+
+        buf.clear();
+        let output = self.collect_into(Some(buf))?;
+        self.state = State::Collected;
+
+        Ok(output.0.status)
+    }
+
+    /// Runs the pipe only for its side effects and discards its output.
+    ///
+    /// The stdout of the last stage is still read, so the stages don't block
+    /// on a full pipe, but it's discarded in small chunks instead of being
+    /// collected, so even a huge output doesn't use much memory. Returns the
+    /// exit status of the pipe.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
+    /// let status = CommandPipe::new()
+    ///     .add_command("seq")
+    ///     .arg("1000000")
+    ///     .drain_output()?;
+    ///
+    /// assert!(status.success());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn drain_output(&mut self) -> Result<ExitStatus> {
+        self.check_redirections(true)?;
+        self.start()?;
+
+        let output = self.collect_into(None)?;
+        self.state = State::Collected;
+
+        Ok(output.0.status)
+    }
+
+    /// Checks the pipe for errors without running it.
+    ///
+    /// The pipe must contain at least one command, arguments must not be
+    /// added before the first one, every command must have a program, and its
+    /// settings must not contradict each other. Settings of
+    /// individual stages are stored with their command, so they can't refer
+    /// to a stage that doesn't exist.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::{CommandPipe, error::APipeError};
+    /// let mut pipe = CommandPipe::new();
+    ///
+    /// assert!(matches!(pipe.validate(), Err(APipeError::EmptyPipe)));
+    ///
+    /// pipe.add_command("ls");
+    ///
+    /// assert!(pipe.validate().is_ok());
+    /// ```
+    pub fn validate(&self) -> Result<()> {
+        if self.pipeline.is_empty() || self.config.args_without_command {
+            return Err(APipeError::EmptyPipe);
+        }
+
+        if let Some(i) = self
+            .pipeline
+            .iter()
+            .position(|c| c.0.get_program().is_empty())
+        {
+            return Err(APipeError::EmptyProgram(i));
+        }
+
+        #[cfg(unix)]
+        if let Some(n) = self.config.nice.filter(|n| !(-20..=19).contains(n)) {
+            return Err(APipeError::InvalidNice(n));
+        }
+
+        #[cfg(all(target_os = "linux", feature = "affinity"))]
+        if let Some(ref cpus) = self.config.cpu_affinity {
+            let setsize = libc::CPU_SETSIZE as usize;
+            if cpus.is_empty() || cpus.iter().any(|&cpu| cpu >= setsize) {
+                return Err(APipeError::InvalidCpuAffinity(cpus.clone()));
+            }
+        }
+
+        self.check_redirections(false)
+    }
+
+    /// Checks that the stdio settings of the pipe don't contradict each other.
+    ///
+    /// With `capture` the output of the last stage is expected to be captured.
+    fn check_redirections(&self, capture: bool) -> Result<()> {
+        use APipeError::ConflictingRedirection as Conflict;
+
+        if self.config.stdin.is_some() && self.config.inherit_stdin {
+            return Err(Conflict("stdin_bytes", "inherit_stdin"));
+        }
+        if self.config.stdin.is_some() && self.config.keep_stdin_open {
+            return Err(Conflict("stdin_bytes", "no_stdin_close"));
+        }
+        if self.config.inherit_stdin && self.config.keep_stdin_open {
+            return Err(Conflict("inherit_stdin", "no_stdin_close"));
+        }
+        if self.raw_stdin.is_some() {
+            if self.config.stdin.is_some() {
+                return Err(Conflict("first_stdin_raw", "stdin_bytes"));
+            }
+            if self.config.inherit_stdin {
+                return Err(Conflict("first_stdin_raw", "inherit_stdin"));
+            }
+            if self.config.keep_stdin_open {
+                return Err(Conflict("first_stdin_raw", "no_stdin_close"));
+            }
+        }
+
+        let last_stdout = self.pipeline.last().and_then(|c| c.1.stdout.as_ref());
+
+        if self.config.stdout.is_some() && last_stdout.is_some() {
+            return Err(Conflict("stdout_to_file", "set_stage_stdout"));
+        }
+        if capture && self.config.stdout.is_some() {
+            return Err(Conflict("stdout_to_file", "spawn_with_output"));
+        }
+        if capture && last_stdout.is_some_and(|spec| *spec != StdioSpec::Piped) {
+            return Err(Conflict("set_stage_stdout", "spawn_with_output"));
+        }
+
+        Ok(())
+    }
+
+    /// Returns the [`Output`] of the pipe.
+    ///
+    /// Fails with [APipeError::NotSpawned] if the pipe was never spawned and
+    /// with [APipeError::NoRunningProcesses] if the output was already
+    /// collected.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// let mut pipe = apipe::CommandPipe::new();
+    ///
+    /// pipe.add_command("echo")
+    ///     .arg("This is a test.")
+    ///     .add_command("grep")
+    ///     .arg("-Eo")
+    ///     .arg(r"\w\w\sa[^.]*")
+    ///     .spawn()
+    ///     .expect("Failed to spawn pipe.");
+    ///     
+    /// let output = pipe.output().unwrap();
+    ///     
+    /// assert_eq!(output.stdout(), "is a test\n".as_bytes());
+    /// ```
+    pub fn output(&mut self) -> Result<Output> {
+        let output = match self.collected.take() {
+            Some(output) => output,
+            None => self.collect()?,
+        };
+        self.state = State::Collected;
+
+        Ok(output)
+    }
+
+    /// Waits for a started pipe to finish and returns the exit status of its
+    /// last stage.
+    ///
+    /// The output is collected while waiting and kept until it's retrieved
+    /// with [CommandPipe::output]. Every stage is only ever waited on once:
+    /// calling this again, or after the output was collected, returns the
+    /// status the pipe exited with instead. Fails with
+    /// [APipeError::NotSpawned] if the pipe was never spawned.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use apipe::CommandPipe;
+    /// # fn main() -> Result<(), apipe::error::APipeError> {
+    /// let mut pipe = CommandPipe::new();
+    /// pipe.add_command("echo").arg("This is a test.").start()?;
+    ///
+    /// assert!(pipe.wait()?.success());
+    /// assert_eq!(pipe.output()?.stdout(), "This is a test.\n".as_bytes());
+    /// assert!(pipe.wait()?.success());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn wait(&mut self) -> Result<ExitStatus> {
+        if let Some(ref output) = self.collected {
+            return Ok(output.0.status);
+        }
+        if self.state == State::Collected {
+            return self
+                .statuses
+                .last()
+                .copied()
+                .ok_or(APipeError::NoRunningProcesses);
+        }
+
+        let output = self.collect()?;
+        let status = output.0.status;
+        self.collected = Some(output);
+        self.state = State::Collected;
+
+        Ok(status)
+    }
+
+    /// Waits for every stage of a started pipe exactly once and returns the
+    /// output of the last one.
+    fn collect(&mut self) -> Result<Output> {
+        let mut stdout = Vec::with_capacity(self.config.output_capacity);
+        let mut output = self.collect_into(Some(&mut stdout))?;
+        output.0.stdout = stdout;
+
+        Ok(output)
+    }
+
+    /// Like [CommandPipe::collect], but appends the stdout of the last stage
+    /// to `stdout` instead of returning it in the [Output], or discards it if
+    /// there is no `stdout`.
+    ///
+    /// The stdout of the last stage is read to the end before waiting, so a
+    /// stage can't block on a full pipe while it's waited on.
+    fn collect_into(&mut self, mut stdout: Option<&mut Vec<u8>>) -> Result<Output> {
+        self.close_stdin();
+
+        let Some(mut last_proc) = self.children.pop() else {
+            return Err(match self.state {
+                State::Fresh => APipeError::NotSpawned,
+                _ => APipeError::NoRunningProcesses,
+            });
+        };
+        let last = self.children.len();
+
+        let mut children = std::mem::take(&mut self.children);
+        let out = last_proc
+            .stdout
+            .take()
+            .map(|out| self.progress_reader(self.stdout_reader(out, last)));
+        children.push(last_proc);
+
+        let read_error =
+            |e| APipeError::child_process(e, "failed to read stdout of", &self.pipeline[last]);
+        let (lines, chunk) = (self.config.max_output_lines, self.chunk_size());
+        let tail = self.config.tail_output_bytes;
+
+        let waited = if self.polls() {
+            self.wait_with_deadlines(&mut children, out, stdout.as_deref_mut())?
+        } else {
+            if let Some(mut out) = out {
+                if read_stdout(&mut out, stdout.as_deref_mut(), lines, tail, chunk)
+                    .map_err(read_error)?
+                {
+                    drop(out);
+                    kill_running(&mut children);
+                }
+            }
+            let (statuses, usages): (Vec<_>, _) =
+                self.wait_children(&mut children)?.into_iter().unzip();
+            let actions = (statuses.iter().enumerate())
+                .map(|(i, status)| self.stage_action(i, *status))
+                .collect();
+
+            Waited {
+                statuses,
+                usages,
+                actions,
+                timed_out: None,
+            }
+        };
+        let Waited {
+            statuses,
+            usages,
+            actions,
+            timed_out,
+        } = waited;
+        self.actions = actions;
+
+        let stdin_written = self.join_feeder().map_err(|e| e.completed(&statuses))?;
+
+        for (i, merger) in std::mem::take(&mut self.mergers) {
+            merger.join().map_err(panicked)?.map_err(|e| {
+                APipeError::child_process(e, "failed to pipe stderr into", &self.pipeline[i])
+                    .completed(&statuses)
+            })?;
+        }
+
+        for (i, tee) in std::mem::take(&mut self.tees) {
+            let captured = tee.join().map_err(panicked)?.map_err(|e| {
+                APipeError::child_process(e, "failed to capture stdout of", &self.pipeline[i])
+                    .completed(&statuses)
+            })?;
+            if self.pipeline[i].1.capture {
+                self.stage_outputs.insert(i, captured);
+            }
+        }
+        if self.pipeline[last].1.capture {
+            let captured = stdout.as_deref().cloned().unwrap_or_default();
+            self.stage_outputs.insert(last, captured);
+        }
+
+        let mut stderr = Vec::new();
+        let mut stderr_ends = vec![0; self.pipeline.len()];
+        for (i, reader) in std::mem::take(&mut self.stderr_readers) {
+            let captured = reader.join().map_err(panicked)?.map_err(|e| {
+                APipeError::child_process(e, "failed to read stderr of", &self.pipeline[i])
+                    .completed(&statuses)
+            })?;
+            stderr.extend(captured);
+            stderr_ends[i..].fill(stderr.len());
+        }
+
+        let status = *statuses.last().ok_or(APipeError::NoRunningProcesses)?;
+        let mut output = Output::from(process::Output {
+            status,
+            stdout: Vec::new(),
+            stderr,
+        });
+        output.1.stdin_written = stdin_written;
+        self.statuses.clone_from(&statuses);
+        output.1.statuses = statuses;
+        output.1.executed = std::mem::take(&mut self.executed);
+        output.1.stderr_ends = stderr_ends;
+        output.1.usages = usages;
+        if let Some(log) = self.tagged_log.take() {
+            output.1.tagged_log =
+                std::mem::take(&mut *log.lock().unwrap_or_else(PoisonError::into_inner));
+            output.1.combined = terminal_lines(&output.1.tagged_log, last);
+        }
+
+        if let Some(stage) = timed_out {
+            return Err(APipeError::StageTimeout { stage });
+        }
+
+        Ok(output)
+    }
+}
+
+impl Drop for CommandPipe {
+    fn drop(&mut self) {
+        if self.config.kill_on_drop {
+            kill_all(&mut self.children);
+        }
+    }
+}
+
+/// Spawns the `prepared` command of the stage at index `stage` reading from
+/// `stdin` and writing to `stdout`, or the next stage if it's `None`.
+///
+/// Transient failures are retried up to `retries` times.
+fn spawn_stage(
+    mut prepared: process::Command,
+    command: &Command,
+    stage: usize,
+    stdin: Stdio,
+    stdout: Option<&StdioSpec>,
+    retries: u32,
+) -> Result<Child> {
+    let stdout = stdout
+        .map_or(Ok(Stdio::piped()), StdioSpec::to_stdio)
+        .map_err(|e| APipeError::child_process(e, "failed to open stdout of", command))?;
+
+    // The files have to stay open until the stage was spawned.
+    #[cfg(unix)]
+    let _files = redirect_fds(&mut prepared, &command.1.fd_redirects)
+        .map_err(|e| APipeError::child_process(e, "failed to open redirection of", command))?;
+
+    prepared.stdin(stdin).stdout(stdout);
+
+    retry_transient(retries, || prepared.spawn()).map_err(|e| APipeError::spawn(e, command, stage))
+}
+
+/// Calls `spawn` until it succeeds, fails with an error that isn't
+/// transient, or was retried `retries` times.
+fn retry_transient<T, F>(retries: u32, mut spawn: F) -> io::Result<T>
+where
+    F: FnMut() -> io::Result<T>,
+{
+    let mut attempt = 0;
+
+    loop {
+        match spawn() {
+            // `EAGAIN` from `fork` is reported as `WouldBlock`.
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock && attempt < retries => {
+                attempt += 1;
+                thread::sleep(SPAWN_RETRY_DELAY);
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Applies the file descriptor redirections of a stage to `prepared`.
+///
+/// Returns the opened files, which have to be kept open until the stage was
+/// spawned.
+#[cfg(unix)]
+fn redirect_fds(
+    prepared: &mut process::Command,
+    redirects: &[(i32, FdTarget)],
+) -> io::Result<Vec<std::os::fd::OwnedFd>> {
+    use std::{
+        fs::File,
+        os::{fd::AsRawFd, unix::process::CommandExt},
+    };
+
+    if redirects.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    // Files are moved above all redirected descriptors, so redirecting one
+    // descriptor can't replace a file that is redirected to later.
+    let min = redirects
+        .iter()
+        .map(|&(fd, _)| fd)
+        .max()
+        .unwrap_or(0)
+        .saturating_add(1);
+    let mut files = Vec::new();
+    let mut dups = Vec::with_capacity(redirects.len());
+
+    for (fd, target) in redirects {
+        let file = match target {
+            FdTarget::Fd(to) => {
+                dups.push((*fd, *to));
+                continue;
+            }
+            FdTarget::File(path) => File::create(path)?,
+            FdTarget::Null => File::options().write(true).open("/dev/null")?,
+        };
+        let file = crate::unix::move_fd_above(file, min)?;
+        dups.push((*fd, file.as_raw_fd()));
+        files.push(file);
+    }
+
+    // SAFETY: `unix::dup_fds` only calls async-signal-safe functions.
+    unsafe {
+        prepared.pre_exec(move || crate::unix::dup_fds(&dups));
+    }
+
+    Ok(files)
+}
+
+/// Returns the command line `prepared` runs, with the program resolved to
+/// the file that is executed.
+fn executed_command_line(prepared: &process::Command) -> String {
+    let program = resolve_program(prepared);
+    let mut line = String::new();
+    let _ = crate::cmd::write_command_line(&mut line, program.as_os_str(), prepared.get_args());
+
+    line
+}
+
+/// Looks up the program of `prepared` in the `PATH` the way spawning it
+/// does, if it's given by name only.
+///
+/// Returns the program unchanged if it isn't found.
+fn resolve_program(prepared: &process::Command) -> PathBuf {
+    let program = std::path::Path::new(prepared.get_program());
+
+    if program.components().count() != 1 || program.has_root() {
+        return program.to_path_buf();
+    }
+
+    let path = match prepared.get_envs().find(|(key, _)| *key == "PATH") {
+        Some((_, path)) => path.map(OsStr::to_os_string),
+        None => std::env::var_os("PATH"),
+    };
+    let candidates = |dir: PathBuf| {
+        let file = dir.join(program);
+        let exe = cfg!(windows).then(|| file.with_extension("exe"));
+        std::iter::once(file).chain(exe)
+    };
+
+    path.iter()
+        .flat_map(std::env::split_paths)
+        .flat_map(candidates)
+        .find(|file| file.is_file())
+        .unwrap_or_else(|| program.to_path_buf())
+}
+
+/// Builds a command running `command` through `shell`.
+///
+/// The environment and working directory of `command` are kept.
+fn shell_command(shell: &OsStr, command: &Command) -> process::Command {
+    let is_cmd = std::path::Path::new(shell)
+        .file_stem()
+        .is_some_and(|stem| stem.eq_ignore_ascii_case("cmd"));
+
+    let mut prepared = process::Command::new(shell);
+    prepared
+        .arg(if is_cmd { "/C" } else { "-c" })
+        .arg(command.to_string());
+
+    if command.1.env_clear {
+        prepared.env_clear();
+    }
+    if let Some(dir) = command.0.get_current_dir() {
+        prepared.current_dir(dir);
+    }
+
+    prepared
+}
+
+/// Kills all children without reaping them, ignoring the ones that already
+/// exited.
+fn kill_running(children: &mut [Child]) {
+    for child in children {
+        let _ = child.kill();
+    }
+}
+
+/// Kills the children that weren't reaped yet according to `statuses`.
+///
+/// A reaped child is skipped since its process id may already be reused.
+fn kill_unreaped(children: &mut [Child], statuses: &[Option<ExitStatus>]) {
+    for (child, _) in children
+        .iter_mut()
+        .zip(statuses)
+        .filter(|(_, s)| s.is_none())
+    {
+        let _ = child.kill();
+    }
+}
+
+/// Waits for `child` to exit and returns its status and, on Unix, its
+/// resource usage.
+fn wait_stage(child: &mut Child) -> io::Result<(ExitStatus, Option<ResourceUsage>)> {
+    #[cfg(unix)]
+    if let Some(reaped) = crate::unix::wait_with_usage(child, true)? {
+        return Ok(reaped);
+    }
+
+    child.wait().map(|status| (status, None))
+}
+
+/// Like [wait_stage], but returns `None` if `child` is still running.
+fn try_wait_stage(child: &mut Child) -> io::Result<Option<(ExitStatus, Option<ResourceUsage>)>> {
+    #[cfg(unix)]
+    return crate::unix::wait_with_usage(child, false);
+
+    #[cfg(not(unix))]
+    child
+        .try_wait()
+        .map(|status| status.map(|status| (status, None)))
+}
+
+/// Turns the payload of a panic into an [APipeError::Panicked] with its
+/// message.
+fn panicked(payload: Box<dyn Any + Send>) -> APipeError {
+    let msg = match payload.downcast::<String>() {
+        Ok(msg) => *msg,
+        Err(payload) => match payload.downcast::<&'static str>() {
+            Ok(msg) => (*msg).to_owned(),
+            Err(_) => "unknown panic".to_owned(),
+        },
+    };
+
+    APipeError::Panicked(msg)
+}
+
+/// Kills and reaps all children, ignoring the ones that already exited.
+fn kill_all(children: &mut Vec<Child>) {
+    for mut child in children.drain(..) {
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+}
+
+/// Writes `bytes` to `stdin` from a new thread and closes it afterwards.
+///
+/// Returns the number of bytes written. A consumer that exits before reading
+/// all of its input is not an error.
+fn feed_stdin(
+    mut stdin: ChildStdin,
+    bytes: Vec<u8>,
+    chunk: usize,
+) -> JoinHandle<io::Result<usize>> {
+    thread::spawn(move || {
+        let mut written = 0;
+
+        while written < bytes.len() {
+            let end = bytes.len().min(written + chunk);
+
+            match stdin.write(&bytes[written..end]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => written += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) if e.kind() == io::ErrorKind::BrokenPipe => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(written)
+    })
+}
+
+/// A reader passing every chunk read through it to a [StdoutTap].
+struct Tapped<R> {
+    inner: R,
+    tap: StdoutTap,
+}
+
+impl<R: Read> Read for Tapped<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+
+        if n > 0 {
+            (self.tap.0.lock().unwrap_or_else(PoisonError::into_inner))(&buf[..n]);
+        }
+
+        Ok(n)
+    }
+}
+
+/// A reader passing the total number of bytes read through it to a
+/// [Progress] callback after every read.
+struct Counted<R> {
+    inner: R,
+    progress: Progress,
+    total: u64,
+}
+
+impl<R: Read> Read for Counted<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+
+        if n > 0 {
+            self.total += n as u64;
+            (self
+                .progress
+                .0
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner))(self.total);
+        }
+
+        Ok(n)
+    }
+}
+
+/// A callback receiving the number of bytes of output read so far.
+///
+/// Clones of a pipe share the callback.
+#[derive(Clone)]
+struct Progress(Arc<Mutex<dyn FnMut(u64) + Send>>);
+
+impl fmt::Debug for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Progress(..)")
+    }
+}
+
+/// A writer the command line of a pipe is printed to before it's started.
+///
+/// Clones of a pipe share the writer.
+#[derive(Clone)]
+struct Verbose(Arc<Mutex<dyn Write + Send>>);
+
+impl fmt::Debug for Verbose {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Verbose(..)")
+    }
+}
+
+/// Lines emitted by the stages of a pipe, each prefixed with a tag naming
+/// its origin, in the order they were read.
+type TaggedLog = Arc<Mutex<Vec<u8>>>;
+
+/// Returns the lines of a tagged `log` that would have appeared on a
+/// terminal, i.e. the stdout of the `last` stage and the stderr of all
+/// stages, without their tags.
+fn terminal_lines(log: &[u8], last: usize) -> Vec<u8> {
+    let stdout_tag = format!("[stage{}:out] ", last);
+    let mut lines = Vec::new();
+
+    for line in log.split_inclusive(|&b| b == b'\n') {
+        // Every line starts with a tag like `[stage0:err] `.
+        let end = line.iter().position(|&b| b == b']').map_or(0, |i| i + 2);
+        let tag = &line[..end.min(line.len())];
+
+        if tag.ends_with(b":err] ") || tag == stdout_tag.as_bytes() {
+            lines.extend_from_slice(&line[tag.len()..]);
+        }
+    }
+
+    lines
+}
+
+/// A reader adding every complete line read through it to a [TaggedLog].
+struct Tagged<R> {
+    inner: R,
+    log: TaggedLog,
+    tag: String,
+    line: Vec<u8>,
+}
+
+impl<R> Tagged<R> {
+    fn flush_line(&mut self) {
+        let mut log = self.log.lock().unwrap_or_else(PoisonError::into_inner);
+        log.extend_from_slice(self.tag.as_bytes());
+        log.append(&mut self.line);
+    }
+}
+
+impl<R: Read> Read for Tagged<R> {
+    /// Reads from the wrapped reader. A final line without a newline is
+    /// added to the log with a newline once the end is reached.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+
+        if n == 0 && !self.line.is_empty() {
+            self.line.push(b'\n');
+            self.flush_line();
+        }
+        for &b in &buf[..n] {
+            self.line.push(b);
+            if b == b'\n' {
+                self.flush_line();
+            }
+        }
+
+        Ok(n)
+    }
+}
+
+/// Appends the stdout of the last stage to `buf`, at most `lines` lines and
+/// only the last `tail` bytes of them if given. Without a `buf` the stdout is
+/// read and discarded.
+///
+/// Returns whether the line limit was reached before the end of `source`.
+fn read_stdout(
+    source: &mut dyn Read,
+    buf: Option<&mut Vec<u8>>,
+    lines: Option<usize>,
+    tail: Option<usize>,
+    chunk: usize,
+) -> io::Result<bool> {
+    match (buf, lines, tail) {
+        (Some(buf), Some(lines), tail) => {
+            let start = buf.len();
+            let limited = read_lines(source, buf, lines, chunk)?;
+            if let Some(tail) = tail {
+                let excess = (buf.len() - start).saturating_sub(tail);
+                buf.drain(start..start + excess);
+            }
+            Ok(limited)
+        }
+        (Some(buf), None, Some(tail)) => read_tail(source, buf, tail, chunk).map(|_| false),
+        (Some(buf), None, None) => source.read_to_end(buf).map(|_| false),
+        (None, _, _) => io::copy(source, &mut io::sink()).map(|_| false),
+    }
+}
+
+/// Appends the last `bytes` bytes read from `source` to `buf`, keeping no
+/// more than that many in memory.
+fn read_tail<R: Read + ?Sized>(
+    source: &mut R,
+    buf: &mut Vec<u8>,
+    bytes: usize,
+    chunk: usize,
+) -> io::Result<()> {
+    let mut tail: VecDeque<u8> = VecDeque::with_capacity(bytes);
+    let mut chunk = vec![0; chunk];
+
+    loop {
+        let n = match source.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+
+        let read = &chunk[n.saturating_sub(bytes)..n];
+        let excess = (tail.len() + read.len()).saturating_sub(bytes);
+        tail.drain(..excess);
+        tail.extend(read);
+    }
+    buf.extend(tail);
+
+    Ok(())
+}
+
+/// Appends at most `lines` lines read from `source` to `buf`.
+///
+/// Returns whether the limit was reached before the end of `source`.
+fn read_lines<R: Read + ?Sized>(
+    source: &mut R,
+    buf: &mut Vec<u8>,
+    lines: usize,
+    chunk: usize,
+) -> io::Result<bool> {
+    let mut remaining = lines;
+    let mut chunk = vec![0; chunk];
+
+    while remaining > 0 {
+        let n = match source.read(&mut chunk) {
+            Ok(0) => return Ok(false),
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+
+        let mut end = n;
+        for (pos, _) in chunk[..n].iter().enumerate().filter(|(_, &b)| b == b'\n') {
+            remaining -= 1;
+            if remaining == 0 {
+                end = pos + 1;
+                break;
+            }
+        }
+        buf.extend_from_slice(&chunk[..end]);
+    }
+
+    Ok(true)
+}
+
+/// Reads everything from `source` from a new thread.
+fn read_all<R>(mut source: R) -> JoinHandle<io::Result<Vec<u8>>>
+where
+    R: Read + Send + 'static,
+{
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        source.read_to_end(&mut buf).map(|_| buf)
+    })
+}
+
+/// Copies everything from `source` into a new pipe from a new thread.
+///
+/// Returns the read end of the pipe and a handle to the copied bytes. If the
+/// reader goes away, copying stops and `source` is dropped, so a stage writing
+/// to it gets `SIGPIPE` like it would without the copy.
+fn tee<R>(
+    mut source: R,
+    chunk: usize,
+) -> io::Result<(io::PipeReader, JoinHandle<io::Result<Vec<u8>>>)>
+where
+    R: Read + Send + 'static,
+{
+    let (reader, mut writer) = io::pipe()?;
+
+    let handle = thread::spawn(move || {
+        let mut captured = Vec::new();
+        let mut buf = vec![0; chunk];
+
+        loop {
+            let n = match source.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            captured.extend_from_slice(&buf[..n]);
+
+            match writer.write_all(&buf[..n]) {
+                Err(e) if e.kind() == io::ErrorKind::BrokenPipe => break,
+                result => result?,
+            }
+        }
+
+        Ok(captured)
+    });
+
+    Ok((reader, handle))
+}
+
+/// Copies everything from all `sources` into a single new pipe, each from its
+/// own thread.
+///
+/// Returns the read end of the pipe and handles to the threads. If the reader
+/// goes away, the threads stop copying.
+fn merge(
+    sources: Vec<Box<dyn Read + Send>>,
+    chunk: usize,
+) -> io::Result<(io::PipeReader, Vec<JoinHandle<io::Result<()>>>)> {
+    let (reader, writer) = io::pipe()?;
+    let mut handles = Vec::with_capacity(sources.len());
+
+    for mut source in sources {
+        let mut writer = writer.try_clone()?;
+
+        handles.push(thread::spawn(move || {
+            let mut buf = vec![0; chunk];
+
+            loop {
+                let n = match source.read(&mut buf) {
+                    Ok(0) => return Ok(()),
+                    Ok(n) => n,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e),
+                };
+
+                match writer.write_all(&buf[..n]) {
+                    Err(e) if e.kind() == io::ErrorKind::BrokenPipe => return Ok(()),
+                    result => result?,
+                }
+            }
+        }));
+    }
+
+    Ok((reader, handles))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_arg() {
+        let mut pipe = CommandPipe::new();
+
+        pipe.add_command("ls").arg("-la").arg("~/Documents");
+
+        let args: Vec<&OsStr> = pipe.pipeline[0].0.get_args().collect();
+
+        assert_eq!(args, &["-la", "~/Documents"])
+    }
+
+    #[test]
+    fn test_args() {
+        let mut pipe = CommandPipe::new();
+
+        pipe.add_command("ls").args(vec!["-la", "~/Documents"]);
+
+        let args: Vec<&OsStr> = pipe.pipeline[0].0.get_args().collect();
+
+        assert_eq!(args, &["-la", "~/Documents"])
+    }
+
+    #[test]
+    fn test_pipe() {
+        let mut pipe = CommandPipe::new();
+
+        pipe.add_command("echo")
+            .arg("This is a test.")
+            .add_command("grep")
+            .arg("-Eo")
+            .arg(r"\w\w\sa[^.]*")
+            .spawn()
+            .expect("Failed to spawn pipe.");
+
+        let output = pipe.output().unwrap();
+
+        assert_eq!(output.stdout(), "is a test\n".as_bytes());
+    }
+
+    #[test]
+    fn test_pipe_output_after_spawn() {
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("echo")
+            .arg("This is a test.")
+            .add_command("grep")
+            .arg("-Eo")
+            .arg(r"\w\w\sa[^.]*")
+            .add_command("cat");
+
+        for _ in 0..20 {
+            pipe.spawn().expect("Failed to spawn pipe.");
+
+            assert!(pipe.children.is_empty());
+            assert_eq!(pipe.output().unwrap().stdout(), "is a test\n".as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_run() {
+        let output = CommandPipe::new()
+            .add_command("echo")
+            .arg("This is a test.")
+            .add_command("tr")
+            .args(["a-z", "A-Z"])
+            .run()
+            .unwrap()
+            .output()
+            .unwrap();
+
+        assert_eq!(output, "THIS IS A TEST.\n");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_wait_all_concurrent() {
+        let reaped = |pid: u32| !std::path::Path::new(&format!("/proc/{}", pid)).exists();
+
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("sh")
+            .args(["-c", "sleep 0.5; exit 1"])
+            .add_command("sh")
+            .args(["-c", "echo last; exit 2"])
+            .wait_all_concurrent()
+            .start()
+            .unwrap();
+
+        let pids: Vec<u32> = pipe.children.iter().map(Child::id).collect();
+
+        let output = thread::scope(|scope| {
+            let collector = scope.spawn(|| pipe.output());
+            thread::sleep(std::time::Duration::from_millis(250));
+
+            // The last stage exited first and was reaped without waiting for
+            // the first one.
+            assert!(reaped(pids[1]));
+            assert!(!reaped(pids[0]));
+
+            collector.join().unwrap().unwrap()
+        });
+
+        assert_eq!(output, "last\n");
+        assert_eq!(output.status_code(), Some(2));
+    }
+
+    #[test]
+    fn test_spawn_collects_once() {
+        // More output than fits into a pipe buffer, so waiting on a stage
+        // before reading the output of the last one would block.
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("head")
+            .args(["-c", "1000000", "/dev/zero"])
+            .add_command("cat")
+            .spawn()
+            .unwrap();
+
+        assert!(pipe.children.is_empty());
+        assert!(pipe.collected.is_some());
+
+        let output = pipe.output().unwrap();
+
+        assert_eq!(output.status_code(), Some(0));
+        assert_eq!(output.stdout().len(), 1_000_000);
+        assert!(output.stdout().iter().all(|&b| b == 0));
+        assert!(matches!(pipe.output(), Err(APipeError::NoRunningProcesses)));
+    }
+
+    #[test]
+    fn test_prepend_command() {
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("cat")
+            .prepend_command(Command::new("echo").arg("hi"));
+
+        assert_eq!(pipe.pipeline.len(), 2);
+        assert_eq!(pipe.spawn_with_output().unwrap().stdout(), b"hi\n");
+    }
+
+    #[test]
+    fn test_prepend_command_keeps_stderr_pipes() {
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("sh")
+            .args(["-c", "echo err >&2"])
+            .add_command("cat")
+            .pipe_stderr(0, 1)
+            .unwrap()
+            .prepend_command("true");
+
+        assert_eq!(pipe.spawn_with_output().unwrap().stdout(), b"err\n");
+    }
+
+    #[test]
+    fn test_add_arg_without_command() {
+        let mut pipe = CommandPipe::new();
+        pipe.arg("ls");
+        assert!(matches!(pipe.validate(), Err(APipeError::EmptyPipe)));
+
+        pipe.args(["-l"]).add_command("echo").arg("hi");
+        assert!(matches!(
+            pipe.try_spawn_with_output(),
+            Err(APipeError::EmptyPipe)
+        ));
+        assert!(matches!(pipe.spawn(), Err(APipeError::EmptyPipe)));
+    }
+
+    #[test]
+    fn test_try_spawn_with_output() {
+        let output = CommandPipe::new()
+            .add_command("echo")
+            .arg("hi")
+            .try_spawn_with_output()
+            .unwrap();
+        assert_eq!(output, "hi\n");
+
+        assert!(matches!(
+            CommandPipe::new().try_spawn_with_output(),
+            Err(APipeError::EmptyPipe)
+        ));
+
+        let mut pipe = CommandPipe::from(vec![Command::new("ls"), Command::default()]);
+        assert!(matches!(
+            pipe.try_spawn_with_output(),
+            Err(APipeError::EmptyProgram(1))
+        ));
+    }
+
+    #[test]
+    fn test_try_spawn_with_output_panic() {
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("echo")
+            .arg("hi")
+            .add_command("cat")
+            .on_stage_stdout(0, |_| panic!("tap failed"))
+            .unwrap();
+
+        assert!(matches!(
+            pipe.try_spawn_with_output(),
+            Err(APipeError::Panicked(_))
+        ));
+
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("echo")
+            .arg("hi")
+            .on_progress(|_| panic!("progress failed"));
+
+        match pipe.try_spawn_with_output() {
+            Err(APipeError::Panicked(msg)) => assert_eq!(msg, "progress failed"),
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_try_spawn_with_output_poisoned() {
+        struct PanicOnce(bool);
+
+        impl Write for PanicOnce {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                if !std::mem::replace(&mut self.0, true) {
+                    panic!("write failed");
+                }
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut panicked = false;
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("echo")
+            .arg("hi")
+            .add_command("cat")
+            .verbose(PanicOnce(false))
+            .on_stage_stdout(0, move |_| {
+                if !std::mem::replace(&mut panicked, true) {
+                    panic!("tap failed");
+                }
+            })
+            .unwrap();
+
+        // The verbose writer panics, then the tap, then both work again.
+        for _ in 0..2 {
+            assert!(matches!(
+                pipe.try_spawn_with_output(),
+                Err(APipeError::Panicked(_))
+            ));
+        }
+        assert_eq!(pipe.try_spawn_with_output().unwrap(), "hi\n");
+    }
+
+    #[test]
+    fn test_spawn_with_output() {
+        let output = CommandPipe::new()
+            .add_command("echo")
+            .arg("This is a test.")
+            .add_command("grep")
+            .arg("-Eo")
+            .arg(r"\w\w\sa[^.]*")
+            .spawn_with_output()
+            .unwrap();
+
+        assert_eq!(output.stdout(), "is a test\n".as_bytes());
+    }
+
+    #[test]
+    #[allow(clippy::needless_borrows_for_generic_args)]
+    fn test_overload() {
+        let mut pipe = CommandPipe::new();
+
+        pipe = pipe | Command::new("grep");
+
+        assert_eq!(pipe.pipeline[0].0.get_program(), "grep");
+
+        let output = (Command::new("echo").arg("This is a test.")
+            | Command::new("grep").args(&["-Eo", r"\w\w\sa[^.]*"]))
+        .spawn_with_output()
+        .unwrap();
+
+        assert_eq!(output.stdout(), "is a test\n".as_bytes());
+    }
+
+    #[test]
+    fn test_env_all() {
+        let output = CommandPipe::new()
+            .add_command("printenv")
+            .arg("APIPE_TEST")
+            .env_all("APIPE_TEST", "pipe")
+            .spawn_with_output()
+            .unwrap();
+
+        assert_eq!(output.stdout(), "pipe\n".as_bytes());
+    }
+
+    #[test]
+    fn test_prepend_args_all() {
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("echo")
+            .arg("This is a test.")
+            .add_command("grep")
+            .arg("is a")
+            .prepend_args_all(["-n"]);
+
+        let output = pipe.spawn_with_output().unwrap();
+        let executed = output.executed_commands();
+
+        assert_eq!(output.stdout(), b"1:This is a test.\n");
+        assert!(executed[0].ends_with("echo -n 'This is a test.'"));
+        assert!(executed[1].ends_with("grep -n 'is a'"));
+        assert_eq!(pipe.pipeline[1].get_args().count(), 1);
+    }
+
+    #[test]
+    fn test_clean_env() {
+        let output = CommandPipe::new()
+            .add_command("printenv")
+            .arg("PATH")
+            .clean_env()
+            .spawn_with_output()
+            .unwrap();
+
+        assert!(output.stdout().is_empty());
+
+        let output = CommandPipe::new()
+            .add_command("printenv")
+            .arg("PATH")
+            .clean_env()
+            .env_all("PATH", "/usr/bin:/bin")
+            .spawn_with_output()
+            .unwrap();
+
+        assert_eq!(output.stdout(), "/usr/bin:/bin\n".as_bytes());
+    }
+
+    #[test]
+    fn test_clean_env_keeps_command_env() {
+        let mut pipe = Command::new("printenv")
+            .arg("APIPE_TEST")
+            .env("APIPE_TEST", "cmd")
+            | Command::new("cat");
+
+        let output = pipe
+            .clean_env()
+            .env_all("APIPE_TEST", "pipe")
+            .spawn_with_output()
+            .unwrap();
+
+        assert_eq!(output.stdout(), "cmd\n".as_bytes());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_spawn_detached() {
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("sleep")
+            .arg("5")
+            .add_command("sleep")
+            .arg("5");
+
+        let pids = pipe.spawn_detached().unwrap();
+
+        assert_eq!(pids.len(), 2);
+
+        for &pid in &pids {
+            let alive = std::process::Command::new("kill")
+                .args(["-0", &pid.to_string()])
+                .status()
+                .unwrap();
+
+            assert!(alive.success());
+
+            // Every stage leads its own session.
+            #[cfg(target_os = "linux")]
+            {
+                let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).unwrap();
+                let fields: Vec<&str> = stat.rsplit(')').next().unwrap().split(' ').collect();
+                assert_eq!(fields[4], pid.to_string());
+            }
+
+            std::process::Command::new("kill")
+                .arg(pid.to_string())
+                .status()
+                .unwrap();
+        }
+
+        // The killed stages are reaped instead of lingering as zombies.
+        #[cfg(target_os = "linux")]
+        {
+            let started = Instant::now();
+            while pids
+                .iter()
+                .any(|pid| std::path::Path::new(&format!("/proc/{}", pid)).exists())
+            {
+                assert!(started.elapsed() < Duration::from_secs(5));
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_spawn_detached_settings() {
+        assert!(matches!(
+            CommandPipe::new().spawn_detached(),
+            Err(APipeError::EmptyPipe)
+        ));
+        let mut pipe = CommandPipe::new();
+        pipe.arg("x");
+        assert!(matches!(pipe.spawn_detached(), Err(APipeError::EmptyPipe)));
+
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("cat").stdin_bytes("in");
+        assert!(matches!(
+            pipe.spawn_detached(),
+            Err(APipeError::ConflictingRedirection("stdin_bytes", _))
+        ));
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("cat").first_stdin_raw(Stdio::null());
+        assert!(matches!(
+            pipe.spawn_detached(),
+            Err(APipeError::ConflictingRedirection("first_stdin_raw", _))
+        ));
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("ls")
+            .add_command("cat")
+            .pipe_stderr(0, 1)
+            .unwrap();
+        assert!(matches!(
+            pipe.spawn_detached(),
+            Err(APipeError::ConflictingRedirection("pipe_stderr", _))
+        ));
+
+        let path = std::env::temp_dir().join("apipe_test_spawn_detached_settings.txt");
+        let _ = std::fs::remove_file(&path);
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("sh")
+            .args(["-c", "echo hi >&3"])
+            .redirect_fd(0, 3, FdTarget::File(path.clone()))
+            .unwrap();
+        pipe.spawn_detached().unwrap();
+
+        let started = Instant::now();
+        while std::fs::read(&path).unwrap_or_default() != b"hi\n" {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_output_capacity() {
+        let output = CommandPipe::new()
+            .add_command("echo")
+            .arg("This is a test.")
+            .output_capacity(4096)
+            .spawn_with_output()
+            .unwrap();
+
+        assert!(output.0.stdout.capacity() >= 4096);
+        assert_eq!(output.stdout(), "This is a test.\n".as_bytes());
+    }
+
+    #[test]
+    fn test_stdin_bytes() {
+        let output = CommandPipe::new()
+            .add_command("cat")
+            .add_command("grep")
+            .arg("foo")
+            .stdin_bytes("foo\nbar\n")
+            .spawn_with_output()
+            .unwrap();
+
+        assert_eq!(output.stdout(), "foo\n".as_bytes());
+    }
+
+    #[test]
+    fn test_first_stdin_raw() {
+        let (reader, mut writer) = io::pipe().unwrap();
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("cat")
+            .add_command("tr")
+            .args(["a-z", "A-Z"])
+            .first_stdin_raw(reader);
+
+        writer.write_all(b"hello\n").unwrap();
+        drop(writer);
+
+        assert_eq!(pipe.spawn_with_output().unwrap(), "HELLO\n");
+        // The stdin was used up by the first run.
+        assert_eq!(pipe.spawn_with_output().unwrap(), "");
+    }
+
+    #[test]
+    fn test_first_stdin_raw_conflicts() {
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("cat")
+            .first_stdin_raw(Stdio::null())
+            .stdin_bytes("foo");
+
+        assert!(matches!(
+            pipe.spawn(),
+            Err(APipeError::ConflictingRedirection(
+                "first_stdin_raw",
+                "stdin_bytes"
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_no_stdin_close() {
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("cat")
+            .add_command("cat")
+            .no_stdin_close()
+            .start()
+            .unwrap();
+
+        let mut partial = [0; 4];
+        pipe.stdin().unwrap().write_all(b"foo\n").unwrap();
+        pipe.stdout().unwrap().read_exact(&mut partial).unwrap();
+        assert_eq!(&partial, b"foo\n");
+
+        pipe.stdin().unwrap().write_all(b"bar\n").unwrap();
+        pipe.close_stdin();
+        assert!(pipe.stdin().is_none());
+
+        assert_eq!(pipe.output().unwrap().stdout(), b"bar\n");
+    }
+
+    #[test]
+    fn test_no_stdin_close_closed_by_output() {
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("cat").no_stdin_close().start().unwrap();
+
+        pipe.stdin().unwrap().write_all(b"foo").unwrap();
+
+        assert_eq!(pipe.output().unwrap().stdout(), b"foo");
+    }
+
+    #[test]
+    fn test_stdin_bytes_written() {
+        let output = CommandPipe::new()
+            .add_command("cat")
+            .stdin_bytes("This is a test.")
+            .spawn_with_output()
+            .unwrap();
+
+        assert_eq!(output.stdin_bytes_written(), 15);
+
+        let input = vec![b'x'; 1 << 20];
+        let output = CommandPipe::new()
+            .add_command("head")
+            .args(["-c", "4"])
+            .stdin_bytes(input.clone())
+            .spawn_with_output()
+            .unwrap();
+
+        assert_eq!(output.stdout(), b"xxxx");
+        assert!(output.stdin_bytes_written() >= 4);
+        assert!(output.stdin_bytes_written() < input.len());
+    }
+
+    #[test]
+    fn test_io_chunk_size() {
+        let input: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+
+        for chunk in [1, 7, 4096, DEFAULT_CHUNK_SIZE, 1 << 20] {
+            let mut pipe = CommandPipe::new();
+            pipe.add_command("cat")
+                .add_command("cat")
+                .stdin_bytes(input.clone())
+                .capture_stage(0)
+                .unwrap()
+                .io_chunk_size(chunk);
+
+            assert_eq!(pipe.chunk_size(), chunk);
+
+            let output = pipe.spawn_with_output().unwrap();
+
+            assert_eq!(output.stdout(), input.as_slice());
+            assert_eq!(pipe.stage_output(0), Some(input.as_slice()));
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_arg0() {
+        let output = CommandPipe::new()
+            .add_command("sh")
+            .arg0("custom-sh")
+            .args(["-c", "tr '\\0' ' ' < /proc/$$/cmdline"])
+            .spawn_with_output()
+            .unwrap();
+
+        assert!(output.stdout().starts_with(b"custom-sh -c "));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_pre_exec() {
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("sh")
+            .args(["-c", "echo \"$APIPE_PRE_EXEC\""])
+            .add_command("cat");
+
+        unsafe {
+            pipe.pre_exec(0, || {
+                // `std::env::set_var` would wait for the lock on the
+                // environment the parent holds while spawning.
+                match libc::setenv(c"APIPE_PRE_EXEC".as_ptr(), c"set".as_ptr(), 1) {
+                    0 => Ok(()),
+                    _ => Err(io::Error::last_os_error()),
+                }
+            })
+            .unwrap();
+        }
+        let output = pipe.clone().spawn_with_output().unwrap();
+        assert_eq!(output.stdout(), b"set\n");
+
+        unsafe {
+            pipe.pre_exec(1, || Err(io::Error::from_raw_os_error(libc::EPERM)))
+                .unwrap();
+        }
+        assert!(matches!(
+            pipe.spawn_with_output(),
+            Err(APipeError::ChildProcess { source, .. }) if source.raw_os_error() == Some(libc::EPERM)
+        ));
+        assert!(std::env::var_os("APIPE_PRE_EXEC").is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_redirect_fd() {
+        let path = std::env::temp_dir().join("apipe_test_redirect_fd.txt");
+        let output = CommandPipe::new()
+            .add_command("sh")
+            .args(["-c", "echo three >&3; echo four >&4; echo five >&5"])
+            .redirect_fd(0, 3, FdTarget::Fd(1))
+            .unwrap()
+            .redirect_fd(0, 4, FdTarget::File(path.clone()))
+            .unwrap()
+            .redirect_fd(0, 5, FdTarget::Null)
+            .unwrap()
+            .add_command("cat")
+            .spawn_with_output()
+            .unwrap();
+
+        assert_eq!(output.stdout(), b"three\n");
+        assert_eq!(std::fs::read(&path).unwrap(), b"four\n");
+        std::fs::remove_file(path).unwrap();
+
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("ls");
+        assert!(matches!(
+            pipe.redirect_fd(0, -1, FdTarget::Null),
+            Err(APipeError::InvalidFd(-1))
+        ));
+        assert!(matches!(
+            pipe.redirect_fd(1, 3, FdTarget::Null),
+            Err(APipeError::InvalidStage(1))
+        ));
+    }
+
+    #[cfg(all(unix, feature = "parser"))]
+    #[test]
+    fn test_parse_fd_redirect() {
+        let pipe = CommandPipe::try_from("ls 3>&1 -l 4>/tmp/a 5> /tmp/b | cat").unwrap();
+        let command = &pipe.pipeline[0];
+
+        assert_eq!(command.get_args().collect::<Vec<_>>(), ["-l"]);
+        assert_eq!(
+            command.1.fd_redirects,
+            [
+                (3, FdTarget::Fd(1)),
+                (4, FdTarget::File("/tmp/a".into())),
+                (5, FdTarget::File("/tmp/b".into()))
+            ]
+        );
+        assert!(matches!(
+            CommandPipe::try_from("ls 3>&x"),
+            Err(APipeError::Syntax(_))
+        ));
+        assert!(matches!(
+            CommandPipe::try_from("ls 3>"),
+            Err(APipeError::Syntax(_))
+        ));
+    }
+
+    #[test]
+    fn test_stdout_closed_early() {
+        let started = Instant::now();
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("sh")
+            .args(["-c", "while :; do echo y; sleep 0.05; done"])
+            .add_command("head")
+            .args(["-n", "1"]);
+
+        let output = pipe.clone().spawn_with_output().unwrap();
+        assert_eq!(output.stdout(), b"y\n");
+
+        // Capturing the output of the first stage must not keep it alive.
+        pipe.capture_stage(0).unwrap().spawn().unwrap();
+        assert_eq!(pipe.output().unwrap().stdout(), b"y\n");
+        assert!(pipe.stage_output(0).unwrap().starts_with(b"y\n"));
+
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_stdout_closed_early_sigpipe() {
+        use std::os::unix::process::ExitStatusExt;
+
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("sh")
+            .args(["-c", "while :; do echo y; sleep 0.05; done"])
+            .add_command("head")
+            .args(["-n", "1"])
+            .spawn()
+            .unwrap();
+
+        let output = pipe.output().unwrap();
+        assert_eq!(output.statuses()[0].signal(), Some(libc::SIGPIPE));
+    }
+
+    #[test]
+    fn test_display() {
+        let mut pipe = CommandPipe::new();
+        assert_eq!(pipe.to_string(), "");
+
+        pipe.add_command("echo")
+            .arg("This is a test.")
+            .add_command("grep")
+            .arg("test");
+        assert_eq!(pipe.to_string(), "echo 'This is a test.' | grep test");
+    }
+
+    #[test]
+    fn test_verbose() {
+        #[derive(Clone, Default)]
+        struct Shared(Arc<Mutex<Vec<u8>>>);
+
+        impl Write for Shared {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let trace = Shared::default();
+        let output = CommandPipe::new()
+            .add_command("echo")
+            .arg("hi")
+            .add_command("cat")
+            .verbose(trace.clone())
+            .spawn_with_output()
+            .unwrap();
+
+        assert_eq!(output.stdout(), b"hi\n");
+        assert_eq!(*trace.0.lock().unwrap(), b"+ echo hi | cat\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_to_shell_line() {
+        let path = std::env::temp_dir().join("apipe_test_to_shell_line.txt");
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("echo")
+            .args(["it's", "$HOME *"])
+            .add_command("tr")
+            .args(["a-z", "A-Z"])
+            .env_all("LC_ALL", "C")
+            .stdout_to_file(&path);
+
+        let line = pipe.to_shell_line();
+        assert_eq!(
+            line,
+            format!(
+                r"LC_ALL=C echo 'it'\''s' '$HOME *' < /dev/null | LC_ALL=C tr a-z A-Z > {}",
+                path.display()
+            )
+        );
+
+        pipe.spawn().unwrap();
+        let expected = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let status = process::Command::new("sh")
+            .args(["-c", &line])
+            .status()
+            .unwrap();
+        assert!(status.success());
+        assert_eq!(std::fs::read(&path).unwrap(), expected);
+        assert_eq!(expected, b"IT'S $HOME *\n");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_to_shell_line_settings() {
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("sh")
+            .args(["-c", "echo err >&2"])
+            .add_command("cat")
+            .pipe_stderr(0, 1)
+            .unwrap()
+            .redirect_fd(1, 3, FdTarget::Null)
+            .unwrap()
+            .stdin_bytes("in")
+            .clean_env();
+
+        assert_eq!(
+            pipe.to_shell_line(),
+            "printf '%s' in | env -i sh -c 'echo err >&2' 2>&1 | env -i cat 3> /dev/null"
+        );
+
+        let pipe = CommandPipe::from(vec![Command::new("ls").current_dir("/tmp dir")]);
+        assert_eq!(pipe.to_shell_line(), "(cd '/tmp dir' && ls < /dev/null)");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_to_shell_line_stdin() {
+        let stdin = b"-%s\\\xff\0'\n";
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("od").arg("-c").stdin_bytes(&stdin[..]);
+
+        let line = pipe.to_shell_line();
+        assert_eq!(line, r"printf '\055%%s\\\377\000'\''\012' | od -c");
+
+        let output = process::Command::new("sh")
+            .args(["-c", &line])
+            .output()
+            .unwrap();
+        assert_eq!(output.stdout, pipe.spawn_with_output().unwrap().stdout());
+
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("cat").first_stdin_raw(Stdio::null());
+        assert_eq!(pipe.to_shell_line(), "cat");
+    }
+
+    #[test]
+    fn test_clone() {
+        let mut pipe = Command::new("printenv").arg("APIPE_TEST").env_clear() | Command::new("cat");
+        pipe.env_all("APIPE_TEST", "pipe");
+
+        let mut clone = pipe.clone();
+
+        assert_eq!(clone.pipeline[0].0.get_program(), "printenv");
+        assert_eq!(clone.pipeline[1].0.get_program(), "cat");
+        assert_eq!(clone.spawn_with_output().unwrap().stdout(), b"pipe\n");
+        assert_eq!(pipe.spawn_with_output().unwrap().stdout(), b"pipe\n");
+    }
+
+    #[test]
+    fn test_clone_template() {
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("printenv")
+            .arg("APIPE_TEST")
+            .add_command("tr")
+            .args(["a-z", "A-Z"])
+            .env_all("APIPE_TEST", "template")
+            .start()
+            .unwrap();
+
+        let mut template = pipe.clone_template().unwrap();
+
+        assert_eq!(template.state, State::Fresh);
+        assert_eq!(template.spawn_with_output().unwrap(), "TEMPLATE\n");
+        assert_eq!(pipe.output().unwrap(), "TEMPLATE\n");
+
+        assert!(matches!(
+            CommandPipe::new().clone_template(),
+            Err(APipeError::EmptyPipe)
+        ));
+    }
+
+    #[test]
+    fn test_map_inputs() {
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("cat");
+
+        let inputs = vec![b"one".to_vec(), b"two".to_vec(), b"three".to_vec()];
+        let outputs: Vec<Output> = pipe
+            .map_inputs(inputs, 2)
+            .into_iter()
+            .map(|r| r.unwrap())
+            .collect();
+
+        assert_eq!(outputs.len(), 3);
+        assert_eq!(outputs[0].stdout(), b"one");
+        assert_eq!(outputs[1].stdout(), b"two");
+        assert_eq!(outputs[2].stdout(), b"three");
+    }
+
+    #[test]
+    fn test_map_inputs_panic() {
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("cat")
+            .on_progress(|_| panic!("progress failed"));
+
+        let results = pipe.map_inputs(vec![b"one".to_vec(), b"two".to_vec()], 2);
+
+        assert_eq!(results.len(), 2);
+        for result in results {
+            assert!(matches!(result, Err(APipeError::Panicked(msg)) if msg == "progress failed"));
+        }
+    }
+
+    #[test]
+    fn test_set_stage_stdout() {
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("echo")
+            .arg("This is a test.")
+            .add_command("cat")
+            .add_command("cat")
+            .set_stage_stdout(1, StdioSpec::Null)
+            .unwrap();
+
+        let output = pipe.spawn_with_output().unwrap();
+
+        assert!(output.stdout().is_empty());
+    }
+
+    #[test]
+    fn test_set_stage_stdout_file() {
+        let path = std::env::temp_dir().join("apipe_test_set_stage_stdout_file.txt");
+
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("echo")
+            .arg("This is a test.")
+            .set_stage_stdout(0, StdioSpec::File(path.clone()))
+            .unwrap()
+            .spawn()
+            .unwrap();
+
+        let output = pipe.output().unwrap();
+
+        assert!(output.stdout().is_empty());
+        assert_eq!(std::fs::read(&path).unwrap(), b"This is a test.\n");
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_set_stage_stdout_invalid_stage() {
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("echo");
+
+        let result = pipe.set_stage_stdout(1, StdioSpec::Null);
+
+        assert!(matches!(result, Err(APipeError::InvalidStage(1))));
+    }
+
+    #[test]
+    fn test_output_not_spawned() {
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("echo");
+
+        assert!(matches!(pipe.output(), Err(APipeError::NotSpawned)));
+    }
+
+    #[test]
+    fn test_output_already_collected() {
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("echo").spawn().unwrap();
+
+        assert!(pipe.output().is_ok());
+        assert!(matches!(pipe.output(), Err(APipeError::NoRunningProcesses)));
+    }
+
+    #[test]
+    fn test_from_iter() {
+        let mut pipe: CommandPipe = std::iter::once(Command::new("echo").arg("This is a test."))
+            .chain((0..3).map(|_| Command::new("cat")))
+            .collect();
+
+        assert_eq!(pipe.pipeline.len(), 4);
+
+        let output = pipe.spawn_with_output().unwrap();
+
+        assert_eq!(output.stdout(), "This is a test.\n".as_bytes());
+    }
+
+    #[test]
+    fn test_extend() {
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("echo").arg("This is a test.");
+
+        pipe.extend(vec![
+            Command::new("cat"),
+            Command::new("grep").args(["-Eo", r"\w\w\sa[^.]*"]),
+        ]);
+
+        assert_eq!(pipe.pipeline.len(), 3);
+
+        let output = pipe.spawn_with_output().unwrap();
+
+        assert_eq!(output.stdout(), "is a test\n".as_bytes());
+    }
+
+    #[test]
+    fn test_with_capacity() {
+        let mut pipe = CommandPipe::with_capacity(3);
+        assert!(pipe.pipeline.capacity() >= 3);
+        assert!(pipe.pipeline.is_empty());
+
+        pipe.add_command("echo")
+            .arg("This is a test.")
+            .add_command("grep")
+            .args(["-Eo", r"\w\w\sa[^.]*"]);
+
+        let output = pipe.spawn_with_output().unwrap();
+
+        assert_eq!(output.stdout(), "is a test\n".as_bytes());
+    }
+
+    #[test]
+    fn test_from_vec() {
+        let mut pipe = CommandPipe::from(vec![
+            Command::new("echo").arg("This is a test."),
+            Command::new("grep").args(["-Eo", r"\w\w\sa[^.]*"]),
+        ]);
+
+        let output = pipe.spawn_with_output().unwrap();
+
+        assert_eq!(output.stdout(), "is a test\n".as_bytes());
+    }
+
+    #[test]
+    fn test_start() {
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("echo")
+            .arg("This is a test.")
+            .add_command("cat")
+            .start()
+            .unwrap();
+
+        assert_eq!(pipe.children.len(), 2);
+        assert_eq!(pipe.output().unwrap().stdout(), b"This is a test.\n");
+    }
+
+    #[test]
+    fn test_children() {
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("echo")
+            .arg("foo")
+            .add_command("cat")
+            .add_command("cat");
+        assert!(pipe.children().is_empty());
+
+        pipe.start().unwrap();
+        let pids: Vec<u32> = pipe.children().iter().map(Child::id).collect();
+        assert_eq!(pids.len(), 3);
+        assert!(pids.iter().all(|&pid| pid != 0));
+
+        assert_eq!(pipe.output().unwrap(), "foo\n");
+        assert!(pipe.children().is_empty());
+    }
+
+    #[test]
+    fn test_is_running() {
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("sleep").arg("0.2");
+
+        assert!(!pipe.is_running());
+
+        pipe.start().unwrap();
+
+        assert!(pipe.is_running());
+
+        std::thread::sleep(std::time::Duration::from_millis(500));
+
+        assert!(!pipe.is_running());
+        assert_eq!(pipe.output().unwrap().status_code(), Some(0));
+        assert!(!pipe.is_running());
+    }
+
+    #[test]
+    fn test_num_running() {
+        let wait_for = |pipe: &mut CommandPipe, running: usize| {
+            let started = Instant::now();
+            while pipe.num_running() > running && started.elapsed() < Duration::from_secs(5) {
+                thread::sleep(Duration::from_millis(10));
+            }
+            pipe.num_running()
+        };
+
+        let mut pipe = CommandPipe::new();
+        assert_eq!(pipe.num_running(), 0);
+
+        pipe.add_command("sleep")
+            .arg("0.3")
+            .add_command("sleep")
+            .arg("1")
+            .start()
+            .unwrap();
+
+        assert_eq!(pipe.num_running(), 2);
+        assert_eq!(wait_for(&mut pipe, 1), 1);
+        assert_eq!(wait_for(&mut pipe, 0), 0);
+        assert!(pipe
+            .output()
+            .unwrap()
+            .statuses()
+            .iter()
+            .all(|s| s.success()));
+    }
+
+    #[test]
+    fn test_wait() {
+        let mut pipe = CommandPipe::new();
+        assert!(matches!(pipe.wait(), Err(APipeError::NotSpawned)));
+
+        pipe.add_command("echo")
+            .arg("This is a test.")
+            .add_command("sh")
+            .args(["-c", "cat; exit 3"])
+            .start()
+            .unwrap();
+
+        assert_eq!(pipe.wait().unwrap().code(), Some(3));
+        assert_eq!(pipe.wait().unwrap().code(), Some(3));
+
+        let output = pipe.output().unwrap();
+        assert_eq!(output.stdout(), b"This is a test.\n");
+        assert_eq!(pipe.wait().unwrap().code(), Some(3));
+
+        assert_eq!(pipe.statuses().len(), 2);
+        assert!(pipe.statuses()[0].success());
+        assert_eq!(pipe.statuses(), output.statuses());
+    }
+
+    #[test]
+    fn test_is_complete() {
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("sleep").arg("0.2");
+        assert!(!pipe.is_complete());
+
+        pipe.start().unwrap();
+        assert!(pipe.is_running());
+        assert!(!pipe.is_complete());
+
+        pipe.output().unwrap();
+        assert!(pipe.is_complete());
+        assert!(!pipe.is_running());
+        assert!(matches!(pipe.output(), Err(APipeError::NoRunningProcesses)));
+        assert!(pipe.is_complete());
+    }
+
+    #[test]
+    fn test_spawn_twice() {
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("echo").arg("first").spawn().unwrap();
+
+        pipe.add_command("tr").args(["a-z", "A-Z"]).start().unwrap();
+        assert_eq!(pipe.output().unwrap().stdout(), b"FIRST\n");
+        assert!(matches!(pipe.output(), Err(APipeError::NoRunningProcesses)));
+
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("sleep")
+            .arg("10")
+            .kill_on_drop(true)
+            .start()
+            .unwrap();
+        let pid = pipe.children[0].id();
+        pipe.start().unwrap();
+        assert_eq!(pipe.children.len(), 1);
+
+        // The stage of the first run was killed and reaped.
+        #[cfg(unix)]
+        // SAFETY: signal 0 only checks whether the process exists.
+        assert_eq!(unsafe { libc::kill(pid as libc::pid_t, 0) }, -1);
+        #[cfg(not(unix))]
+        let _ = pid;
+    }
+
+    #[test]
+    fn test_is_complete_spawn() {
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("echo").arg("x");
+
+        pipe.spawn().unwrap();
+        assert!(pipe.is_complete());
+        assert_eq!(pipe.output().unwrap().stdout(), b"x\n");
+        assert!(pipe.is_complete());
+
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("echo").arg("x").start().unwrap();
+        pipe.wait().unwrap();
+        assert!(pipe.is_complete());
+        assert_eq!(pipe.output().unwrap().stdout(), b"x\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_kill_on_drop() {
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("sleep")
+            .arg("10")
+            .kill_on_drop(true)
+            .start()
+            .unwrap();
+
+        let pid = pipe.children[0].id().to_string();
+        drop(pipe);
+
+        let alive = std::process::Command::new("kill")
+            .args(["-0", &pid])
+            .stderr(Stdio::null())
+            .status()
+            .unwrap();
+
+        assert!(!alive.success());
+    }
+
+    #[test]
+    fn test_error_last_completed_stage() {
+        let err = CommandPipe::new()
+            .add_command("echo")
+            .arg("test")
+            .add_command("sh")
+            .args(["-c", "cat; exit 3"])
+            .run_checked()
+            .unwrap_err();
+
+        assert!(matches!(
+            err,
+            APipeError::NonZeroExit {
+                last_completed: Some(0),
+                ..
+            }
+        ));
+
+        // An ignored failure of stage 0 doesn't count as completed.
+        let err = CommandPipe::new()
+            .add_command("sh")
+            .args(["-c", "echo test; exit 1"])
+            .add_command("sh")
+            .args(["-c", "cat; exit 3"])
+            .allow_exit_codes(0, &[1])
+            .unwrap()
+            .run_checked()
+            .unwrap_err();
+
+        assert!(matches!(
+            err,
+            APipeError::NonZeroExit {
+                last_completed: None,
+                ..
+            }
+        ));
+
+        // Stage 0 is still running when stage 1 fails to spawn.
+        let err = CommandPipe::new()
+            .add_command("sleep")
+            .arg("10")
+            .add_command("apipe-does-not-exist")
+            .spawn_with_output()
+            .unwrap_err();
+
+        assert!(matches!(
+            err,
+            APipeError::ChildProcess {
+                last_completed: None,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_capture_stage() {
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("echo")
+            .arg("This is a test.")
+            .add_command("grep")
+            .args(["-Eo", r"\w\w\sa[^.]*"])
+            .capture_stage(0)
+            .unwrap();
+
+        let output = pipe.spawn_with_output().unwrap();
+
+        assert_eq!(output.stdout(), "is a test\n".as_bytes());
+        assert_eq!(pipe.stage_output(0), Some("This is a test.\n".as_bytes()));
+        assert_eq!(pipe.stage_output(1), None);
+    }
+
+    #[test]
+    fn test_stage_output_struct() {
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("echo")
+            .arg("This is a test.")
+            .add_command("grep")
+            .arg("test")
+            .add_command("wc")
+            .arg("-l")
+            .capture_stage(1)
+            .unwrap();
+
+        assert_eq!(pipe.stage_output_struct(1), None);
+
+        pipe.spawn().unwrap();
+
+        let output = pipe.stage_output_struct(1).unwrap();
+        assert_eq!(output.stdout_str().unwrap(), "This is a test.\n");
+        assert_eq!(output.exit_code(), Some(0));
+        assert_eq!(output.statuses().len(), 1);
+        assert_eq!(pipe.stage_output_struct(0), None);
+    }
+
+    #[test]
+    fn test_on_stage_stdout() {
+        let first = Arc::new(Mutex::new(Vec::new()));
+        let last = Arc::new(Mutex::new(Vec::new()));
+        let (f, l) = (Arc::clone(&first), Arc::clone(&last));
+
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("seq")
+            .arg("10000")
+            .add_command("grep")
+            .arg("7")
+            .io_chunk_size(256)
+            .on_stage_stdout(0, move |chunk| f.lock().unwrap().extend_from_slice(chunk))
+            .unwrap()
+            .on_stage_stdout(1, move |chunk| l.lock().unwrap().extend_from_slice(chunk))
+            .unwrap();
+
+        let output = pipe.spawn_with_output().unwrap();
+        let expected = process::Command::new("seq").arg("10000").output().unwrap();
+
+        assert_eq!(*first.lock().unwrap(), expected.stdout);
+        assert_eq!(*last.lock().unwrap(), output.stdout());
+        assert!(matches!(
+            pipe.on_stage_stdout(2, |_| {}),
+            Err(APipeError::InvalidStage(2))
+        ));
+    }
+
+    #[test]
+    fn test_stage_timeout() {
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("sleep")
+            .arg("10")
+            .add_command("cat")
+            .stage_timeout(0, Duration::from_millis(100))
+            .unwrap();
+
+        let start = Instant::now();
+        let result = pipe.spawn_with_output();
+
+        assert!(matches!(result, Err(APipeError::StageTimeout { stage: 0 })));
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(matches!(
+            pipe.stage_timeout(2, Duration::from_secs(1)),
+            Err(APipeError::InvalidStage(2))
+        ));
+    }
+
+    #[test]
+    fn test_stage_timeout_not_reached() {
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("seq")
+            .arg("100000")
+            .add_command("tail")
+            .args(["-n", "1"])
+            .stage_timeout(1, Duration::from_secs(10))
+            .unwrap();
+
+        assert_eq!(pipe.spawn_with_output().unwrap(), "100000\n");
+    }
+
+    #[test]
+    fn test_capture_stage_invalid_stage() {
+        let mut pipe = CommandPipe::new();
+
+        assert!(matches!(
+            pipe.capture_stage(0),
+            Err(APipeError::InvalidStage(0))
+        ));
+    }
+
+    #[test]
+    fn test_stdout_to_file() {
+        let path = std::env::temp_dir().join("apipe_test_stdout_to_file.txt");
+
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("echo")
+            .arg("This is a test.")
+            .add_command("cat")
+            .stdout_to_file(&path)
+            .spawn()
+            .unwrap();
+
+        assert!(pipe.output().unwrap().stdout().is_empty());
+        assert_eq!(std::fs::read(&path).unwrap(), b"This is a test.\n");
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_conflicting_stdout_to_file_and_capture() {
+        let result = CommandPipe::new()
+            .add_command("echo")
+            .stdout_to_file("apipe_test_never_created.txt")
+            .spawn_with_output();
+
+        assert!(matches!(
+            result,
+            Err(APipeError::ConflictingRedirection(
+                "stdout_to_file",
+                "spawn_with_output"
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_conflicting_stdin() {
+        let result = CommandPipe::new()
+            .add_command("cat")
+            .inherit_stdin()
+            .stdin_bytes("test")
+            .spawn();
+
+        assert!(matches!(
+            result,
+            Err(APipeError::ConflictingRedirection(
+                "stdin_bytes",
+                "inherit_stdin"
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_conflicting_no_stdin_close() {
+        let result = CommandPipe::new()
+            .add_command("cat")
+            .no_stdin_close()
+            .stdin_bytes("test")
+            .spawn();
+
+        assert!(matches!(
+            result,
+            Err(APipeError::ConflictingRedirection(
+                "stdin_bytes",
+                "no_stdin_close"
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_spawn_into() {
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("echo")
+            .arg("This is a test.")
+            .add_command("grep")
+            .args(["-Eo", r"\w\w\sa[^.]*"]);
+
+        let mut buf = b"leftover".to_vec();
+
+        let status = pipe.spawn_into(&mut buf).unwrap();
+        assert!(status.success());
+        assert_eq!(buf, b"is a test\n");
+
+        let capacity = buf.capacity();
+
+        let status = pipe.spawn_into(&mut buf).unwrap();
+        assert!(status.success());
+        assert_eq!(buf, b"is a test\n");
+        assert_eq!(buf.capacity(), capacity);
+    }
+
+    #[test]
+    fn test_drain_output() {
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("head")
+            .args(["-c", "100000000", "/dev/zero"])
+            .add_command("cat")
+            .on_stage_stdout(1, |_| {})
+            .unwrap();
+
+        let status = pipe.drain_output().unwrap();
+
+        assert!(status.success());
+        assert!(pipe.collected.is_none());
+        assert!(matches!(pipe.output(), Err(APipeError::NoRunningProcesses)));
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("echo").add_command("cat");
+
+        assert!(pipe.validate().is_ok());
+    }
+
+    #[test]
+    fn test_validate_invalid() {
+        let pipe = CommandPipe::new();
+        assert!(matches!(pipe.validate(), Err(APipeError::EmptyPipe)));
+
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("echo").add_command("");
+        assert!(matches!(pipe.validate(), Err(APipeError::EmptyProgram(1))));
+
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("cat").inherit_stdin().stdin_bytes("test");
+        assert!(matches!(
+            pipe.validate(),
+            Err(APipeError::ConflictingRedirection(..))
+        ));
+
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("cat")
+            .stdout_to_file("apipe_test_never_created.txt")
+            .set_stage_stdout(0, StdioSpec::Null)
+            .unwrap();
+        assert!(matches!(
+            pipe.validate(),
+            Err(APipeError::ConflictingRedirection(..))
+        ));
+
+        #[cfg(unix)]
+        {
+            let mut pipe = CommandPipe::new();
+            pipe.add_command("cat").nice(-21);
+            assert!(matches!(pipe.validate(), Err(APipeError::InvalidNice(-21))));
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_argument_list_too_large() {
+        // Linux limits a single argument to 128 KiB.
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("echo")
+            .add_command("echo")
+            .arg("x".repeat(256 * 1024));
+
+        assert!(matches!(
+            pipe.spawn_with_output(),
+            Err(APipeError::ArgumentListTooLarge { stage: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn test_spawn_error_names_command() {
+        let err = CommandPipe::new()
+            .add_command("echo")
+            .arg("test")
+            .add_command("apipe-does-not-exist")
+            .args(["-i", "foo"])
+            .spawn()
+            .unwrap_err();
+
+        let msg = err.to_string();
+
+        assert!(msg.starts_with("failed to spawn `apipe-does-not-exist -i foo`: "));
+    }
+
+    #[test]
+    fn test_from_token_stages() {
+        let stages = vec![
+            vec!["echo".to_owned(), "hi".to_owned()],
+            vec!["cat".to_owned()],
+        ];
+        let mut pipe = CommandPipe::from_token_stages(stages).unwrap();
+
+        assert_eq!(pipe.pipeline.len(), 2);
+        assert_eq!(pipe.spawn_with_output().unwrap(), "hi\n");
+
+        let stages = vec![vec!["echo".to_owned()], vec![]];
+        assert!(matches!(
+            CommandPipe::from_token_stages(stages),
+            Err(APipeError::EmptyProgram(1))
+        ));
+    }
+
+    #[test]
+    fn test_from_argv() {
+        let mut pipe = CommandPipe::from_argv(&["echo", "hi", "|", "cat"], "|").unwrap();
+
+        assert_eq!(pipe.pipeline.len(), 2);
+        assert_eq!(pipe.spawn_with_output().unwrap(), "hi\n");
+
+        let args = vec![OsString::from("echo"), OsString::from("a | b")];
+        let mut pipe = CommandPipe::from_argv(&args, "|").unwrap();
+        assert_eq!(pipe.spawn_with_output().unwrap(), "a | b\n");
+
+        assert!(matches!(
+            CommandPipe::from_argv(&["echo", "::", "::", "cat"], "::"),
+            Err(APipeError::EmptyProgram(1))
+        ));
+        assert!(matches!(
+            CommandPipe::from_argv::<&str>(&[], "|"),
+            Err(APipeError::EmptyProgram(0))
+        ));
+    }
+
+    #[test]
+    fn test_from_parts() {
+        let mut pipe = CommandPipe::from_parts("echo", &["This is", "a test."]);
+        pipe.add_command("grep").arg("test");
+
+        assert_eq!(pipe.spawn_with_output().unwrap(), "This is a test.\n");
+    }
+
+    #[test]
+    fn test_max_output_lines() {
+        let output = CommandPipe::new()
+            .add_command("yes")
+            .add_command("cat")
+            .max_output_lines(3)
+            .spawn_with_output()
+            .unwrap();
+
+        assert_eq!(output.stdout(), b"y\ny\ny\n");
+    }
+
+    #[test]
+    fn test_tail_output_bytes() {
+        let output = CommandPipe::new()
+            .add_command("seq")
+            .arg("10000")
+            .tail_output_bytes(10)
+            .io_chunk_size(7)
+            .spawn_with_output()
+            .unwrap();
+        assert_eq!(output.stdout(), b"999\n10000\n");
+
+        let output = CommandPipe::new()
+            .add_command("echo")
+            .arg("short")
+            .tail_output_bytes(10)
+            .spawn_with_output()
+            .unwrap();
+        assert_eq!(output.stdout(), b"short\n");
+    }
+
+    #[test]
+    fn test_tail_output_bytes_max_output_lines() {
+        let output = CommandPipe::new()
+            .add_command("seq")
+            .arg("10000")
+            .max_output_lines(12)
+            .tail_output_bytes(6)
+            .spawn_with_output()
+            .unwrap();
+
+        assert_eq!(output.stdout(), b"11\n12\n");
+    }
+
+    #[test]
+    fn test_max_output_lines_not_reached() {
+        let output = CommandPipe::new()
+            .add_command("printf")
+            .arg("a\\nb")
+            .max_output_lines(3)
+            .spawn_with_output()
+            .unwrap();
+
+        assert_eq!(output.stdout(), b"a\nb");
+        assert_eq!(output.status_code(), Some(0));
+    }
+
+    #[test]
+    fn test_capture_tagged() {
+        let output = CommandPipe::new()
+            .add_command("sh")
+            .args(["-c", "echo out0; sleep 0.1; echo err0 >&2"])
+            .add_command("sh")
+            .args([
+                "-c",
+                "read line; echo \"got $line\"; sleep 0.3; echo err1 >&2",
+            ])
+            .capture_tagged()
+            .spawn_with_output()
+            .unwrap();
+
+        assert_eq!(output.stdout(), b"got out0\n");
+        assert_eq!(
+            String::from_utf8_lossy(output.tagged_log()),
+            "[stage0:out] out0\n\
+             [stage1:out] got out0\n\
+             [stage0:err] err0\n\
+             [stage1:err] err1\n"
+        );
+    }
+
+    #[test]
+    fn test_run_trimmed_single_newline() {
+        let stdout = CommandPipe::new()
+            .add_command("printf")
+            .arg("a\\n\\n")
+            .run_trimmed()
+            .unwrap();
+        assert_eq!(stdout, "a\n");
+
+        let stdout = CommandPipe::new()
+            .add_command("printf")
+            .arg("a\\r\\n")
+            .run_trimmed()
+            .unwrap();
+        assert_eq!(stdout, "a");
+    }
+
+    #[test]
+    fn test_on_stage_error_ignore() {
+        let output = CommandPipe::new()
+            .add_command("echo")
+            .arg("x")
+            .add_command("grep")
+            .arg("y")
+            .on_stage_error(1, |status| {
+                assert_eq!(status.code(), Some(1));
+                StageAction::Ignore
+            })
+            .unwrap()
+            .run_checked()
+            .unwrap();
+
+        assert_eq!(output.status_code(), Some(1));
+    }
+
+    #[test]
+    fn test_on_stage_error_fail() {
+        let result = CommandPipe::new()
+            .add_command("false")
+            .add_command("cat")
+            .on_stage_error(0, |_| StageAction::Fail)
+            .unwrap()
+            .run_checked();
+
+        assert!(matches!(
+            result,
+            Err(APipeError::NonZeroExit { command, .. }) if command == "false"
+        ));
+    }
+
+    #[test]
+    fn test_on_stage_error_abort() {
+        let started = Instant::now();
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("sleep")
+            .arg("10")
+            .add_command("sh")
+            .args(["-c", "exit 3"])
+            .on_stage_error(1, |_| StageAction::Abort)
+            .unwrap();
+
+        assert!(matches!(
+            pipe.run_checked(),
+            Err(APipeError::NonZeroExit { command, .. }) if command == "sh -c 'exit 3'"
+        ));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(pipe.status_code_of(0), None);
+    }
+
+    #[test]
+    fn test_allow_exit_codes() {
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("echo")
+            .arg("x")
+            .add_command("grep")
+            .arg("y")
+            .allow_exit_codes(1, &[1])
+            .unwrap();
+
+        assert!(pipe.run_checked().is_ok());
+    }
+
+    #[test]
+    fn test_allow_exit_codes_other_code() {
+        let result = CommandPipe::new()
+            .add_command("sh")
+            .args(["-c", "exit 2"])
+            .add_command("cat")
+            .allow_exit_codes(0, &[1])
+            .unwrap()
+            .run_checked();
+
+        assert!(matches!(
+            result,
+            Err(APipeError::NonZeroExit { command, .. }) if command == "sh -c 'exit 2'"
+        ));
+    }
+
+    #[test]
+    fn test_allow_exit_codes_no_polling() {
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("echo")
+            .add_command("grep")
+            .arg("y")
+            .allow_exit_codes(1, &[1])
+            .unwrap();
+        assert!(!pipe.polls());
+
+        pipe.on_stage_error(0, |_| StageAction::Abort).unwrap();
+        assert!(pipe.polls());
+    }
+
+    #[test]
+    fn test_on_stage_error_invalid_stage() {
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("ls");
+
+        assert!(matches!(
+            pipe.on_stage_error(1, |_| StageAction::Ignore),
+            Err(APipeError::InvalidStage(1))
+        ));
+    }
+
+    #[test]
+    fn test_combined() {
+        let output = CommandPipe::new()
+            .add_command("sh")
+            .args(["-c", "echo skipped; echo err0 >&2; sleep 0.1"])
+            .add_command("sh")
+            .args([
+                "-c",
+                "cat >/dev/null; echo out1; sleep 0.1; echo err1 >&2; sleep 0.1; echo out2",
+            ])
+            .capture_tagged()
+            .spawn_with_output()
+            .unwrap();
+
+        assert_eq!(output.stdout(), b"out1\nout2\n");
+        assert_eq!(output.stderr(), b"err0\nerr1\n");
+        assert_eq!(output.combined(), b"err0\nout1\nerr1\nout2\n");
+    }
+
+    #[test]
+    fn test_combined_not_captured() {
+        let output = CommandPipe::new()
+            .add_command("echo")
+            .arg("test")
+            .spawn_with_output()
+            .unwrap();
+
+        assert!(output.combined().is_empty());
+    }
+
+    #[test]
+    fn test_terminal_lines() {
+        let log = b"[stage0:out] a]\n[stage1:err] b\n[stage1:out] [stage0:out] c\n";
+
+        assert_eq!(terminal_lines(log, 1), b"b\n[stage0:out] c\n");
+        assert_eq!(terminal_lines(log, 0), b"a]\nb\n");
+    }
+
+    #[test]
+    fn test_run_full() {
+        let full = CommandPipe::new()
+            .add_command("printf")
+            .arg("foo\\nbar\\n")
+            .add_command("sh")
+            .args(["-c", "grep foo; echo 'middle failed' >&2; exit 3"])
+            .add_command("tr")
+            .args(["a-z", "A-Z"])
+            .run_full()
+            .unwrap();
+
+        assert_eq!(full.stdout, b"FOO\n");
+        assert_eq!(
+            full.statuses
+                .iter()
+                .map(ExitStatus::code)
+                .collect::<Vec<_>>(),
+            &[Some(0), Some(3), Some(0)]
+        );
+        assert_eq!(
+            full.stderr,
+            &[b"".to_vec(), b"middle failed\n".to_vec(), b"".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_run_full_scoped() {
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("sh").args(["-c", "echo foo >&2"]);
+
+        assert_eq!(pipe.run_full().unwrap().stderr, &[b"foo\n".to_vec()]);
+        assert!(!pipe.config.capture_stderr);
+    }
+
+    #[test]
+    fn test_statuses() {
+        let output = CommandPipe::new()
+            .add_command("false")
+            .add_command("true")
+            .spawn_with_output()
+            .unwrap();
+
+        assert_eq!(output.status_code(), Some(0));
+        assert_eq!(
+            output
+                .statuses()
+                .iter()
+                .map(ExitStatus::code)
+                .collect::<Vec<_>>(),
+            &[Some(1), Some(0)]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_status_code_of() {
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("true").add_command("false");
+        assert_eq!(pipe.status_code_of(1), None);
+
+        pipe.start().unwrap();
+        assert_eq!(pipe.status_code_of(1), None);
+
+        pipe.output().unwrap();
+        assert_eq!(pipe.status_code_of(0), Some(0));
+        assert_eq!(pipe.status_code_of(1), Some(1));
+        assert_eq!(pipe.status_code_of(2), None);
+    }
+
+    #[test]
+    fn test_executed_commands() {
+        let output = CommandPipe::new()
+            .add_command("echo")
+            .args(["~/file", "two words"])
+            .add_command("cat")
+            .env_all("PATH", "/bin")
+            .spawn_with_output()
+            .unwrap();
+
+        // Arguments are never expanded, but the program is resolved.
+        assert_eq!(
+            output.executed_commands(),
+            &["/bin/echo ~/file 'two words'", "/bin/cat"]
+        );
+        assert_eq!(output, "~/file two words\n");
+    }
+
+    #[test]
+    fn test_resolve_program() {
+        let dir = std::env::temp_dir();
+        let relative = Command::new("./script.sh").current_dir(&dir);
+
+        assert_eq!(
+            resolve_program(&relative.to_process()),
+            dir.join("./script.sh")
+        );
+        assert_eq!(
+            resolve_program(&process::Command::new("apipe-does-not-exist")),
+            PathBuf::from("apipe-does-not-exist")
+        );
+    }
+
+    #[test]
+    fn test_capture_stderr() {
+        let output = CommandPipe::new()
+            .add_command("sh")
+            .args(["-c", "echo first >&2; echo out"])
+            .add_command("sh")
+            .args(["-c", "cat; echo second >&2"])
+            .capture_stderr()
+            .spawn_with_output()
+            .unwrap();
+
+        assert_eq!(output.stdout(), b"out\n");
+        assert_eq!(output.stderr(), b"first\nsecond\n");
+    }
+
+    #[test]
+    fn test_capture_stderr_large_outputs_separate() {
+        let output = CommandPipe::new()
+            .add_command("sh")
+            .args([
+                "-c",
+                "yes out | head -c 1000000 & yes err | head -c 1000000 >&2; wait",
+            ])
+            .capture_stderr()
+            .spawn_with_output()
+            .unwrap();
+
+        assert!(output.stdout() == "out\n".repeat(250_000).as_bytes());
+        assert!(output.stderr() == "err\n".repeat(250_000).as_bytes());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_inherit_io() {
+        let path = std::env::temp_dir().join("apipe_test_inherit_io.txt");
+        let script = format!(
+            "links=$(readlink /proc/$$/fd/0 /proc/$$/fd/1 /proc/$$/fd/2); echo \"$links\" > {}",
+            path.display()
+        );
+        let output = CommandPipe::from(vec![
+            Command::new("echo").arg("discarded"),
+            Command::new("sh").args(["-c", &script]).inherit_io(),
+            Command::new("wc").arg("-c"),
+        ])
+        .capture_stderr()
+        .spawn_with_output()
+        .unwrap();
+
+        let parent: String = (0..3)
+            .map(|fd| {
+                let link = std::fs::read_link(format!("/proc/self/fd/{}", fd)).unwrap();
+                format!("{}\n", link.display())
+            })
+            .collect();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), parent);
+        assert_eq!(output.stdout(), b"0\n");
+        assert!(output.stderr().is_empty());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    /// Runs a stage writing where its stdio is connected to into a file
+    /// under `policy`, and returns that with the output of the pipe.
+    #[cfg(target_os = "linux")]
+    fn stdio_with_policy(policy: StdioPolicy, name: &str) -> (String, Output) {
+        let path = std::env::temp_dir().join(name);
+        let script = format!(
+            "links=$(readlink /proc/$$/fd/0 /proc/$$/fd/1 /proc/$$/fd/2); \
+             echo \"$links\" > {}; echo out",
+            path.display()
+        );
+        let output = CommandPipe::new()
+            .add_command("sh")
+            .args(["-c", &script])
+            .with_default_stdio(policy)
+            .spawn_with_output()
+            .unwrap();
+
+        let links = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        (links, output)
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_with_default_stdio_capture() {
+        let (links, output) =
+            stdio_with_policy(StdioPolicy::Capture, "apipe_test_default_stdio_capture.txt");
+        let links: Vec<&str> = links.lines().collect();
+
+        assert_eq!(links[0], "/dev/null");
+        assert!(links[1].starts_with("pipe:"));
+        assert_eq!(output.stdout(), b"out\n");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_with_default_stdio_inherit() {
+        let (links, output) =
+            stdio_with_policy(StdioPolicy::Inherit, "apipe_test_default_stdio_inherit.txt");
+
+        let parent: String = (0..3)
+            .map(|fd| {
+                let link = std::fs::read_link(format!("/proc/self/fd/{}", fd)).unwrap();
+                format!("{}\n", link.display())
+            })
+            .collect();
+        assert_eq!(links, parent);
+        assert!(output.stdout().is_empty());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_with_default_stdio_null() {
+        let (links, output) =
+            stdio_with_policy(StdioPolicy::Null, "apipe_test_default_stdio_null.txt");
+
+        assert_eq!(links, "/dev/null\n/dev/null\n/dev/null\n");
+        assert!(output.stdout().is_empty());
+    }
+
+    #[test]
+    fn test_with_default_stdio_overridden() {
+        let output = CommandPipe::new()
+            .add_command("sh")
+            .args(["-c", "cat; echo err >&2"])
+            .stdin_bytes("in\n")
+            .capture_stderr()
+            .with_default_stdio(StdioPolicy::Null)
+            .set_stage_stdout(0, StdioSpec::Piped)
+            .unwrap()
+            .spawn_with_output()
+            .unwrap();
+
+        assert_eq!(output.stdout(), b"in\n");
+        assert_eq!(output.stderr(), b"err\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_spawn_not_executable() {
+        let path = std::env::temp_dir().join("apipe_test_spawn_not_executable.sh");
+        std::fs::write(&path, "echo foo\n").unwrap();
+
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("echo").add_command(&path);
+
+        match pipe.spawn() {
+            Err(APipeError::NotExecutable {
+                source,
+                stage,
+                program,
+            }) => {
+                assert_eq!(source.raw_os_error(), Some(libc::EACCES));
+                assert_eq!(stage, 1);
+                assert_eq!(program, path.to_string_lossy());
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_spawn_current_dir_denied() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // Permissions don't keep root out of a directory.
+        // SAFETY: `geteuid` is always safe to call.
+        if unsafe { libc::geteuid() } == 0 {
+            return;
+        }
+
+        let dir = std::env::temp_dir().join("apipe_test_spawn_current_dir_denied");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o600)).unwrap();
+
+        let mut pipe = CommandPipe::from(vec![Command::new("true").current_dir(&dir)]);
+        let result = pipe.spawn();
+        std::fs::remove_dir(&dir).unwrap();
+
+        assert!(matches!(
+            result,
+            Err(APipeError::ChildProcess { ref source, .. })
+                if source.raw_os_error() == Some(libc::EACCES)
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_spawn_directory() {
+        let dir = std::env::temp_dir();
+
+        let mut pipe = CommandPipe::new();
+        pipe.add_command(&dir).add_command("cat");
+
+        match pipe.spawn() {
+            Err(APipeError::IsDirectory { stage, program, .. }) => {
+                assert_eq!(stage, 0);
+                assert_eq!(program, dir.to_string_lossy());
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+
+        let mut pipe = CommandPipe::from(vec![Command::new("./tmp").current_dir("/")]);
+        assert!(matches!(
+            pipe.spawn(),
+            Err(APipeError::IsDirectory { stage: 0, .. })
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_resource_usage() {
+        let busy = "i=0; while [ $i -lt 300000 ]; do i=$((i + 1)); done; echo $i";
+        let build = || {
+            let mut pipe = CommandPipe::new();
+            pipe.add_command("sh").args(["-c", busy]).add_command("cat");
+            pipe
+        };
+
+        let output = build().spawn_with_output().unwrap();
+        assert_eq!(output, "300000\n");
+        let usage = output.resource_usage(0).unwrap();
+        assert!(usage.user_time > Duration::ZERO);
+        assert!(usage.max_rss > 0);
+        assert!(output.resource_usage(1).is_some());
+        assert!(output.resource_usage(2).is_none());
+
+        // Stages with a timeout are polled instead.
+        let mut pipe = build();
+        pipe.stage_timeout(1, Duration::from_secs(60)).unwrap();
+        let output = pipe.spawn_with_output().unwrap();
+        assert!(output.resource_usage(0).unwrap().user_time > Duration::ZERO);
+        assert!(output.resource_usage(1).is_some());
+    }
+
+    #[cfg(all(unix, feature = "posix_spawn"))]
+    #[test]
+    fn test_spawn_with_output_posix() {
+        let builds: [fn() -> CommandPipe; 4] = [
+            || {
+                let mut pipe = CommandPipe::new();
+                pipe.add_command("echo")
+                    .arg("foo bar")
+                    .add_command("tr")
+                    .args(["a-z", "A-Z"])
+                    .add_command("grep")
+                    .arg("FOO");
+                pipe
+            },
+            || {
+                let mut pipe = CommandPipe::from(vec![
+                    Command::new("sh")
+                        .args(["-c", "echo \"$0 $APIPE_A $APIPE_B\"; exit 3"])
+                        .arg0("zero")
+                        .env("APIPE_B", "b"),
+                    Command::new("cat"),
+                ]);
+                pipe.env_all("APIPE_A", "a");
+                pipe
+            },
+            || {
+                let mut pipe = CommandPipe::new();
+                pipe.add_command("/usr/bin/env")
+                    .clean_env()
+                    .env_all("APIPE_A", "a");
+                pipe
+            },
+            // The default disposition of `SIGPIPE` is restored.
+            || {
+                let mut pipe = CommandPipe::new();
+                pipe.add_command("yes")
+                    .add_command("head")
+                    .args(["-n", "3"]);
+                pipe
+            },
+        ];
+
+        for build in builds {
+            let forked = build().spawn_with_output().unwrap();
+            let spawned = build().spawn_with_output_posix().unwrap();
+            assert_eq!(spawned, forked);
+            assert_eq!(spawned.statuses(), forked.statuses());
+            assert!(spawned.resource_usage(0).is_some());
+        }
+
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("echo")
+            .add_command("apipe-does-not-exist")
+            .add_command("cat");
+        assert!(matches!(
+            pipe.spawn_with_output_posix(),
+            Err(APipeError::ChildProcess { .. })
+        ));
+
+        // Pipes with `pre_exec` hooks fall back to forking.
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("sh")
+            .args(["-c", "echo \"$APIPE_PRE_EXEC\""])
+            .add_command("cat");
+        unsafe {
+            pipe.pre_exec(0, || {
+                match libc::setenv(c"APIPE_PRE_EXEC".as_ptr(), c"set".as_ptr(), 1) {
+                    0 => Ok(()),
+                    _ => Err(io::Error::last_os_error()),
+                }
+            })
+            .unwrap();
+        }
+        assert!(!pipe.posix_spawnable());
+        assert_eq!(pipe.spawn_with_output_posix().unwrap(), "set\n");
+    }
+
+    #[test]
+    fn test_dry_run() {
+        let mut pipe = CommandPipe::from(vec![
+            Command::new("echo").arg("foo"),
+            Command::new("tr").args(["a-z", "A-Z"]),
+            Command::new("grep").arg("FOO").env("LANG", "C"),
+        ]);
+        pipe.env_all("APIPE_TEST", "dry")
+            .pipe_stderr(0, 1)
+            .unwrap()
+            .stdout_to_file("/tmp/apipe_test_dry_run.txt");
+
+        let report = pipe.dry_run();
+        let stages = &report.stages;
+
+        assert_eq!(stages.len(), 3);
+        assert!(stages[0].program.is_absolute());
+        assert!(stages[0].program.ends_with("echo"));
+        assert_eq!(stages[1].args, ["a-z", "A-Z"]);
+        assert!(!stages[2].env_clear);
+        assert_eq!(
+            stages[2].env,
+            [
+                ("APIPE_TEST".into(), Some("dry".into())),
+                ("LANG".into(), Some("C".into()))
+            ]
+        );
+        assert_eq!(stages[0].stdin, StdioSpec::Null);
+        assert_eq!(stages[0].stderr, StdioSpec::Piped);
+        assert_eq!(stages[1].stdin, StdioSpec::Piped);
+        assert_eq!(stages[1].stdout, StdioSpec::Piped);
+        assert_eq!(stages[1].stderr, StdioSpec::Inherit);
+        assert_eq!(
+            stages[2].stdout,
+            StdioSpec::File("/tmp/apipe_test_dry_run.txt".into())
+        );
+        assert!(!std::path::Path::new("/tmp/apipe_test_dry_run.txt").exists());
+
+        pipe.stdin_bytes("foo").clean_env().quiet();
+        let report = pipe.dry_run();
+        assert_eq!(report.stages[0].stdin, StdioSpec::Piped);
+        assert!(report.stages[1].env_clear);
+        assert_eq!(report.stages[1].stderr, StdioSpec::Null);
+    }
+
+    #[test]
+    fn test_add_command_if() {
+        let build = |sort| {
+            let mut pipe = CommandPipe::new();
+            pipe.add_command("printf")
+                .arg("b\\na\\n")
+                .add_command_if(sort, "sort")
+                .add_command_if(false, Command::new("grep").arg("c"));
+            pipe
+        };
+
+        let mut pipe = build(false);
+        assert_eq!(pipe.to_string(), r"printf b\na\n");
+        assert_eq!(pipe.spawn_with_output().unwrap(), "b\na\n");
+
+        let mut pipe = build(true);
+        assert_eq!(pipe.to_string(), r"printf b\na\n | sort");
+        assert_eq!(pipe.spawn_with_output().unwrap(), "a\nb\n");
+    }
+
+    #[test]
+    fn test_replace_command() {
         let mut pipe = CommandPipe::new();
+        pipe.add_command("echo")
+            .arg("foo bar")
+            .add_command("grep")
+            .arg("baz")
+            .add_command("cat");
 
-        pipe.add_command("ls").arg("-la").arg("~/Documents");
+        let old = pipe
+            .replace_command(1, Command::new("sed").arg("s/foo/baz/"))
+            .unwrap();
 
-        let args: Vec<&OsStr> = pipe.pipeline[0].0.get_args().collect();
+        assert_eq!(old.to_string(), "grep baz");
+        assert_eq!(pipe.to_string(), "echo 'foo bar' | sed s/foo/baz/ | cat");
+        assert_eq!(pipe.spawn_with_output().unwrap(), "baz bar\n");
 
-        assert_eq!(args, &["-la", "~/Documents"])
+        assert!(matches!(
+            pipe.replace_command(3, Command::new("cat")),
+            Err(APipeError::InvalidStage(3))
+        ));
     }
 
     #[test]
-    fn test_args() {
+    fn test_simplify() {
         let mut pipe = CommandPipe::new();
+        pipe.add_command("echo")
+            .arg("hi")
+            .add_command("cat")
+            .add_command("cat")
+            .add_command("grep")
+            .arg("h");
+        let expected = pipe.clone().spawn_with_output().unwrap();
 
-        pipe.add_command("ls").args(vec!["-la", "~/Documents"]);
+        pipe.simplify();
 
-        let args: Vec<&OsStr> = pipe.pipeline[0].0.get_args().collect();
+        assert_eq!(pipe.to_string(), "echo hi | grep h");
+        assert_eq!(
+            pipe.spawn_with_output().unwrap().stdout(),
+            expected.stdout()
+        );
+    }
 
-        assert_eq!(args, &["-la", "~/Documents"])
+    #[test]
+    fn test_simplify_conservative() {
+        let mut pipe = CommandPipe::from(vec![
+            Command::new("cat"),
+            Command::new("cat").arg("-n"),
+            Command::new("cat").env("FOO", "bar"),
+            Command::new("cat"),
+            Command::new("sh").args(["-c", "echo err >&2"]),
+            Command::new("cat"),
+            Command::new("cat"),
+            Command::new("cat"),
+        ]);
+        pipe.capture_stage(3)
+            .unwrap()
+            .pipe_stderr(4, 6)
+            .unwrap()
+            .simplify();
+
+        assert_eq!(
+            pipe.to_string(),
+            "cat | cat -n | cat | cat | sh -c 'echo err >&2' | cat | cat"
+        );
+        assert_eq!(pipe.config.stderr_pipes.get(&4), Some(&5));
     }
 
     #[test]
-    fn test_pipe() {
+    fn test_simplify_pipe_settings() {
+        let plain = || {
+            let mut pipe = CommandPipe::new();
+            pipe.add_command("echo")
+                .add_command("cat")
+                .add_command("cat");
+            pipe
+        };
+
+        let mut pipe = plain();
+        pipe.prepend_args_all(["-u"]).simplify();
+        assert_eq!(pipe.pipeline.len(), 3);
+
+        let mut pipe = plain();
+        pipe.env_all("FOO", "bar").simplify();
+        assert_eq!(pipe.pipeline.len(), 3);
+
+        let mut pipe = plain();
+        pipe.clean_env().simplify();
+        assert_eq!(pipe.pipeline.len(), 3);
+
+        let mut pipe = plain();
+        pipe.via_shell("sh").simplify();
+        assert_eq!(pipe.pipeline.len(), 3);
+
+        let mut pipe = plain();
+        pipe.capture_tagged().simplify();
+        assert_eq!(pipe.pipeline.len(), 3);
+
+        assert_eq!(plain().simplify().pipeline.len(), 2);
+    }
+
+    #[test]
+    fn test_feed_into() {
+        let mut echo = CommandPipe::new();
+        echo.add_command("echo").arg("foo\nbar\nbaz");
+        let mut grep = CommandPipe::new();
+        grep.add_command("grep")
+            .arg("ba")
+            .add_command("grep")
+            .arg("z");
+
+        let mut pipe = echo.feed_into(grep).unwrap();
+        assert_eq!(pipe.pipeline.len(), 3);
+        assert_eq!(pipe.spawn_with_output().unwrap().stdout(), b"baz\n");
+
+        let mut redirected = CommandPipe::new();
+        redirected.add_command("echo").stdout_to_file("/dev/null");
+        assert!(matches!(
+            redirected.feed_into(CommandPipe::new()),
+            Err(APipeError::ConflictingRedirection(
+                "stdout_to_file",
+                "feed_into"
+            ))
+        ));
+
+        let mut with_stdin = CommandPipe::new();
+        with_stdin.add_command("cat").stdin_bytes("foo");
+        assert!(matches!(
+            CommandPipe::new().feed_into(with_stdin),
+            Err(APipeError::ConflictingRedirection(
+                "stdin_bytes",
+                "feed_into"
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_on_progress() {
+        let reported = Arc::new(Mutex::new(Vec::new()));
+        let counts = reported.clone();
+        let output = CommandPipe::new()
+            .add_command("seq")
+            .arg("100000")
+            .io_chunk_size(4096)
+            .on_progress(move |bytes| counts.lock().unwrap().push(bytes))
+            .spawn_with_output()
+            .unwrap();
+
+        let reported = reported.lock().unwrap();
+        assert!(reported.len() > 1);
+        assert!(reported.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(reported.last(), Some(&(output.stdout().len() as u64)));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_retry_transient() {
+        let mut attempts = 0;
+        let result = retry_transient(3, || {
+            attempts += 1;
+            match attempts {
+                1 => Err(io::Error::from_raw_os_error(libc::EAGAIN)),
+                _ => Ok(attempts),
+            }
+        });
+
+        assert_eq!(result.unwrap(), 2);
+
+        let mut attempts = 0;
+        let result: io::Result<()> = retry_transient(3, || {
+            attempts += 1;
+            Err(io::Error::from_raw_os_error(libc::EAGAIN))
+        });
+
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(attempts, 4);
+    }
+
+    #[test]
+    fn test_retry_transient_not_found() {
+        let mut attempts = 0;
+        let result: io::Result<()> = retry_transient(3, || {
+            attempts += 1;
+            Err(io::ErrorKind::NotFound.into())
+        });
+
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(attempts, 1);
+
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("apipe-does-not-exist").spawn_retries(3);
+
+        assert!(matches!(pipe.spawn(), Err(APipeError::ChildProcess { .. })));
+    }
+
+    #[test]
+    fn test_stderr_not_captured_by_default() {
+        let output = CommandPipe::new()
+            .add_command("sh")
+            .args(["-c", "echo err >&2"])
+            .spawn_with_output()
+            .unwrap();
+
+        assert!(output.stderr().is_empty());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_quiet() {
+        let output = CommandPipe::new()
+            .add_command("sh")
+            .args(["-c", "echo err >&2; readlink /proc/self/fd/2"])
+            .add_command("sh")
+            .args(["-c", "cat; echo err >&2; readlink /proc/self/fd/2"])
+            .quiet()
+            .spawn_with_output()
+            .unwrap();
+
+        assert_eq!(output.stdout(), b"/dev/null\n/dev/null\n");
+        assert!(output.stderr().is_empty());
+    }
+
+    #[test]
+    fn test_quiet_capture_stderr() {
+        let output = CommandPipe::new()
+            .add_command("sh")
+            .args(["-c", "echo err >&2"])
+            .quiet()
+            .capture_stderr()
+            .spawn_with_output()
+            .unwrap();
+
+        assert_eq!(output.stderr(), b"err\n");
+    }
+
+    #[test]
+    fn test_pipe_stderr() {
+        let output = CommandPipe::new()
+            .add_command("sh")
+            .args(["-c", "echo err >&2"])
+            .add_command("tr")
+            .args(["a-z", "A-Z"])
+            .pipe_stderr(0, 1)
+            .unwrap()
+            .capture_stderr()
+            .spawn_with_output()
+            .unwrap();
+
+        assert_eq!(output.stdout(), b"ERR\n");
+        assert!(output.stderr().is_empty());
+    }
+
+    #[test]
+    fn test_pipe_stderr_merged() {
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("sh")
+            .args(["-c", "echo out; echo err >&2"])
+            .add_command("cat")
+            .add_command("sort")
+            .pipe_stderr(0, 2)
+            .unwrap()
+            .capture_stage(1)
+            .unwrap()
+            .spawn()
+            .unwrap();
+
+        assert_eq!(pipe.output().unwrap().stdout(), b"err\nout\n");
+        assert_eq!(pipe.stage_output(1), Some("out\n".as_bytes()));
+    }
+
+    #[test]
+    fn test_pipe_stderr_invalid() {
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("ls").add_command("cat");
+
+        assert!(matches!(
+            pipe.pipe_stderr(0, 2),
+            Err(APipeError::InvalidStage(2))
+        ));
+        assert!(matches!(
+            pipe.pipe_stderr(1, 0),
+            Err(APipeError::InvalidStderrPipe { from: 1, to: 0 })
+        ));
+        assert!(matches!(
+            pipe.pipe_stderr(1, 1),
+            Err(APipeError::InvalidStderrPipe { from: 1, to: 1 })
+        ));
+    }
+
+    #[test]
+    fn test_run_checked() {
+        let err = CommandPipe::new()
+            .add_command("ls")
+            .arg("/nope")
+            .run_checked()
+            .unwrap_err();
+
+        assert!(matches!(err, APipeError::NonZeroExit { .. }));
+        let message = err.to_string();
+        assert!(message.contains("`ls /nope`"), "{}", message);
+        assert!(message.contains("/nope"), "{}", message);
+        assert!(message.contains("No such file"), "{}", message);
+
+        let output = CommandPipe::new()
+            .add_command("echo")
+            .arg("ok")
+            .run_checked()
+            .unwrap();
+        assert_eq!(output, "ok\n");
+    }
+
+    #[test]
+    fn test_run_checked_scoped() {
         let mut pipe = CommandPipe::new();
+        pipe.add_command("sh")
+            .args(["-c", "echo first >&2"])
+            .add_command("sh")
+            .args(["-c", "echo second >&2; exit 3"]);
 
+        match pipe.run_checked() {
+            Err(APipeError::NonZeroExit { stderr, .. }) => assert_eq!(stderr, "second"),
+            result => panic!("unexpected result {:?}", result),
+        }
+
+        // Stderr is only captured for the checked run.
+        assert!(!pipe.config.capture_stderr);
+        pipe.quiet();
+        assert!(pipe.spawn_with_output().unwrap().stderr().is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_stdout_raw_fd() {
+        let mut pipe = CommandPipe::new();
         pipe.add_command("echo")
             .arg("This is a test.")
-            .add_command("grep")
-            .arg("-Eo")
-            .arg(r"\w\w\sa[^.]*")
+            .add_command("cat");
+
+        assert_eq!(pipe.stdout_raw_fd(1), None);
+
+        pipe.start().unwrap();
+        assert_eq!(pipe.stdout_raw_fd(0), None);
+        assert_eq!(pipe.stdout_raw_fd(2), None);
+
+        let fd = pipe.stdout_raw_fd(1).unwrap();
+        let mut read = Vec::new();
+        let mut buf = [0u8; 64];
+        loop {
+            let n = unsafe { libc::read(fd, buf.as_mut_ptr().cast(), buf.len()) };
+            assert!(n >= 0);
+            if n == 0 {
+                break;
+            }
+            read.extend_from_slice(&buf[..n as usize]);
+        }
+
+        assert_eq!(read, b"This is a test.\n");
+        assert!(pipe.output().unwrap().stdout().is_empty());
+        assert_eq!(pipe.stdout_raw_fd(1), None);
+    }
+
+    #[cfg(all(unix, feature = "rlimit"))]
+    #[test]
+    fn test_limit_memory() {
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("dd")
+            .args(["if=/dev/zero", "of=/dev/null", "bs=512M", "count=1"])
+            .capture_stderr();
+
+        assert!(pipe.spawn_with_output().unwrap().0.status.success());
+
+        let output = pipe
+            .limit_memory(64 * 1024 * 1024)
+            .spawn_with_output()
+            .unwrap();
+
+        assert!(!output.0.status.success());
+    }
+
+    #[cfg(all(unix, feature = "rlimit"))]
+    #[test]
+    fn test_limit_cpu_time() {
+        use std::os::unix::process::ExitStatusExt;
+
+        let output = CommandPipe::new()
+            .add_command("sh")
+            .args(["-c", "while :; do :; done"])
+            .limit_cpu_time(1)
+            .spawn_with_output()
+            .unwrap();
+
+        assert!(output.0.status.signal().is_some());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_via_shell() {
+        let dir = std::env::temp_dir().join("apipe_test_via_shell");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.txt"), "").unwrap();
+
+        let pattern = format!("{}/*.txt", dir.display());
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("echo").arg(&pattern);
+
+        let literal = pipe.spawn_with_output().unwrap();
+        assert_eq!(literal.stdout(), format!("{}\n", pattern).as_bytes());
+
+        let expanded = pipe.via_shell("sh").spawn_with_output().unwrap();
+        let expected = format!("{}\n", dir.join("a.txt").display());
+        assert_eq!(expanded.stdout(), expected.as_bytes());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_with_umask() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join("apipe_test_with_umask.txt");
+        let _ = std::fs::remove_file(&path);
+
+        CommandPipe::new()
+            .add_command("touch")
+            .arg(&path)
+            .with_umask(0o077)
             .spawn()
-            .expect("Failed to spawn pipe.");
+            .unwrap();
 
-        let output = pipe.output().unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
 
-        assert_eq!(output.stdout(), "is a test\n".as_bytes());
+        assert_eq!(mode & 0o777, 0o600);
+
+        std::fs::remove_file(path).unwrap();
     }
 
+    #[cfg(unix)]
     #[test]
-    #[should_panic]
-    fn test_add_arg_without_command() {
+    fn test_nice() {
+        let niceness = |pipe: &mut CommandPipe| -> i32 {
+            let output = pipe.spawn_with_output().unwrap();
+            String::from_utf8_lossy(output.stdout())
+                .trim()
+                .parse()
+                .unwrap()
+        };
+
         let mut pipe = CommandPipe::new();
-        pipe.arg("ls");
+        pipe.add_command("nice");
+        let base = niceness(&mut pipe);
+
+        pipe.nice(5);
+
+        assert_eq!(niceness(&mut pipe), (base + 5).min(19));
+        assert_eq!(niceness(&mut pipe), (base + 5).min(19));
     }
 
+    #[cfg(unix)]
     #[test]
-    fn test_spawn_with_output() {
+    fn test_nice_invalid() {
+        let result = CommandPipe::new().add_command("nice").nice(20).spawn();
+
+        assert!(matches!(result, Err(APipeError::InvalidNice(20))));
+    }
+
+    #[cfg(all(target_os = "linux", feature = "affinity"))]
+    #[test]
+    fn test_cpu_affinity() {
+        let allowed = |status: &str| {
+            status
+                .lines()
+                .find_map(|line| line.strip_prefix("Cpus_allowed_list:"))
+                .unwrap()
+                .trim()
+                .to_owned()
+        };
+        // Pin to the first CPU this process may run on, which is the only
+        // one known to be available.
+        let own = allowed(&std::fs::read_to_string("/proc/self/status").unwrap());
+        let first: usize = own
+            .split(|c: char| !c.is_ascii_digit())
+            .next()
+            .unwrap()
+            .parse()
+            .unwrap();
+
         let output = CommandPipe::new()
-            .add_command("echo")
-            .arg("This is a test.")
-            .add_command("grep")
-            .arg("-Eo")
-            .arg(r"\w\w\sa[^.]*")
+            .add_command("cat")
+            .arg("/proc/self/status")
+            .cpu_affinity(&[first])
             .spawn_with_output()
             .unwrap();
 
-        assert_eq!(output.stdout(), "is a test\n".as_bytes());
+        assert_eq!(allowed(&output.stdout_string().unwrap()), first.to_string());
     }
 
+    #[cfg(all(target_os = "linux", feature = "affinity"))]
     #[test]
-    fn test_overload() {
+    fn test_cpu_affinity_invalid() {
         let mut pipe = CommandPipe::new();
+        pipe.add_command("true").cpu_affinity(&[]);
+        assert!(matches!(
+            pipe.validate(),
+            Err(APipeError::InvalidCpuAffinity(_))
+        ));
 
-        pipe = pipe | Command::new("grep");
+        let cpus = [0, libc::CPU_SETSIZE as usize];
+        pipe.cpu_affinity(&cpus);
+        assert!(matches!(
+            pipe.spawn(),
+            Err(APipeError::InvalidCpuAffinity(invalid)) if invalid == cpus
+        ));
+    }
 
-        assert_eq!(pipe.pipeline[0].0.get_program(), "grep");
+    #[cfg(windows)]
+    #[test]
+    fn test_creation_flags() {
+        const CREATE_NO_WINDOW: u32 = 0x08000000;
 
-        let output = (Command::new("echo").arg("This is a test.")
-            | Command::new("grep").args(&["-Eo", r"\w\w\sa[^.]*"]))
-        .spawn_with_output()
-        .unwrap();
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("cmd")
+            .args(["/C", "echo test"])
+            .creation_flags(CREATE_NO_WINDOW);
 
-        assert_eq!(output.stdout(), "is a test\n".as_bytes());
+        assert_eq!(pipe.config.creation_flags, Some(CREATE_NO_WINDOW));
+
+        let output = pipe.spawn_with_output().unwrap();
+
+        assert_eq!(output.stdout(), "test\r\n".as_bytes());
     }
 
     #[cfg(feature = "parser")]
@@ -315,6 +7541,26 @@ mod tests {
         assert_eq!(output.stdout(), "is a test\n".as_bytes());
     }
 
+    #[cfg(feature = "parser")]
+    #[test]
+    fn test_run_trimmed() {
+        let mut pipe = CommandPipe::try_from("echo hi | cat").unwrap();
+
+        assert_eq!(pipe.run_trimmed().unwrap(), "hi");
+    }
+
+    #[cfg(feature = "parser")]
+    #[test]
+    fn test_try_from_string() {
+        let cmd = String::from(r#"echo "This is a test." | grep -Eo \w\w\sa[^.]*"#);
+
+        let mut pipe = CommandPipe::try_from(&cmd).unwrap();
+        assert_eq!(pipe.spawn_with_output().unwrap().stdout(), b"is a test\n");
+
+        let mut pipe = CommandPipe::try_from(cmd).unwrap();
+        assert_eq!(pipe.spawn_with_output().unwrap().stdout(), b"is a test\n");
+    }
+
     #[cfg(feature = "parser")]
     #[test]
     fn test_try_from_command() {
@@ -326,6 +7572,7 @@ mod tests {
 
     #[cfg(feature = "parser")]
     #[test]
+    #[allow(clippy::redundant_pattern_matching)]
     fn test_try_from_empty_str() {
         let pipe = CommandPipe::try_from("");
 
@@ -336,6 +7583,128 @@ mod tests {
 
     #[cfg(feature = "parser")]
     #[test]
+    fn test_try_from_here_string() {
+        let mut pipe = CommandPipe::try_from("grep foo <<< \"foo\nbar\"").unwrap();
+        let output = pipe.spawn_with_output().unwrap();
+
+        assert_eq!(output.stdout(), "foo\n".as_bytes());
+
+        let mut pipe = CommandPipe::try_from("cat <<< 'foo bar' | tr a-z A-Z").unwrap();
+        let output = pipe.spawn_with_output().unwrap();
+
+        assert_eq!(output.stdout(), "FOO BAR\n".as_bytes());
+    }
+
+    #[cfg(feature = "parser")]
+    #[test]
+    fn test_try_from_quoted_here_string() {
+        let mut pipe = CommandPipe::try_from(r#"echo "a <<< b""#).unwrap();
+
+        assert_eq!(pipe.pipeline.len(), 1);
+        assert!(pipe.config.stdin.is_none());
+        assert_eq!(pipe.spawn_with_output().unwrap(), "\"a <<< b\"\n");
+
+        let mut pipe = CommandPipe::try_from("grep b <<< 'a <<< b'").unwrap();
+
+        assert_eq!(pipe.spawn_with_output().unwrap(), "a <<< b\n");
+
+        let pipe = CommandPipe::try_from("echo foo | grep '<<<'");
+
+        assert!(pipe.is_ok());
+    }
+
+    #[cfg(feature = "parser")]
+    #[test]
+    fn test_try_from_here_string_not_first() {
+        let pipe = CommandPipe::try_from("echo foo | grep foo <<< foo");
+
+        assert!(matches!(pipe, Err(APipeError::Syntax(_))));
+    }
+
+    #[cfg(feature = "parser")]
+    #[test]
+    fn test_try_from_quoted_pipe() {
+        let mut pipe = CommandPipe::try_from("echo 'a|b' | tr a-z A-Z").unwrap();
+
+        assert_eq!(pipe.pipeline.len(), 2);
+        assert_eq!(pipe.spawn_with_output().unwrap(), "'A|B'\n");
+    }
+
+    #[cfg(feature = "parser")]
+    #[test]
+    fn test_args_str() {
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("grep").args_str("-i 'foo bar'").unwrap();
+
+        assert_eq!(
+            pipe.pipeline[0].get_args().collect::<Vec<&OsStr>>(),
+            &["-i", "foo bar"]
+        );
+
+        assert!(matches!(pipe.args_str("'foo"), Err(APipeError::Syntax(_))));
+        assert!(matches!(
+            CommandPipe::new().args_str("-i"),
+            Err(APipeError::EmptyPipe)
+        ));
+    }
+
+    #[cfg(feature = "parser")]
+    #[test]
+    fn test_strip_comments() {
+        assert_eq!(
+            strip_comments("echo foo#bar '# not' \"#no\" # comment\n| cat #|tac\n"),
+            "echo foo#bar '# not' \"#no\"  | cat  "
+        );
+        assert_eq!(strip_comments("#only\n#comments"), " ");
+    }
+
+    #[cfg(feature = "parser")]
+    #[test]
+    fn test_try_from_script() {
+        let script = r#"
+            # Find the interesting part.
+            echo "This is a #1 test."  # The input.
+            | grep -Eo [#][0-9]        # Keep the number.
+            |tr 1 2                   #| cat
+        "#;
+        let mut pipe = CommandPipe::try_from_script(script).unwrap();
+
+        assert_eq!(pipe.pipeline.len(), 3);
+        assert_eq!(pipe.spawn_with_output().unwrap(), "#2\n");
+    }
+
+    #[cfg(feature = "parser")]
+    #[test]
+    fn test_append_pipe_str() {
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("printf")
+            .arg("foo\\nbar\\n")
+            .append_pipe_str("| grep foo")
+            .unwrap()
+            .append_pipe_str("tr a-z A-Z")
+            .unwrap();
+
+        assert_eq!(pipe.pipeline.len(), 3);
+        assert_eq!(pipe.spawn_with_output().unwrap(), "FOO\n");
+    }
+
+    #[cfg(feature = "parser")]
+    #[test]
+    fn test_append_pipe_str_invalid() {
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("ls");
+
+        assert!(pipe.append_pipe_str("| grep foo | | cat").is_err());
+        assert!(matches!(
+            pipe.append_pipe_str("cat <<< foo"),
+            Err(APipeError::Syntax(_))
+        ));
+        assert_eq!(pipe.pipeline.len(), 1);
+    }
+
+    #[cfg(feature = "parser")]
+    #[test]
+    #[allow(clippy::redundant_pattern_matching)]
     fn test_try_from_invalid_pipe() {
         let pipe = CommandPipe::try_from(" | ");
 
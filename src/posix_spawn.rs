@@ -0,0 +1,244 @@
+//! Spawning the stages of a pipe with `posix_spawn`, see
+//! [CommandPipe::spawn_with_output_posix](crate::CommandPipe::spawn_with_output_posix).
+//!
+//! Everything here runs in the parent. The stages are spawned without any
+//! code of the crate running in the child, which is what makes it faster
+//! than forking, but also why `pre_exec` hooks can't be supported.
+
+use std::{
+    collections::BTreeMap,
+    ffi::{CString, OsStr, OsString},
+    io::{self, PipeReader},
+    iter,
+    os::{
+        fd::{AsRawFd, RawFd},
+        unix::ffi::{OsStrExt, OsStringExt},
+    },
+    path::Path,
+    process, ptr,
+};
+
+/// A stage ready to be spawned, with everything converted to C strings.
+pub(crate) struct Stage {
+    path: CString,
+    argv: Vec<CString>,
+    envp: Vec<CString>,
+}
+
+impl Stage {
+    /// Converts `prepared` into a stage running the program at `path`.
+    ///
+    /// The environment starts out empty with `env_clear`, or as the one of the
+    /// parent otherwise. `argv[0]` is `arg0` if it's given.
+    pub(crate) fn new(
+        prepared: &process::Command,
+        path: &Path,
+        env_clear: bool,
+        arg0: Option<&OsStr>,
+    ) -> io::Result<Stage> {
+        // A program given by name that wasn't found in the `PATH`.
+        if path.components().count() == 1 && !path.has_root() {
+            return Err(io::Error::from_raw_os_error(libc::ENOENT));
+        }
+
+        let mut env: BTreeMap<OsString, OsString> = match env_clear {
+            true => BTreeMap::new(),
+            false => std::env::vars_os().collect(),
+        };
+        for (key, val) in prepared.get_envs() {
+            match val {
+                Some(val) => env.insert(key.to_owned(), val.to_owned()),
+                None => env.remove(key),
+            };
+        }
+
+        let argv = iter::once(arg0.unwrap_or(prepared.get_program()))
+            .chain(prepared.get_args())
+            .map(c_string)
+            .collect::<io::Result<_>>()?;
+        let envp = env
+            .into_iter()
+            .map(|(key, val)| {
+                let mut var = key.into_vec();
+                var.push(b'=');
+                var.extend(val.into_vec());
+                CString::new(var).map_err(io::Error::from)
+            })
+            .collect::<io::Result<_>>()?;
+
+        Ok(Stage {
+            path: c_string(path.as_os_str())?,
+            argv,
+            envp,
+        })
+    }
+}
+
+/// Spawns `stages`, each reading the stdout of the one before it. The first
+/// stage reads from the null device, the stderr of all stages is inherited.
+///
+/// Returns the process ids and the stdout of the last stage, or the index of
+/// the stage that failed to spawn and the error. The stages spawned before a
+/// failure are killed and reaped.
+pub(crate) fn spawn(
+    stages: &[Stage],
+) -> std::result::Result<(Vec<libc::pid_t>, PipeReader), (usize, io::Error)> {
+    let mut pids = Vec::with_capacity(stages.len());
+    let mut stdin: Option<PipeReader> = None;
+
+    for (i, stage) in stages.iter().enumerate() {
+        // The pipes are close-on-exec, only their copies as 0 and 1 are kept.
+        let spawned = io::pipe().and_then(|(reader, writer)| {
+            let stdin = stdin.as_ref().map(AsRawFd::as_raw_fd);
+            spawn_stage(stage, stdin, writer.as_raw_fd()).map(|pid| (pid, reader))
+        });
+
+        match spawned {
+            Ok((pid, reader)) => {
+                pids.push(pid);
+                stdin = Some(reader);
+            }
+            Err(e) => {
+                kill_all(&pids);
+                return Err((i, e));
+            }
+        }
+    }
+
+    match stdin {
+        Some(stdout) => Ok((pids, stdout)),
+        None => Err((0, io::Error::from(io::ErrorKind::InvalidInput))),
+    }
+}
+
+/// Kills and reaps the processes `pids`.
+pub(crate) fn kill_all(pids: &[libc::pid_t]) {
+    for &pid in pids {
+        // SAFETY: `pid` is a child that wasn't reaped yet, so it can't have
+        // been reused.
+        unsafe { libc::kill(pid, libc::SIGKILL) };
+        let _ = crate::unix::wait_pid(pid, true);
+    }
+}
+
+fn spawn_stage(stage: &Stage, stdin: Option<RawFd>, stdout: RawFd) -> io::Result<libc::pid_t> {
+    let argv = pointers(&stage.argv);
+    let envp = pointers(&stage.envp);
+    let mut actions = FileActions::new()?;
+    let attr = Attr::new()?;
+    let mut pid = 0;
+
+    // SAFETY: `actions` and `attr` are initialized, and the strings outlive
+    // the calls.
+    unsafe {
+        match stdin {
+            Some(fd) => check(libc::posix_spawn_file_actions_adddup2(
+                &mut actions.0,
+                fd,
+                0,
+            ))?,
+            None => check(libc::posix_spawn_file_actions_addopen(
+                &mut actions.0,
+                0,
+                c"/dev/null".as_ptr(),
+                libc::O_RDONLY,
+                0,
+            ))?,
+        }
+        check(libc::posix_spawn_file_actions_adddup2(
+            &mut actions.0,
+            stdout,
+            1,
+        ))?;
+
+        check(libc::posix_spawn(
+            &mut pid,
+            stage.path.as_ptr(),
+            &actions.0,
+            &attr.0,
+            argv.as_ptr(),
+            envp.as_ptr(),
+        ))?;
+    }
+
+    Ok(pid)
+}
+
+/// Spawn file actions that are destroyed when dropped.
+struct FileActions(libc::posix_spawn_file_actions_t);
+
+impl FileActions {
+    fn new() -> io::Result<Self> {
+        // SAFETY: the actions are initialized before they are used.
+        let mut actions = FileActions(unsafe { std::mem::zeroed() });
+        // SAFETY: `actions.0` is valid for writes.
+        check(unsafe { libc::posix_spawn_file_actions_init(&mut actions.0) })?;
+
+        Ok(actions)
+    }
+}
+
+impl Drop for FileActions {
+    fn drop(&mut self) {
+        // SAFETY: the actions were initialized.
+        unsafe { libc::posix_spawn_file_actions_destroy(&mut self.0) };
+    }
+}
+
+/// Spawn attributes that are destroyed when dropped.
+///
+/// Like with std, `SIGPIPE`, which the Rust runtime ignores, is reset to its
+/// default in the child, and no signals are blocked.
+struct Attr(libc::posix_spawnattr_t);
+
+impl Attr {
+    fn new() -> io::Result<Self> {
+        // SAFETY: the attributes are initialized before they are used.
+        let mut attr = Attr(unsafe { std::mem::zeroed() });
+        // SAFETY: `attr.0` is valid for writes, the signal sets are
+        // initialized by `sigemptyset` before they are used.
+        unsafe {
+            check(libc::posix_spawnattr_init(&mut attr.0))?;
+
+            let mut set: libc::sigset_t = std::mem::zeroed();
+            libc::sigemptyset(&mut set);
+            check(libc::posix_spawnattr_setsigmask(&mut attr.0, &set))?;
+            libc::sigaddset(&mut set, libc::SIGPIPE);
+            check(libc::posix_spawnattr_setsigdefault(&mut attr.0, &set))?;
+
+            let flags = libc::POSIX_SPAWN_SETSIGMASK | libc::POSIX_SPAWN_SETSIGDEF;
+            check(libc::posix_spawnattr_setflags(&mut attr.0, flags as _))?;
+        }
+
+        Ok(attr)
+    }
+}
+
+impl Drop for Attr {
+    fn drop(&mut self) {
+        // SAFETY: the attributes were initialized.
+        unsafe { libc::posix_spawnattr_destroy(&mut self.0) };
+    }
+}
+
+/// Returns the null terminated array of pointers to `strings`.
+fn pointers(strings: &[CString]) -> Vec<*mut libc::c_char> {
+    strings
+        .iter()
+        .map(|s| s.as_ptr() as *mut _)
+        .chain(iter::once(ptr::null_mut()))
+        .collect()
+}
+
+fn c_string(s: &OsStr) -> io::Result<CString> {
+    CString::new(s.as_bytes()).map_err(io::Error::from)
+}
+
+/// The `posix_spawn` functions return the error number instead of setting
+/// errno.
+fn check(ret: libc::c_int) -> io::Result<()> {
+    match ret {
+        0 => Ok(()),
+        errno => Err(io::Error::from_raw_os_error(errno)),
+    }
+}
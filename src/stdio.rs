@@ -0,0 +1,90 @@
+//! Redirection settings for the stages of a pipe.
+
+use std::{fs::File, io, path::PathBuf, process::Stdio};
+
+/// Describes where a stream of a stage in the pipe is connected to.
+///
+/// With the `serde` feature it can be serialized, with the variants in snake
+/// case, e.g. `"null"` or `{ file = "out.txt" }` in TOML.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum StdioSpec {
+    /// Connect to the next stage, or capture it for the last stage.
+    Piped,
+    /// Discard the stream.
+    Null,
+    /// Inherit the stream from the parent process.
+    Inherit,
+    /// Write the stream to a file, truncating it if it exists.
+    File(PathBuf),
+}
+
+/// Describes where a file descriptor of a stage is redirected to, see
+/// [CommandPipe::redirect_fd](crate::CommandPipe::redirect_fd).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FdTarget {
+    /// Duplicate another file descriptor of the stage, like `3>&1`.
+    Fd(i32),
+    /// Write to a file, truncating it if it exists, like `3>file`.
+    File(PathBuf),
+    /// Discard everything written, like `3>/dev/null`.
+    Null,
+}
+
+/// Where the stdin of the first stage and the stdout and stderr of a pipe go
+/// by default, see
+/// [CommandPipe::with_default_stdio](crate::CommandPipe::with_default_stdio).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StdioPolicy {
+    /// Read stdin from the null device, capture stdout and inherit stderr.
+    #[default]
+    Capture,
+    /// Connect stdin, stdout and stderr to the ones of the parent process.
+    Inherit,
+    /// Read stdin from and discard stdout and stderr to the null device.
+    Null,
+}
+
+impl StdioPolicy {
+    /// Returns where the stdout of the last stage goes under the policy.
+    pub(crate) fn stdout(self) -> Option<StdioSpec> {
+        match self {
+            StdioPolicy::Capture => None,
+            StdioPolicy::Inherit => Some(StdioSpec::Inherit),
+            StdioPolicy::Null => Some(StdioSpec::Null),
+        }
+    }
+}
+
+impl StdioSpec {
+    pub(crate) fn to_stdio(&self) -> io::Result<Stdio> {
+        Ok(match self {
+            StdioSpec::Piped => Stdio::piped(),
+            StdioSpec::Null => Stdio::null(),
+            StdioSpec::Inherit => Stdio::inherit(),
+            StdioSpec::File(path) => File::create(path)?.into(),
+        })
+    }
+}
+
+#[cfg(all(test, feature = "serde"))]
+mod tests {
+    use super::*;
+    use serde::{
+        de::value::{Error, MapAccessDeserializer, MapDeserializer, StrDeserializer},
+        Deserialize,
+    };
+
+    #[test]
+    fn test_deserialize_stdio_spec() {
+        let null = StdioSpec::deserialize(StrDeserializer::<Error>::new("null")).unwrap();
+        assert_eq!(null, StdioSpec::Null);
+
+        let map = MapDeserializer::<_, Error>::new([("file", "out.txt")].into_iter());
+        let file = StdioSpec::deserialize(MapAccessDeserializer::new(map)).unwrap();
+        assert_eq!(file, StdioSpec::File("out.txt".into()));
+
+        assert!(StdioSpec::deserialize(StrDeserializer::<Error>::new("Null")).is_err());
+    }
+}
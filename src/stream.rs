@@ -0,0 +1,137 @@
+//! Streaming the output of a [CommandPipe](crate::CommandPipe) asynchronously.
+
+use crate::{cmd::Command, error::APipeError};
+use bytes::Bytes;
+use futures_core::Stream;
+use std::{
+    io::{self, Read},
+    pin::Pin,
+    process::Child,
+    task::{Context, Poll},
+    thread,
+};
+use tokio::sync::mpsc;
+
+type Result<T> = std::result::Result<T, APipeError>;
+
+/// How many chunks are buffered before the reader waits for the consumer.
+const BUFFERED_CHUNKS: usize = 16;
+
+/// The stdout of the last stage of a running pipe, as a [Stream] of chunks.
+///
+/// Created by [CommandPipe::spawn_stream_async](crate::CommandPipe::spawn_stream_async). The stream owns the
+/// processes of the pipe and kills them when it's dropped, e.g. when a client
+/// disconnects before the output was streamed completely.
+#[derive(Debug)]
+pub struct OutputStream {
+    chunks: mpsc::Receiver<Result<Bytes>>,
+    children: Vec<Child>,
+}
+
+impl OutputStream {
+    /// Streams what's read from `stdout` of the stage running `command`,
+    /// reading at most `chunk_size` bytes at a time.
+    pub(crate) fn new<R>(
+        stdout: Option<R>,
+        children: Vec<Child>,
+        command: Command,
+        chunk_size: usize,
+    ) -> Self
+    where
+        R: Read + Send + 'static,
+    {
+        let (tx, chunks) = mpsc::channel(BUFFERED_CHUNKS);
+
+        if let Some(mut stdout) = stdout {
+            // The reader blocks, so it can't run on the runtime of the consumer.
+            thread::spawn(move || {
+                let mut buf = vec![0; chunk_size];
+                loop {
+                    let chunk = match stdout.read(&mut buf) {
+                        Ok(0) => break,
+                        Ok(n) => Ok(Bytes::copy_from_slice(&buf[..n])),
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                        Err(e) => Err(APipeError::child_process(
+                            e,
+                            "failed to read stdout of",
+                            &command,
+                        )),
+                    };
+                    let failed = chunk.is_err();
+                    // The stream was dropped, so nobody is interested anymore.
+                    if tx.blocking_send(chunk).is_err() || failed {
+                        break;
+                    }
+                }
+            });
+        }
+
+        OutputStream { chunks, children }
+    }
+}
+
+impl Stream for OutputStream {
+    type Item = Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.chunks.poll_recv(cx)
+    }
+}
+
+impl Drop for OutputStream {
+    fn drop(&mut self) {
+        self.chunks.close();
+        for child in &mut self.children {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipe::CommandPipe;
+    use std::{future::poll_fn, time::Duration};
+
+    async fn next(stream: &mut OutputStream) -> Option<Result<Bytes>> {
+        poll_fn(|cx| Pin::new(&mut *stream).poll_next(cx)).await
+    }
+
+    #[tokio::test]
+    async fn test_spawn_stream_async() {
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("seq")
+            .arg("1000")
+            .add_command("grep")
+            .arg("0$")
+            .io_chunk_size(16);
+
+        let mut stream = pipe.spawn_stream_async().unwrap();
+        let mut streamed = Vec::new();
+        while let Some(chunk) = next(&mut stream).await {
+            streamed.extend_from_slice(&chunk.unwrap());
+        }
+
+        let expected = (1..=100)
+            .map(|i| format!("{}\n", i * 10))
+            .collect::<String>();
+        assert_eq!(streamed, expected.as_bytes());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_spawn_stream_async_drop() {
+        let mut pipe = CommandPipe::new();
+        pipe.add_command("sh")
+            .args(["-c", "echo started; sleep 10"]);
+
+        let mut stream = pipe.spawn_stream_async().unwrap();
+        let pid = stream.children[0].id();
+        assert_eq!(&next(&mut stream).await.unwrap().unwrap()[..], b"started\n");
+        drop(stream);
+
+        thread::sleep(Duration::from_millis(50));
+        assert!(!std::path::Path::new(&format!("/proc/{}", pid)).exists());
+    }
+}
@@ -0,0 +1,216 @@
+//! Helpers for configuring child processes on Unix.
+//!
+//! Unless noted otherwise, the functions in this module run in the forked
+//! child before `exec`, so they must only call async-signal-safe functions.
+
+use crate::output::ResourceUsage;
+use std::{
+    ffi::CString,
+    fs::File,
+    io,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        unix::{ffi::OsStrExt, process::ExitStatusExt},
+    },
+    path::Path,
+    process::{Child, ExitStatus},
+    time::Duration,
+};
+
+/// Adds `increment` to the niceness of the calling process.
+pub(crate) fn nice(increment: i32) -> io::Result<()> {
+    // `nice` may legitimately return -1, so errors are told apart by errno.
+    clear_errno();
+
+    // SAFETY: `nice` is async-signal-safe.
+    if unsafe { libc::nice(increment) } == -1 {
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(0) {
+            return Err(err);
+        }
+    }
+
+    Ok(())
+}
+
+/// Makes the calling process the leader of a new session without a
+/// controlling terminal.
+pub(crate) fn setsid() -> io::Result<()> {
+    // SAFETY: `setsid` is async-signal-safe.
+    check(unsafe { libc::setsid() })
+}
+
+/// Returns whether the calling process may search `dir`, i.e. change into it.
+///
+/// This runs in the parent.
+pub(crate) fn is_searchable(dir: &Path) -> bool {
+    let Ok(dir) = CString::new(dir.as_os_str().as_bytes()) else {
+        return false;
+    };
+
+    // SAFETY: `dir` is a valid C string.
+    unsafe { libc::access(dir.as_ptr(), libc::X_OK) == 0 }
+}
+
+/// Makes each first file descriptor of `redirects` a copy of the second one,
+/// in order.
+pub(crate) fn dup_fds(redirects: &[(i32, i32)]) -> io::Result<()> {
+    for &(fd, to) in redirects {
+        // SAFETY: `dup2` is async-signal-safe.
+        check(unsafe { libc::dup2(to, fd) })?;
+    }
+
+    Ok(())
+}
+
+/// Moves `file` to the lowest free file descriptor not below `min`.
+///
+/// This runs in the parent.
+pub(crate) fn move_fd_above(file: File, min: i32) -> io::Result<OwnedFd> {
+    // SAFETY: `file` is a valid descriptor and the duplicate is owned by the
+    // returned value only.
+    unsafe {
+        let fd = libc::fcntl(file.as_raw_fd(), libc::F_DUPFD_CLOEXEC, min);
+        check(fd)?;
+        Ok(OwnedFd::from_raw_fd(fd))
+    }
+}
+
+/// Limits the address space of the calling process to `bytes`.
+#[cfg(feature = "rlimit")]
+pub(crate) fn limit_memory(bytes: u64) -> io::Result<()> {
+    // SAFETY: `setrlimit` is async-signal-safe.
+    check(unsafe { libc::setrlimit(libc::RLIMIT_AS, &rlimit(bytes)) })
+}
+
+/// Limits the CPU time of the calling process to `secs` seconds.
+#[cfg(feature = "rlimit")]
+pub(crate) fn limit_cpu_time(secs: u64) -> io::Result<()> {
+    // SAFETY: `setrlimit` is async-signal-safe.
+    check(unsafe { libc::setrlimit(libc::RLIMIT_CPU, &rlimit(secs)) })
+}
+
+/// Restricts the calling process to the CPUs in `cpus`.
+#[cfg(all(target_os = "linux", feature = "affinity"))]
+pub(crate) fn set_affinity(cpus: &libc::cpu_set_t) -> io::Result<()> {
+    let size = std::mem::size_of::<libc::cpu_set_t>();
+    // SAFETY: `sched_setaffinity` is a plain system call and `cpus` is valid
+    // for `size` bytes.
+    check(unsafe { libc::sched_setaffinity(0, size, cpus) })
+}
+
+/// Returns the set of the given CPUs.
+///
+/// This runs in the parent. Every index must be below `libc::CPU_SETSIZE`.
+#[cfg(all(target_os = "linux", feature = "affinity"))]
+pub(crate) fn cpu_set(cpus: &[usize]) -> libc::cpu_set_t {
+    // SAFETY: an all zero `cpu_set_t` is the empty set.
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    for &cpu in cpus {
+        // SAFETY: the caller guarantees that `cpu` is within the set.
+        unsafe { libc::CPU_SET(cpu, &mut set) };
+    }
+
+    set
+}
+
+/// Reaps `child` once it exited and returns its status and resource usage,
+/// or `None` if `block` is false and it's still running.
+///
+/// This runs in the parent. The status isn't known to `child` afterwards, so
+/// it must not be killed or waited on again. If `child` was already waited
+/// on, its status is returned without a resource usage.
+pub(crate) fn wait_with_usage(
+    child: &mut Child,
+    block: bool,
+) -> io::Result<Option<(ExitStatus, Option<ResourceUsage>)>> {
+    match wait_pid(child.id() as libc::pid_t, block) {
+        Ok(waited) => Ok(waited.map(|(status, usage)| (status, Some(usage)))),
+        Err(e) if e.raw_os_error() == Some(libc::ECHILD) && block => {
+            Ok(Some((child.wait()?, None)))
+        }
+        Err(e) if e.raw_os_error() == Some(libc::ECHILD) => {
+            Ok(child.try_wait()?.map(|s| (s, None)))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Reaps the child process `pid` once it exited and returns its status and
+/// resource usage, or `None` if `block` is false and it's still running.
+///
+/// This runs in the parent.
+pub(crate) fn wait_pid(
+    pid: libc::pid_t,
+    block: bool,
+) -> io::Result<Option<(ExitStatus, ResourceUsage)>> {
+    let flags = if block { 0 } else { libc::WNOHANG };
+    let mut status = 0;
+    // SAFETY: an all zero `rusage` is valid.
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+
+    loop {
+        // SAFETY: `status` and `usage` are valid for writes.
+        match unsafe { libc::wait4(pid, &mut status, flags, &mut usage) } {
+            0 => return Ok(None),
+            -1 => {
+                let err = io::Error::last_os_error();
+                if err.kind() != io::ErrorKind::Interrupted {
+                    return Err(err);
+                }
+            }
+            _ => break,
+        }
+    }
+
+    // `ru_maxrss` is in bytes on Apple platforms and in kilobytes elsewhere.
+    let rss_unit = if cfg!(target_vendor = "apple") {
+        1
+    } else {
+        1024
+    };
+    let usage = ResourceUsage {
+        user_time: duration(usage.ru_utime),
+        sys_time: duration(usage.ru_stime),
+        max_rss: (usage.ru_maxrss as u64).saturating_mul(rss_unit),
+    };
+
+    Ok(Some((ExitStatus::from_raw(status), usage)))
+}
+
+fn duration(time: libc::timeval) -> Duration {
+    Duration::new(time.tv_sec as u64, time.tv_usec as u32 * 1000)
+}
+
+/// Returns a resource limit with the same soft and hard limit.
+#[cfg(feature = "rlimit")]
+fn rlimit(limit: libc::rlim_t) -> libc::rlimit {
+    libc::rlimit {
+        rlim_cur: limit,
+        rlim_max: limit,
+    }
+}
+
+fn check(ret: libc::c_int) -> io::Result<()> {
+    match ret {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+fn clear_errno() {
+    // SAFETY: errno is thread local and always valid to write.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    unsafe {
+        *libc::__errno_location() = 0;
+    }
+    #[cfg(any(
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "freebsd",
+        target_os = "dragonfly"
+    ))]
+    unsafe {
+        *libc::__error() = 0;
+    }
+}